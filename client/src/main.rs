@@ -111,7 +111,7 @@ fn main() {
     let reverse_binary_string = reverse_binary.to_string();
     println!("reverse_binary_string {}", reverse_binary_string);
 
-    let appended = append_binary_string(reverse_binary_string, &"user".to_string(), &"ASDF".to_string());
+    let appended = with_sender(&reverse_binary_string, "user", "ASDF").unwrap();
 
     println!("appended_binary_string {}", appended);
 
@@ -119,31 +119,24 @@ fn main() {
 }
 
 
-fn append_binary_string(binary_string: String, key: &String, value: &String) -> Binary {
-    let binary = Binary::from_base64(&binary_string).unwrap();;
-    
-    let mut binary_vector = binary.0.to_vec();
-
-    // Pop last two brackets
-    binary_vector.pop();
-    binary_vector.pop();
-
-    binary_vector.push(b',');
-    binary_vector.push(b'"');
-    for elem in key.chars() {
-        binary_vector.push(elem as u8);
-    }
-    binary_vector.push(b'"');
-    binary_vector.push(b':');
-    binary_vector.push(b'"');
-    for elem in value.chars() {
-        binary_vector.push(elem as u8);
+// Adds `key` to the fields of a single variant execute msg, as the contract does with the
+// sender of a decided input (see trust-boost/src/payload.rs)
+fn with_sender(binary_string: &str, key: &str, value: &str) -> Result<Binary, String> {
+    let binary = Binary::from_base64(binary_string).map_err(|err| err.to_string())?;
+    let mut msg: Value = serde_json::from_slice(&binary).map_err(|err| err.to_string())?;
+    let variant = match msg.as_object_mut() {
+        Some(variant) if variant.len() == 1 => variant,
+        _ => return Err("expected an object with a single execute variant".to_string()),
+    };
+    let (name, fields) = variant.iter_mut().next().unwrap();
+    let fields = fields
+        .as_object_mut()
+        .ok_or_else(|| format!("variant {:?} is not an object of fields", name))?;
+    if fields.contains_key(key) {
+        return Err(format!("variant {:?} already has a {:?} field", name, key));
     }
-    binary_vector.push(b'"');
-    binary_vector.push(b'}');
-    binary_vector.push(b'}');
-
-    Binary(binary_vector)
+    fields.insert(key.to_string(), Value::String(value.to_string()));
+    Ok(Binary(serde_json::to_vec(&msg).map_err(|err| err.to_string())?))
 }
 
 
@@ -49,11 +49,18 @@ console.log(privkey);
 let binary_string = "eyJyZWdpc3RlciI6eyJuYW1lIjoidGVzdF9mcm9tX3RydXN0Ym9vc3Rfc2VwdCJ9fQ==";
 binary_string = "eyJyZWdpc3Rlcl90YiI6eyJuYW1lIjoidGVzdF9mcm9tX3RydXN0Ym9vc3Rfc2VwdCJ9fQ==";
 
-const len = binary_string.length;
-let bytes = new Uint8Array(len);
-for (var i = 0; i < len; i++) {
-   bytes[i] = binary_string.charCodeAt(i);
-}
+// the signature also covers the deployment domain, the nonce and the expiry (see signature.rs),
+// they have to match DOMAIN, NONCE, EXPIRES_AT_HEIGHT, INPUT_CONTRACT and FUNDS of the helper
+const domain = process.env.DOMAIN || "trustboost-local";
+const nonce = process.env.NONCE || "0";
+const expires_at_height = process.env.EXPIRES_AT_HEIGHT || "1000000";
+// the target contract, empty for the default one
+const input_contract = process.env.INPUT_CONTRACT || "";
+// coins sent along, as FUNDS of the helper, e.g. 100stake,5uatom
+const funds = process.env.FUNDS || "";
+const sign_text = `trustboost input\ndomain: ${domain}\nnonce: ${nonce}\nexpires: height ${expires_at_height}\ncontract: ${input_contract}\nfunds: ${funds}\nbinary: ${binary_string}`;
+
+let bytes = new TextEncoder().encode(sign_text);
 let messageHash = sha256(bytes);
 
 console.log("Message Hash");
@@ -2,7 +2,7 @@ use cosmwasm_std::{
     StdResult, Order, StdError, Storage, IbcTimeout, Env, Api
 };
 
-use crate::utils::{get_id_channel_pair_from_storage};
+use crate::utils::get_instance_peers;
 use crate::state::{
     STATE, HIGHEST_ABORT, DEBUG
 };
@@ -19,22 +19,22 @@ use crate::view_change::{
 use crate::ibc_msg::Msg;
 
 pub fn handle_abort(storage: &mut dyn Storage, 
-                    queue: &mut Vec<Vec<Msg>>, view: u32, 
+                    queue: &mut Vec<Vec<Msg>>, instance_id: u64, view: u32, 
                     sender_chain_id: u32, timeout: IbcTimeout,
                     env: &Env,
                     api: &dyn Api,
                     ) -> Result<(), StdError> {
-    let mut state = STATE.load(storage)?;
+    let mut state = STATE.load(storage, instance_id)?;
     
     let mut loaded_val: i32 = 0;
-    let option = HIGHEST_ABORT.load(storage, sender_chain_id);
+    let option = HIGHEST_ABORT.load(storage, (instance_id, sender_chain_id));
     match option {
         Ok(val) => loaded_val = val,
         Err(_) => return Err(StdError::GenericErr { msg: "handle_abort cannot find loadedVal".to_string()} ), 
     }
 
     if ((loaded_val + 1) as u32)< (view+1) {
-        HIGHEST_ABORT.update(storage, sender_chain_id, |option| -> StdResult<i32> {
+        HIGHEST_ABORT.update(storage, (instance_id, sender_chain_id), |option| -> StdResult<i32> {
             match option {
                 Some(_val) => Ok(view as i32),
                 None => Ok(view as i32),
@@ -42,6 +42,7 @@ pub fn handle_abort(storage: &mut dyn Storage,
         })?;
 
         let highest_abort_vector_pair: StdResult<Vec<_>> = HIGHEST_ABORT
+            .prefix(instance_id)
             .range(storage, None, None, Order::Ascending)
             .collect();
         let mut vector_values = match highest_abort_vector_pair {
@@ -57,20 +58,20 @@ pub fn handle_abort(storage: &mut dyn Storage,
         // F+1 highest meaning n-F+1
         let u = vector_values[ (vector_values.len()-(state.F+1) as usize)]; 
         let mut loaded_val: i32 = 0;
-        match HIGHEST_ABORT.load(storage, sender_chain_id) {
+        match HIGHEST_ABORT.load(storage, (instance_id, sender_chain_id)) {
             Ok(val) => loaded_val = val,
             Err(_) => return Err(StdError::GenericErr { msg: "handle_abort cannot find loaded_val part 2".to_string()} ), 
         }
 
         if u > loaded_val {
             if u > -1 {
-                let abort_packet = Msg::Abort { view: u as u32, chain_id: state.chain_id};
-                let channel_ids = get_id_channel_pair_from_storage(storage)?;
+                let abort_packet = Msg::Abort { instance_id, view: u as u32, chain_id: state.chain_id};
+                let channel_ids = get_instance_peers(storage, instance_id)?;
                 DEBUG.save(storage, 1200, &"CLONE_ABORT_PACKET".to_string())?;
                 for (chain_id, _channel_id) in &channel_ids {
                     queue[*chain_id as usize].push(abort_packet.clone());
                 }
-                HIGHEST_ABORT.update(storage, sender_chain_id, |option| -> StdResult<i32> {
+                HIGHEST_ABORT.update(storage, (instance_id, sender_chain_id), |option| -> StdResult<i32> {
                     match option {
                         Some(_val) => Ok(u),
                         None => Ok(u),
@@ -80,6 +81,7 @@ pub fn handle_abort(storage: &mut dyn Storage,
         }
 
         let highest_abort_vector_pair: StdResult<Vec<_>> = HIGHEST_ABORT
+            .prefix(instance_id)
             .range(storage, None, None, Order::Ascending)
             .collect();
         let mut vector_values = match highest_abort_vector_pair {
@@ -97,13 +99,15 @@ pub fn handle_abort(storage: &mut dyn Storage,
         let w = vector_values[idx as usize];
         if (w+1) as u32 >= state.view {
             let previous_view = state.view;
-            state.view = (w + 1) as u32;
-            state.primary = (state.view % state.n) + 1;
-            state.start_time = env.block.time;
-            STATE.save(storage, &state)?;
+            if previous_view != (w + 1) as u32 {
+                state.enter_view((w + 1) as u32, env.block.time);
+            } else {
+                state.start_time = env.block.time;
+            }
+            STATE.save(storage, instance_id, &state)?;
             if previous_view != state.view {
                 DEBUG.save(storage, 1300, &"TRIGGER_VIEW_CHANGE_NEW".to_string())?;
-                match reset_view_specific_maps(storage) {
+                match reset_view_specific_maps(storage, instance_id) {
                     Ok(_) => {
                         
                     }
@@ -113,7 +117,7 @@ pub fn handle_abort(storage: &mut dyn Storage,
                     }
                 }         
                 
-                let result = append_queue_view_change(storage, queue, timeout, env, api);
+                let result = append_queue_view_change(storage, queue, instance_id, timeout, env, api);
                 match result {
                     Ok(_) => {
 
@@ -0,0 +1,159 @@
+use cosmwasm_std::{StdResult, Storage};
+
+use crate::ibc_msg::Msg;
+use crate::state::{Batch, Fault, Reconfig, FAULTS, STATE};
+
+// Fault injection, compiled only with the "byzantine" feature (and in tests).
+// A faulty chain runs the protocol correctly on its own state, the faults set through
+// ExecuteMsg::SetFaults only rewrite the msg queues it is about to send to its peers.
+
+pub fn set_faults(store: &mut dyn Storage, faults: Vec<Fault>) -> StdResult<()> {
+    FAULTS.save(store, &faults)
+}
+
+// Apply the configured faults to the msgs queued for every peer, indexed by chain_id
+pub fn corrupt(store: &dyn Storage, mut queue: Vec<Vec<Msg>>) -> StdResult<Vec<Vec<Msg>>> {
+    for fault in FAULTS.may_load(store)?.unwrap_or_default() {
+        for (chain_id, msgs) in queue.iter_mut().enumerate() {
+            *msgs = apply(store, &fault, chain_id as u32, std::mem::take(msgs))?;
+        }
+    }
+    Ok(queue)
+}
+
+fn apply(store: &dyn Storage, fault: &Fault, dst: u32, msgs: Vec<Msg>) -> StdResult<Vec<Msg>> {
+    let msgs = match fault {
+        Fault::EquivocatingPrimary => msgs
+            .into_iter()
+            .map(|msg| match msg {
+                Msg::Propose { instance_id, chain_id, k, view, .. } if dst % 2 == 1 => {
+                    Msg::Propose { instance_id, chain_id, k, v: Batch::noop(), view }
+                },
+                msg => msg,
+            })
+            .collect(),
+        Fault::Withhold { msg_types } => msgs
+            .into_iter()
+            .filter(|msg| !msg_types.iter().any(|name| name == msg.name()))
+            .collect(),
+        Fault::StaleView { by } => msgs.into_iter().map(|msg| lower_view(msg, *by)).collect(),
+        Fault::ForgeSuggest { key2, prev_key2, key3 } => msgs
+            .into_iter()
+            .map(|msg| match msg {
+                Msg::Suggest { instance_id, chain_id, view, key2_val, key3_val, .. } => Msg::Suggest {
+                    instance_id,
+                    chain_id,
+                    view,
+                    key2: *key2,
+                    key2_val,
+                    prev_key2: *prev_key2,
+                    key3: *key3,
+                    key3_val,
+                },
+                msg => msg,
+            })
+            .collect(),
+        Fault::ForgeInputs => msgs
+            .into_iter()
+            .map(|msg| match msg {
+                Msg::Propose { instance_id, chain_id, k, mut v, view } => {
+                    for input in v.inputs.iter_mut() {
+                        if let Some(byte) = input.signature.first_mut() {
+                            *byte ^= 1;
+                        }
+                    }
+                    Msg::Propose { instance_id, chain_id, k, v, view }
+                },
+                msg => msg,
+            })
+            .collect(),
+        Fault::PadBatch { to } => msgs
+            .into_iter()
+            .map(|msg| match msg {
+                Msg::Propose { instance_id, chain_id, k, mut v, view } if !v.inputs.is_empty() => {
+                    v.inputs = v.inputs.iter().cycle().take(*to as usize).cloned().collect();
+                    Msg::Propose { instance_id, chain_id, k, v, view }
+                },
+                msg => msg,
+            })
+            .collect(),
+        Fault::ForgeReconfig { n, f } => msgs
+            .into_iter()
+            .map(|msg| match msg {
+                Msg::Propose { instance_id, chain_id, k, mut v, view } => {
+                    v.reconfig = Some(Reconfig { n: *n, f: *f, three_chain_demo: false, members: vec![] });
+                    Msg::Propose { instance_id, chain_id, k, v, view }
+                },
+                msg => msg,
+            })
+            .collect(),
+        Fault::ProposeInputs { inputs } => msgs
+            .into_iter()
+            .map(|msg| match msg {
+                Msg::Propose { instance_id, chain_id, k, mut v, view } => {
+                    v.inputs = inputs.clone();
+                    Msg::Propose { instance_id, chain_id, k, v, view }
+                },
+                msg => msg,
+            })
+            .collect(),
+        Fault::ReplayDone { from } => {
+            let replayed = match STATE.may_load(store, *from)?.and_then(|state| state.done) {
+                Some(val) => val,
+                None => return Ok(msgs),
+            };
+            let mut instances: Vec<u64> = msgs
+                .iter()
+                .map(|msg| msg.instance_id())
+                .filter(|instance_id| instance_id > from)
+                .collect();
+            instances.dedup();
+            let mut msgs = msgs;
+            msgs.extend(instances.into_iter().map(|instance_id| Msg::Done { instance_id, digest: replayed.digest() }));
+            msgs
+        },
+    };
+    Ok(msgs)
+}
+
+fn lower_view(msg: Msg, by: u32) -> Msg {
+    let stale = |view: u32| view.saturating_sub(by);
+    match msg {
+        Msg::Request { instance_id, view, chain_id } => Msg::Request { instance_id, view: stale(view), chain_id },
+        Msg::Suggest { instance_id, chain_id, view, key2, key2_val, prev_key2, key3, key3_val } => Msg::Suggest {
+            instance_id, chain_id, view: stale(view), key2, key2_val, prev_key2, key3, key3_val,
+        },
+        Msg::Proof { instance_id, key1, key1_val, prev_key1, view } => Msg::Proof {
+            instance_id, key1, key1_val, prev_key1, view: stale(view),
+        },
+        Msg::Abort { instance_id, view, chain_id } => Msg::Abort { instance_id, view: stale(view), chain_id },
+        Msg::Propose { instance_id, chain_id, k, v, view } => Msg::Propose { instance_id, chain_id, k, v, view: stale(view) },
+        Msg::Echo { instance_id, digest, view } => Msg::Echo { instance_id, digest, view: stale(view) },
+        Msg::Key1 { instance_id, digest, view } => Msg::Key1 { instance_id, digest, view: stale(view) },
+        Msg::Key2 { instance_id, digest, view } => Msg::Key2 { instance_id, digest, view: stale(view) },
+        Msg::Key3 { instance_id, digest, view } => Msg::Key3 { instance_id, digest, view: stale(view) },
+        Msg::Lock { instance_id, digest, view } => Msg::Lock { instance_id, digest, view: stale(view) },
+        Msg::Done { .. } | Msg::Fetch { .. } | Msg::Value { .. } | Msg::Report { .. } => msg,
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use cosmwasm_std::testing::mock_dependencies;
+
+    #[test]
+    fn faults_rewrite_outgoing_queues() {
+        let mut deps = mock_dependencies();
+        let store = deps.as_mut().storage;
+        let propose = Msg::Propose { instance_id: 0, chain_id: 1, k: 0, v: Batch::default(), view: 2 };
+        let echo = Msg::Echo { instance_id: 0, digest: Batch::default().digest(), view: 2 };
+        let queue = vec![vec![propose.clone(), echo.clone()]; 2];
+        assert_eq!(corrupt(store, queue.clone()).unwrap(), queue);
+
+        let faults = vec![Fault::Withhold { msg_types: vec!["Echo".to_string()] }, Fault::StaleView { by: 1 }];
+        set_faults(store, faults).unwrap();
+        let stale = Msg::Propose { instance_id: 0, chain_id: 1, k: 0, v: Batch::default(), view: 1 };
+        assert_eq!(corrupt(store, queue).unwrap(), vec![vec![stale]; 2]);
+    }
+}
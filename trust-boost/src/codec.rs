@@ -0,0 +1,550 @@
+use std::convert::TryInto;
+
+use cosmwasm_std::{from_slice, to_binary, Binary, Coin, StdError, StdResult, Storage, Timestamp, Uint128};
+
+use crate::ibc_msg::{Msg, PacketMsg};
+use crate::state::{Batch, Encoding, Expiry, Member, ReceiptStatus, Reconfig, SignatureScheme, TBInput, CHANNEL_ENCODING};
+use crate::utils::{IBC_APP_VERSION, IBC_APP_VERSION_COMPACT};
+
+// Packets go over a channel either as JSON or in a compact binary encoding, picked by the
+// channel version both ends agreed on in the handshake. JSON stays the default so channels
+// opened with `simple_storage` keep working. The compact encoding writes every field in
+// declaration order, integers as fixed width big endian, bools as one byte and every
+// variable length field prefixed with its length (u32, big endian):
+//
+//   packet = 0 #msgs msg* | 1 chain_id
+//   msg    = tag field*, tags numbered in the order of the Msg variants, Request = 0
+//   value  = #inputs input* reconfig, as in digest.rs
+//   status = 0 pending | 1 succeeded | 2 error | 3 reason, a Report carries #statuses status*
+//
+// Acknowledgements stay JSON on every channel.
+
+impl Encoding {
+    pub fn from_version(version: &str) -> Option<Encoding> {
+        match version {
+            IBC_APP_VERSION => Some(Encoding::Json),
+            IBC_APP_VERSION_COMPACT => Some(Encoding::Compact),
+            _ => None,
+        }
+    }
+}
+
+// Channels connected before the encoding was recorded use JSON
+pub fn channel_encoding(store: &dyn Storage, channel_id: &str) -> StdResult<Encoding> {
+    Ok(CHANNEL_ENCODING.may_load(store, channel_id.to_string())?.unwrap_or(Encoding::Json))
+}
+
+pub fn encode_for_channel(store: &dyn Storage, channel_id: &str, packet: &PacketMsg) -> StdResult<Binary> {
+    encode_packet(packet, channel_encoding(store, channel_id)?)
+}
+
+pub fn encode_packet(packet: &PacketMsg, encoding: Encoding) -> StdResult<Binary> {
+    match encoding {
+        Encoding::Json => to_binary(packet),
+        Encoding::Compact => {
+            let mut out = Vec::new();
+            put_packet(&mut out, packet);
+            Ok(Binary::from(out))
+        },
+    }
+}
+
+pub fn decode_packet(data: &[u8], encoding: Encoding) -> StdResult<PacketMsg> {
+    match encoding {
+        Encoding::Json => from_slice(data),
+        Encoding::Compact => {
+            let mut reader = Reader { data };
+            let packet = reader.packet()?;
+            if !reader.data.is_empty() {
+                return Err(parse_err(format!("{} trailing bytes", reader.data.len())));
+            }
+            Ok(packet)
+        },
+    }
+}
+
+fn put_len(out: &mut Vec<u8>, len: usize) {
+    out.extend_from_slice(&(len as u32).to_be_bytes());
+}
+
+fn put_bytes(out: &mut Vec<u8>, bytes: &[u8]) {
+    put_len(out, bytes.len());
+    out.extend_from_slice(bytes);
+}
+
+fn put_option(out: &mut Vec<u8>, value: &Option<String>) {
+    match value {
+        None => out.push(0),
+        Some(value) => {
+            out.push(1);
+            put_bytes(out, value.as_bytes());
+        },
+    }
+}
+
+fn put_input(out: &mut Vec<u8>, input: &TBInput) {
+    put_bytes(out, input.binary.as_bytes());
+    put_bytes(out, &input.public_key);
+    put_bytes(out, &input.signature);
+    match &input.scheme {
+        SignatureScheme::Secp256k1 => out.push(0),
+        SignatureScheme::Ed25519 => out.push(1),
+        SignatureScheme::Adr036 { signer } => {
+            out.push(2);
+            put_bytes(out, signer.as_bytes());
+        },
+    }
+    put_bytes(out, input.domain.as_bytes());
+    out.extend_from_slice(&input.nonce.to_be_bytes());
+    match &input.expires {
+        Expiry::AtHeight(height) => {
+            out.push(0);
+            out.extend_from_slice(&height.to_be_bytes());
+        },
+        Expiry::AtTime(time) => {
+            out.push(1);
+            out.extend_from_slice(&time.nanos().to_be_bytes());
+        },
+    }
+    put_option(out, &input.contract);
+    put_len(out, input.funds.len());
+    for coin in &input.funds {
+        put_bytes(out, coin.denom.as_bytes());
+        out.extend_from_slice(&coin.amount.u128().to_be_bytes());
+    }
+}
+
+fn put_reconfig(out: &mut Vec<u8>, reconfig: &Reconfig) {
+    out.extend_from_slice(&reconfig.n.to_be_bytes());
+    out.extend_from_slice(&reconfig.f.to_be_bytes());
+    out.push(reconfig.three_chain_demo as u8);
+    put_len(out, reconfig.members.len());
+    for (chain_id, Member { port_id, connection_id }) in &reconfig.members {
+        out.extend_from_slice(&chain_id.to_be_bytes());
+        put_option(out, port_id);
+        put_option(out, connection_id);
+    }
+}
+
+pub(crate) fn put_batch(out: &mut Vec<u8>, batch: &Batch) {
+    put_len(out, batch.inputs.len());
+    for input in &batch.inputs {
+        put_input(out, input);
+    }
+    match &batch.reconfig {
+        None => out.push(0),
+        Some(reconfig) => {
+            out.push(1);
+            put_reconfig(out, reconfig);
+        },
+    }
+}
+
+fn put_packet(out: &mut Vec<u8>, packet: &PacketMsg) {
+    match packet {
+        PacketMsg::MsgQueue(msgs) => {
+            out.push(0);
+            put_len(out, msgs.len());
+            for msg in msgs {
+                put_msg(out, msg);
+            }
+        },
+        PacketMsg::WhoAmI { chain_id } => {
+            out.push(1);
+            out.extend_from_slice(&chain_id.to_be_bytes());
+        },
+    }
+}
+
+fn put_msg(out: &mut Vec<u8>, msg: &Msg) {
+    match msg {
+        Msg::Request { instance_id, view, chain_id } => {
+            out.push(0);
+            out.extend_from_slice(&instance_id.to_be_bytes());
+            out.extend_from_slice(&view.to_be_bytes());
+            out.extend_from_slice(&chain_id.to_be_bytes());
+        },
+        Msg::Suggest { instance_id, chain_id, view, key2, key2_val, prev_key2, key3, key3_val } => {
+            out.push(1);
+            out.extend_from_slice(&instance_id.to_be_bytes());
+            out.extend_from_slice(&chain_id.to_be_bytes());
+            out.extend_from_slice(&view.to_be_bytes());
+            out.extend_from_slice(&key2.to_be_bytes());
+            put_batch(out, key2_val);
+            out.extend_from_slice(&prev_key2.to_be_bytes());
+            out.extend_from_slice(&key3.to_be_bytes());
+            put_batch(out, key3_val);
+        },
+        Msg::Proof { instance_id, key1, key1_val, prev_key1, view } => {
+            out.push(2);
+            out.extend_from_slice(&instance_id.to_be_bytes());
+            out.extend_from_slice(&key1.to_be_bytes());
+            put_batch(out, key1_val);
+            out.extend_from_slice(&prev_key1.to_be_bytes());
+            out.extend_from_slice(&view.to_be_bytes());
+        },
+        Msg::Abort { instance_id, view, chain_id } => {
+            out.push(3);
+            out.extend_from_slice(&instance_id.to_be_bytes());
+            out.extend_from_slice(&view.to_be_bytes());
+            out.extend_from_slice(&chain_id.to_be_bytes());
+        },
+        Msg::Propose { instance_id, chain_id, k, v, view } => {
+            out.push(4);
+            out.extend_from_slice(&instance_id.to_be_bytes());
+            out.extend_from_slice(&chain_id.to_be_bytes());
+            out.extend_from_slice(&k.to_be_bytes());
+            put_batch(out, v);
+            out.extend_from_slice(&view.to_be_bytes());
+        },
+        Msg::Echo { instance_id, digest, view } => put_vote(out, 5, *instance_id, digest, Some(*view)),
+        Msg::Key1 { instance_id, digest, view } => put_vote(out, 6, *instance_id, digest, Some(*view)),
+        Msg::Key2 { instance_id, digest, view } => put_vote(out, 7, *instance_id, digest, Some(*view)),
+        Msg::Key3 { instance_id, digest, view } => put_vote(out, 8, *instance_id, digest, Some(*view)),
+        Msg::Lock { instance_id, digest, view } => put_vote(out, 9, *instance_id, digest, Some(*view)),
+        Msg::Done { instance_id, digest } => put_vote(out, 10, *instance_id, digest, None),
+        Msg::Fetch { instance_id, digest } => put_vote(out, 11, *instance_id, digest, None),
+        Msg::Value { instance_id, val } => {
+            out.push(12);
+            out.extend_from_slice(&instance_id.to_be_bytes());
+            put_batch(out, val);
+        },
+        Msg::Report { instance_id, statuses } => {
+            out.push(13);
+            out.extend_from_slice(&instance_id.to_be_bytes());
+            put_len(out, statuses.len());
+            for status in statuses {
+                put_status(out, status);
+            }
+        },
+    }
+}
+
+fn put_status(out: &mut Vec<u8>, status: &ReceiptStatus) {
+    match status {
+        ReceiptStatus::Pending {} => out.push(0),
+        ReceiptStatus::Succeeded {} => out.push(1),
+        ReceiptStatus::Failed { error } => {
+            out.push(2);
+            put_bytes(out, error.as_bytes());
+        },
+        ReceiptStatus::Skipped { reason } => {
+            out.push(3);
+            put_bytes(out, reason.as_bytes());
+        },
+    }
+}
+
+// Votes and Fetch: instance_id, digest and the view if the msg has one
+fn put_vote(out: &mut Vec<u8>, tag: u8, instance_id: u64, digest: &Binary, view: Option<u32>) {
+    out.push(tag);
+    out.extend_from_slice(&instance_id.to_be_bytes());
+    put_bytes(out, digest);
+    if let Some(view) = view {
+        out.extend_from_slice(&view.to_be_bytes());
+    }
+}
+
+fn parse_err(msg: impl ToString) -> StdError {
+    StdError::parse_err("PacketMsg", msg)
+}
+
+struct Reader<'a> {
+    data: &'a [u8],
+}
+
+impl<'a> Reader<'a> {
+    fn take(&mut self, len: usize) -> StdResult<&'a [u8]> {
+        if self.data.len() < len {
+            return Err(parse_err(format!("expected {} more bytes, got {}", len, self.data.len())));
+        }
+        let (head, rest) = self.data.split_at(len);
+        self.data = rest;
+        Ok(head)
+    }
+
+    fn u8(&mut self) -> StdResult<u8> {
+        Ok(self.take(1)?[0])
+    }
+
+    fn bool(&mut self) -> StdResult<bool> {
+        match self.u8()? {
+            0 => Ok(false),
+            1 => Ok(true),
+            b => Err(parse_err(format!("invalid bool {}", b))),
+        }
+    }
+
+    fn u32(&mut self) -> StdResult<u32> {
+        Ok(u32::from_be_bytes(self.take(4)?.try_into().unwrap()))
+    }
+
+    fn i32(&mut self) -> StdResult<i32> {
+        Ok(i32::from_be_bytes(self.take(4)?.try_into().unwrap()))
+    }
+
+    fn u64(&mut self) -> StdResult<u64> {
+        Ok(u64::from_be_bytes(self.take(8)?.try_into().unwrap()))
+    }
+
+    fn len(&mut self) -> StdResult<usize> {
+        Ok(self.u32()? as usize)
+    }
+
+    fn bytes(&mut self) -> StdResult<Vec<u8>> {
+        let len = self.len()?;
+        Ok(self.take(len)?.to_vec())
+    }
+
+    fn string(&mut self) -> StdResult<String> {
+        String::from_utf8(self.bytes()?).map_err(parse_err)
+    }
+
+    fn option(&mut self) -> StdResult<Option<String>> {
+        match self.bool()? {
+            false => Ok(None),
+            true => Ok(Some(self.string()?)),
+        }
+    }
+
+    fn input(&mut self) -> StdResult<TBInput> {
+        let (binary, public_key, signature) = (self.string()?, self.bytes()?, self.bytes()?);
+        let scheme = match self.u8()? {
+            0 => SignatureScheme::Secp256k1,
+            1 => SignatureScheme::Ed25519,
+            2 => SignatureScheme::Adr036 { signer: self.string()? },
+            tag => return Err(parse_err(format!("unknown signature scheme {}", tag))),
+        };
+        let (domain, nonce) = (self.string()?, self.u64()?);
+        let expires = match self.u8()? {
+            0 => Expiry::AtHeight(self.u64()?),
+            1 => Expiry::AtTime(Timestamp::from_nanos(self.u64()?)),
+            tag => return Err(parse_err(format!("unknown expiry {}", tag))),
+        };
+        let contract = self.option()?;
+        let mut funds = Vec::new();
+        for _ in 0..self.len()? {
+            let denom = self.string()?;
+            let amount = u128::from_be_bytes(self.take(16)?.try_into().unwrap());
+            funds.push(Coin { denom, amount: Uint128::new(amount) });
+        }
+        Ok(TBInput { binary, public_key, signature, scheme, domain, nonce, expires, contract, funds })
+    }
+
+    fn reconfig(&mut self) -> StdResult<Reconfig> {
+        let n = self.u32()?;
+        let f = self.u32()?;
+        let three_chain_demo = self.bool()?;
+        // counts are not trusted for allocation, every entry has to be read first
+        let mut members = Vec::new();
+        for _ in 0..self.len()? {
+            let chain_id = self.u32()?;
+            members.push((chain_id, Member { port_id: self.option()?, connection_id: self.option()? }));
+        }
+        Ok(Reconfig { n, f, three_chain_demo, members })
+    }
+
+    fn batch(&mut self) -> StdResult<Batch> {
+        let mut inputs = Vec::new();
+        for _ in 0..self.len()? {
+            inputs.push(self.input()?);
+        }
+        let reconfig = match self.bool()? {
+            false => None,
+            true => Some(self.reconfig()?),
+        };
+        Ok(Batch { inputs, reconfig })
+    }
+
+    fn status(&mut self) -> StdResult<ReceiptStatus> {
+        match self.u8()? {
+            0 => Ok(ReceiptStatus::Pending {}),
+            1 => Ok(ReceiptStatus::Succeeded {}),
+            2 => Ok(ReceiptStatus::Failed { error: self.string()? }),
+            3 => Ok(ReceiptStatus::Skipped { reason: self.string()? }),
+            tag => Err(parse_err(format!("unknown receipt status {}", tag))),
+        }
+    }
+
+    fn packet(&mut self) -> StdResult<PacketMsg> {
+        match self.u8()? {
+            0 => {
+                let mut msgs = Vec::new();
+                for _ in 0..self.len()? {
+                    msgs.push(self.msg()?);
+                }
+                Ok(PacketMsg::MsgQueue(msgs))
+            },
+            1 => Ok(PacketMsg::WhoAmI { chain_id: self.u32()? }),
+            tag => Err(parse_err(format!("unknown packet tag {}", tag))),
+        }
+    }
+
+    fn msg(&mut self) -> StdResult<Msg> {
+        let tag = self.u8()?;
+        let instance_id = self.u64()?;
+        let msg = match tag {
+            0 => Msg::Request { instance_id, view: self.u32()?, chain_id: self.u32()? },
+            1 => Msg::Suggest {
+                instance_id,
+                chain_id: self.u32()?,
+                view: self.u32()?,
+                key2: self.u32()?,
+                key2_val: self.batch()?,
+                prev_key2: self.i32()?,
+                key3: self.u32()?,
+                key3_val: self.batch()?,
+            },
+            2 => Msg::Proof {
+                instance_id,
+                key1: self.u32()?,
+                key1_val: self.batch()?,
+                prev_key1: self.i32()?,
+                view: self.u32()?,
+            },
+            3 => Msg::Abort { instance_id, view: self.u32()?, chain_id: self.u32()? },
+            4 => Msg::Propose {
+                instance_id,
+                chain_id: self.u32()?,
+                k: self.u32()?,
+                v: self.batch()?,
+                view: self.u32()?,
+            },
+            5 => Msg::Echo { instance_id, digest: self.bytes()?.into(), view: self.u32()? },
+            6 => Msg::Key1 { instance_id, digest: self.bytes()?.into(), view: self.u32()? },
+            7 => Msg::Key2 { instance_id, digest: self.bytes()?.into(), view: self.u32()? },
+            8 => Msg::Key3 { instance_id, digest: self.bytes()?.into(), view: self.u32()? },
+            9 => Msg::Lock { instance_id, digest: self.bytes()?.into(), view: self.u32()? },
+            10 => Msg::Done { instance_id, digest: self.bytes()?.into() },
+            11 => Msg::Fetch { instance_id, digest: self.bytes()?.into() },
+            12 => Msg::Value { instance_id, val: self.batch()? },
+            13 => {
+                let mut statuses = Vec::new();
+                for _ in 0..self.len()? {
+                    statuses.push(self.status()?);
+                }
+                Msg::Report { instance_id, statuses }
+            },
+            tag => return Err(parse_err(format!("unknown msg tag {}", tag))),
+        };
+        Ok(msg)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn batch(reconfig: bool) -> Batch {
+        let input = TBInput {
+            binary: Binary::from(br#"{"register":{"name":"alice"}}"#).to_base64(),
+            public_key: vec![2; 33],
+            signature: vec![7; 64],
+            scheme: SignatureScheme::Secp256k1,
+            domain: "tb-test".to_string(),
+            nonce: 9,
+            expires: Expiry::AtHeight(100),
+            ..TBInput::default()
+        };
+        let inputs = vec![
+            input.clone(),
+            TBInput {
+                scheme: SignatureScheme::Ed25519,
+                expires: Expiry::AtTime(Timestamp::from_seconds(5)),
+                contract: Some("wasm1target".to_string()),
+                funds: vec![Coin::new(5, "stake"), Coin::new(u128::MAX, "uatom")],
+                ..input.clone()
+            },
+            TBInput { scheme: SignatureScheme::Adr036 { signer: "cosmos1signer".to_string() }, ..input },
+        ];
+        let reconfig = reconfig.then(|| Reconfig {
+            n: 5,
+            f: 1,
+            three_chain_demo: false,
+            members: vec![
+                (3, Member::default()),
+                (4, Member { port_id: Some("wasm.4".to_string()), connection_id: Some("connection-4".to_string()) }),
+            ],
+        });
+        Batch { inputs, reconfig }
+    }
+
+    // one msg of every variant, a new variant has to be added here and to the compact encoding
+    fn every_msg() -> Vec<Msg> {
+        let (instance_id, view) = (7, 3);
+        let digest = batch(false).digest();
+        let msgs = vec![
+            Msg::Request { instance_id, view, chain_id: 2 },
+            Msg::Suggest { instance_id, chain_id: 1, view, key2: 2, key2_val: batch(false), prev_key2: -1, key3: 1, key3_val: batch(true) },
+            Msg::Proof { instance_id, key1: 2, key1_val: batch(true), prev_key1: -1, view },
+            Msg::Abort { instance_id, view, chain_id: 0 },
+            Msg::Propose { instance_id, chain_id: 1, k: 2, v: Batch::noop(), view },
+            Msg::Echo { instance_id, digest: digest.clone(), view },
+            Msg::Key1 { instance_id, digest: digest.clone(), view },
+            Msg::Key2 { instance_id, digest: digest.clone(), view },
+            Msg::Key3 { instance_id, digest: digest.clone(), view },
+            Msg::Lock { instance_id, digest: digest.clone(), view },
+            Msg::Done { instance_id, digest: digest.clone() },
+            Msg::Fetch { instance_id, digest },
+            Msg::Value { instance_id, val: batch(true) },
+            Msg::Report {
+                instance_id,
+                statuses: vec![
+                    ReceiptStatus::Pending {},
+                    ReceiptStatus::Succeeded {},
+                    ReceiptStatus::Failed { error: "NameTaken".to_string() },
+                    ReceiptStatus::Skipped { reason: "invalid signature".to_string() },
+                ],
+            },
+        ];
+        for msg in &msgs {
+            // fails to compile when a variant is missing above
+            match msg {
+                Msg::Request { .. } | Msg::Suggest { .. } | Msg::Proof { .. } | Msg::Abort { .. }
+                | Msg::Propose { .. } | Msg::Echo { .. } | Msg::Key1 { .. } | Msg::Key2 { .. }
+                | Msg::Key3 { .. } | Msg::Lock { .. } | Msg::Done { .. } | Msg::Fetch { .. }
+                | Msg::Value { .. } | Msg::Report { .. } => {},
+            }
+        }
+        msgs
+    }
+
+    #[test]
+    fn every_msg_round_trips_in_both_encodings() {
+        for encoding in [Encoding::Json, Encoding::Compact] {
+            for msg in every_msg() {
+                let packet = PacketMsg::MsgQueue(vec![msg.clone()]);
+                let data = encode_packet(&packet, encoding).unwrap();
+                assert_eq!(decode_packet(&data, encoding).unwrap(), packet, "{} in {:?}", msg.name(), encoding);
+            }
+            let queue = PacketMsg::MsgQueue(every_msg());
+            assert_eq!(decode_packet(&encode_packet(&queue, encoding).unwrap(), encoding).unwrap(), queue);
+            let who_am_i = PacketMsg::WhoAmI { chain_id: 3 };
+            assert_eq!(decode_packet(&encode_packet(&who_am_i, encoding).unwrap(), encoding).unwrap(), who_am_i);
+        }
+    }
+
+    #[test]
+    fn compact_is_smaller_and_rejects_malformed_packets() {
+        let queue = PacketMsg::MsgQueue(every_msg());
+        let json = encode_packet(&queue, Encoding::Json).unwrap();
+        let compact = encode_packet(&queue, Encoding::Compact).unwrap();
+        println!("MsgQueue with one msg of every type: {} bytes as JSON, {} bytes compact", json.len(), compact.len());
+        assert!(compact.len() * 2 < json.len());
+
+        // every strict prefix is truncated somewhere
+        for len in 0..compact.len() {
+            assert!(decode_packet(&compact[..len], Encoding::Compact).is_err());
+        }
+        let mut trailing = compact.to_vec();
+        trailing.push(0);
+        assert!(decode_packet(&trailing, Encoding::Compact).is_err());
+        assert!(decode_packet(&[2], Encoding::Compact).is_err());
+        assert!(decode_packet(&[0, 0, 0, 0, 1, 13, 0, 0, 0, 0, 0, 0, 0, 0], Encoding::Compact).is_err());
+        // a huge count does not allocate before the entries are read
+        assert!(decode_packet(&[0, 255, 255, 255, 255], Encoding::Compact).is_err());
+        // the encodings are not interchangeable
+        assert!(decode_packet(&json, Encoding::Compact).is_err());
+        assert!(decode_packet(&compact, Encoding::Json).is_err());
+    }
+}
@@ -2,35 +2,48 @@
 use cosmwasm_std::entry_point;
 use cosmwasm_std::{
     to_binary, Binary, Deps, DepsMut, Env, IbcMsg, IbcTimeout, MessageInfo, Order, Reply, Response,
-    StdError, StdResult, SubMsg, wasm_execute, WasmMsg, Storage, Addr, Timestamp,
+    StdError, StdResult, SubMsg, wasm_execute, WasmMsg, Storage, Addr, Timestamp, Empty,
 };
 
 use std::convert::TryInto;
 
 use cw2::set_contract_version;
+use cw_storage_plus::Bound;
 use std::cmp::Ordering;
 use std::collections::HashSet;
-use sha2::{Digest, Sha256};
 
 use crate::error::ContractError;
 use crate::ibc_msg::{Msg, PacketMsg};
 use crate::queue_handler::{receive_queue, send_all_party};
-use crate::utils::{get_timeout, init_receive_map, get_id_channel_pair_from_storage, convert_send_ibc_msg, derive_addr_from_pubkey, get_seconds_diff};
+use crate::utils::{get_timeout, init_instance, new_queue, get_id_channel_pair_from_storage, convert_send_ibc_msg, get_seconds_diff, ABORT_CHANNEL};
 use crate::view_change::{view_change, convert_queue_to_ibc_msgs, testing_add2queue};
 // use crate::ibc_msg::PacketMsg;
 use crate::msg::{
     AbortResponse, ChannelsResponse, DoneQueryResponse, EchoQueryResponse, ExecuteMsg,
-    HighestAbortResponse, HighestReqResponse, InstantiateMsg, Key1QueryResponse, Key2QueryResponse,
+    HighestAbortResponse, HighestReqResponse, InstancesResponse, InstantiateMsg, Key1QueryResponse, Key2QueryResponse, LogResponse,
     Key3QueryResponse, LockQueryResponse, QueryMsg, ReceivedSuggestResponse, SendAllUponResponse,
-    StateResponse, TestQueueResponse,
+    ReceiptsResponse, ReconfigsResponse, StateResponse, SudoMsg, TestQueueResponse,
 };
 use crate::state::{
-    State, CHANNELS, DEBUG, HIGHEST_ABORT, HIGHEST_REQ, RECEIVED, RECEIVED_ECHO, DEBUG_CTR,
-    RECEIVED_KEY1, RECEIVED_KEY2, RECEIVED_KEY3, RECEIVED_LOCK, STATE, TEST, RECEIVED_DONE, IBC_MSG_SEND_DEBUG, InputType,
-    DEBUG_RECEIVE_MSG
+    Config, CONFIG, OWNERSHIP, CHANNELS, DISCONNECTED, DEBUG, HIGHEST_ABORT, HIGHEST_REQ, RECEIVED, RECEIVED_ECHO, DEBUG_CTR,
+    RECEIVED_KEY1, RECEIVED_KEY2, RECEIVED_KEY3, RECEIVED_LOCK, STATE, TEST, RECEIVED_DONE, IBC_MSG_SEND_DEBUG, InputType, TBInput,
+    DEBUG_RECEIVE_MSG, LOG_LENGTH, EXEC_CURSOR, REPORT_CURSOR, PENDING_INPUTS, PENDING_RECONFIG, APPROVED_RECONFIG, TARGETS, ReceiptStatus, ESCROWS
 };
 use crate::state::{SEND_ALL_UPON, TEST_QUEUE};
-use crate::malicious_trigger::{trigger_done, trigger_done_2, trigger_abort, trigger_key1_diff_val, trigger_multi_propose};
+use crate::log::{read_log, execute_decided};
+use crate::mempool::{submit, take_batch};
+use crate::replay::check_fresh;
+use crate::payload::with_sender;
+use crate::receipts::{input_receipt, record_reply, slot_receipts};
+use crate::reports::{queue_reports, slot_reports};
+use crate::escrow::{deposit, refund_unused, settle};
+use crate::targets::{add_target, list_targets, remove_target, set_default_target, target_of};
+use crate::ownership::{ensure_owner, initialize_owner, update_ownership};
+use crate::values::remember;
+use crate::membership::{has_channel_quorum, membership, recompute_quorum, set_members, validate_quorum};
+use crate::retransmit::outstanding;
+use crate::reconfig::{can_start, propose, scheduled, RECONFIG_WINDOW};
+use crate::pipeline::{fill_pipeline, next_free_slot};
 
 // version info for migration info
 const CONTRACT_NAME: &str = "crates.io:simple-storage";
@@ -39,8 +52,13 @@ pub const REQUEST_REPLY_ID: u64 = 100;
 pub const SUGGEST_REPLY_ID: u64 = 101;
 pub const PROOF_REPLY_ID: u64 = 102;
 pub const PROPOSE_REPLY_ID: u64 = 103;
+// reply to the execution of a decided input on its target
+pub const EXECUTE_REPLY_ID: u64 = 104;
 pub const VIEW_TIMEOUT_SECONDS: u64 = 10;
 pub const ALLOW_DEBUG: bool = true;
+// default and max page size of list queries
+const DEFAULT_LIMIT: u32 = 10;
+const MAX_LIMIT: u32 = 30;
 
 #[cfg_attr(not(feature = "library"), entry_point)]
 pub fn instantiate(
@@ -49,23 +67,73 @@ pub fn instantiate(
     info: MessageInfo,
     msg: InstantiateMsg,
 ) -> Result<Response, ContractError> {
-    let state = State::new(msg.chain_id, msg.input, deps.api.addr_validate(&msg.contract_addr)?, env.block.time);
+    let mut config = Config::new(msg.chain_id, deps.api.addr_validate(&msg.contract_addr)?);
+    let three_chain_demo = msg.three_chain_demo.unwrap_or_default();
+    validate_quorum(msg.chain_id, msg.n, msg.f, three_chain_demo)?;
+    config.n = msg.n;
+    config.f = msg.f;
+    config.three_chain_demo = three_chain_demo;
+    config.pipeline_depth = msg.pipeline_depth.unwrap_or_default();
+    // slots of a new replica set must not be started before its reconfiguration is decided
+    if config.pipeline_depth > RECONFIG_WINDOW {
+        return Err(ContractError::CustomError {
+            val: format!("pipeline_depth must be at most {}", RECONFIG_WINDOW),
+        });
+    }
+    if let Some(max_batch_size) = msg.max_batch_size {
+        if max_batch_size == 0 {
+            return Err(ContractError::CustomError { val: "max_batch_size must be at least 1".to_string() });
+        }
+        config.max_batch_size = max_batch_size;
+    }
+    if let Some(max_mempool_size) = msg.max_mempool_size {
+        if max_mempool_size == 0 {
+            return Err(ContractError::CustomError { val: "max_mempool_size must be at least 1".to_string() });
+        }
+        config.max_mempool_size = max_mempool_size;
+    }
+    config.allowed_ports = msg.allowed_ports.unwrap_or_default();
+    config.allowed_connections = msg.allowed_connections.unwrap_or_default();
+    // the domain is part of the signed text, one line of it
+    if msg.domain.is_empty() || msg.domain.chars().any(char::is_control) {
+        return Err(ContractError::CustomError { val: "domain must be non-empty and on one line".to_string() });
+    }
+    config.domain = msg.domain;
+    let owner = match msg.owner {
+        Some(owner) => deps.api.addr_validate(&owner)?,
+        None => info.sender.clone(),
+    };
+    initialize_owner(deps.storage, owner.clone())?;
+    TARGETS.save(deps.storage, &config.contract_addr, &Empty {})?;
+    for contract in msg.targets.unwrap_or_default() {
+        add_target(deps.storage, deps.api, &contract)?;
+    }
+    if let Some(sender_field) = msg.sender_field {
+        if sender_field.is_empty() {
+            return Err(ContractError::CustomError { val: "sender_field must not be empty".to_string() });
+        }
+        config.sender_field = sender_field;
+    }
+    set_members(deps.storage, msg.members.unwrap_or_default())?;
     // let exe_msg = WasmMsg::Execute { contract_addr: , msg: , funds: () };
     // let exe_msg: ContractExecuteMsg = serde_json::from_str(&msg.msg).unwrap();
     // let exe_msg = wasm_execute(state.contract_addr.to_string(), &msg.msg, vec![])?;
-    STATE.save(deps.storage, &state)?;
-    for msg_type in vec!["Suggest", "Proof"] {
-        RECEIVED.save(deps.storage, msg_type.to_string(), &HashSet::new())?;
-    }
+    CONFIG.save(deps.storage, &config)?;
     set_contract_version(deps.storage, CONTRACT_NAME, CONTRACT_VERSION)?;
 
     DEBUG_CTR.save(deps.storage, &0)?;
+    // a chain joining through a reconfiguration starts its log at the activation slot
+    let first_slot = msg.first_slot.unwrap_or_default();
+    LOG_LENGTH.save(deps.storage, &first_slot)?;
+    EXEC_CURSOR.save(deps.storage, &first_slot)?;
+    REPORT_CURSOR.save(deps.storage, &first_slot)?;
+    PENDING_INPUTS.save(deps.storage, &Vec::new())?;
 
     // let action = |_| -> StdResult<u32> { Ok(u32::MAX) };
     Ok(Response::new()
         // .add_message(exe_msg)
         .add_attribute("method", "instantiate")
-        .add_attribute("owner", info.sender))
+        .add_attribute("owner", owner))
 }
 
 // execute entry_point is used for beginning new instance of IT-HS consensus
@@ -77,31 +145,48 @@ pub fn execute(
     msg: ExecuteMsg,
 ) -> Result<Response, ContractError> {
     match msg {
-        ExecuteMsg::Input { value } => handle_execute_input(deps, env, info, value),
-        ExecuteMsg::PreInput { value } => handle_execute_preinput(deps, env, info, value),
+        ExecuteMsg::Input { instance_id, value } => handle_execute_input(deps, env, info, instance_id, value),
+        ExecuteMsg::PreInput { instance_id, value } => handle_execute_preinput(deps, env, info, instance_id, value),
+        ExecuteMsg::Submit { value } => handle_execute_submit(deps, env, info, value),
+        ExecuteMsg::Refund { sender, nonce } => {
+            let refund = refund_unused(deps.storage, &env, sender, nonce)?;
+            Ok(Response::new()
+                .add_message(refund)
+                .add_attribute("action", "execute")
+                .add_attribute("msg_type", "refund"))
+        },
         ExecuteMsg::ForceAbort {} => {
             todo!()
         },
-        ExecuteMsg::Abort {} => handle_execute_abort(deps, env),
-        ExecuteMsg::Trigger { behavior } => handle_trigger(deps, env, behavior),
-        ExecuteMsg::Key3 { val, view, local_channel_id } => {
+        ExecuteMsg::Abort { instance_id } => handle_execute_abort(deps, env, instance_id),
+        #[cfg(any(test, feature = "byzantine"))]
+        ExecuteMsg::SetFaults { faults } => {
+            ensure_owner(deps.storage, &info.sender)?;
+            crate::byzantine::set_faults(deps.storage, faults)?;
+            Ok(Response::new()
+                .add_attribute("action", "execute")
+                .add_attribute("msg_type", "set_faults"))
+        },
+        ExecuteMsg::Key3 { instance_id, val, view, local_channel_id } => {
+            ensure_owner(deps.storage, &info.sender)?;
             if !ALLOW_DEBUG {
                 return Ok(Response::new())
             }
-            HIGHEST_REQ.save(deps.storage, 0,&0)?;
-            HIGHEST_REQ.save(deps.storage, 1, &0)?;
-            HIGHEST_REQ.save(deps.storage, 2, &0)?;
-            HIGHEST_REQ.save(deps.storage, 3, &0)?;
-
-            let state = STATE.load(deps.storage)?;
-            let mut queue: Vec<Vec<Msg>> = vec!(Vec::new(); state.n.try_into().unwrap());
+            // votes only carry the digest
+            remember(deps.storage, instance_id, &val)?;
+            HIGHEST_REQ.save(deps.storage, (instance_id, 0), &0)?;
+            HIGHEST_REQ.save(deps.storage, (instance_id, 1), &0)?;
+            HIGHEST_REQ.save(deps.storage, (instance_id, 2), &0)?;
+            HIGHEST_REQ.save(deps.storage, (instance_id, 3), &0)?;
+
+            let mut queue: Vec<Vec<Msg>> = new_queue(deps.storage)?;
             let mut result;
             if local_channel_id != "None" {
                 result =receive_queue(
                     deps.storage,
                     get_timeout(&env),
                     Some(local_channel_id),
-                    vec![Msg::Key3 { val: val, view: view }],
+                    vec![Msg::Key3 { instance_id, digest: val.digest(), view: view }],
                     &mut queue,
                     &env, 
                     deps.api
@@ -111,7 +196,7 @@ pub fn execute(
                     deps.storage,
                     get_timeout(&env),
                     None,
-                    vec![Msg::Key3 { val: val, view: view }],
+                    vec![Msg::Key3 { instance_id, digest: val.digest(), view: view }],
                     &mut queue,
                     &env,
                     deps.api
@@ -121,19 +206,21 @@ pub fn execute(
             let messages = result.messages;
             Ok(Response::new().add_submessages(messages))
         },
-        ExecuteMsg::Lock { val, view, local_channel_id } => {
+        ExecuteMsg::Lock { instance_id, val, view, local_channel_id } => {
+            ensure_owner(deps.storage, &info.sender)?;
             if !ALLOW_DEBUG {
                 return Ok(Response::new())
             }
-            let state = STATE.load(deps.storage)?;
-            let mut queue: Vec<Vec<Msg>> = vec!(Vec::new(); state.n.try_into().unwrap());
+            // votes only carry the digest
+            remember(deps.storage, instance_id, &val)?;
+            let mut queue: Vec<Vec<Msg>> = new_queue(deps.storage)?;
             let mut result;
             if local_channel_id != "None" {
                 result = receive_queue(
                     deps.storage,
                     get_timeout(&env),
                     Some(local_channel_id),
-                    vec![Msg::Lock { val: val, view: view }],
+                    vec![Msg::Lock { instance_id, digest: val.digest(), view: view }],
                     &mut queue,
                     &env,
                     deps.api
@@ -143,7 +230,7 @@ pub fn execute(
                     deps.storage,
                     get_timeout(&env),
                     None,
-                    vec![Msg::Lock { val: val, view: view }],
+                    vec![Msg::Lock { instance_id, digest: val.digest(), view: view }],
                     &mut queue,
                     &env,
                     deps.api
@@ -153,19 +240,21 @@ pub fn execute(
             let messages = result.messages;
             Ok(Response::new().add_submessages(messages))
         },
-        ExecuteMsg::Done { val, view, local_channel_id } => {
+        ExecuteMsg::Done { instance_id, val, view, local_channel_id } => {
+            ensure_owner(deps.storage, &info.sender)?;
             if !ALLOW_DEBUG {
                 return Ok(Response::new())
             }
-            let state = STATE.load(deps.storage)?;
-            let mut queue: Vec<Vec<Msg>> = vec!(Vec::new(); state.n.try_into().unwrap());
+            // votes only carry the digest
+            remember(deps.storage, instance_id, &val)?;
+            let mut queue: Vec<Vec<Msg>> = new_queue(deps.storage)?;
             let mut result;
             if local_channel_id != "None" {
                 result = receive_queue(
                     deps.storage,
                     get_timeout(&env),
                     Some(local_channel_id),
-                    vec![Msg::Done { val: val }],
+                    vec![Msg::Done { instance_id, digest: val.digest() }],
                     &mut queue,
                     &env,
                     deps.api
@@ -175,7 +264,7 @@ pub fn execute(
                     deps.storage,
                     get_timeout(&env),
                     None,
-                    vec![Msg::Done { val: val }],
+                    vec![Msg::Done { instance_id, digest: val.digest() }],
                     &mut queue,
                     &env,
                     deps.api
@@ -186,37 +275,89 @@ pub fn execute(
             Ok(Response::new().add_submessages(messages))
         },         
         ExecuteMsg::SetContractAddr { addr } => {
-            let mut state = STATE.load(deps.storage)?;
-            state.contract_addr = cosmwasm_std::Addr::unchecked(addr);
-            STATE.save(deps.storage, &state)?;
-            Ok(Response::new())
+            ensure_owner(deps.storage, &info.sender)?;
+            let contract = deps.api.addr_validate(&addr)?;
+            set_default_target(deps.storage, contract.clone())?;
+            Ok(Response::new()
+                .add_attribute("action", "execute")
+                .add_attribute("msg_type", "set_contract_addr")
+                .add_attribute("contract", contract))
+        },
+        ExecuteMsg::AddTarget { contract } => {
+            ensure_owner(deps.storage, &info.sender)?;
+            let contract = add_target(deps.storage, deps.api, &contract)?;
+            Ok(Response::new()
+                .add_attribute("action", "execute")
+                .add_attribute("msg_type", "add_target")
+                .add_attribute("contract", contract))
+        },
+        ExecuteMsg::RemoveTarget { contract } => {
+            ensure_owner(deps.storage, &info.sender)?;
+            let contract = remove_target(deps.storage, deps.api, &contract)?;
+            Ok(Response::new()
+                .add_attribute("action", "execute")
+                .add_attribute("msg_type", "remove_target")
+                .add_attribute("contract", contract))
+        },
+        ExecuteMsg::UpdateOwnership(action) => {
+            let ownership = update_ownership(deps.storage, deps.api, &env, &info.sender, action)?;
+            let owner = ownership.owner.map_or("none".to_string(), String::from);
+            Ok(Response::new()
+                .add_attribute("action", "execute")
+                .add_attribute("msg_type", "update_ownership")
+                .add_attribute("owner", owner))
         },
     }
 }
 
-pub fn handle_trigger(
-    deps: DepsMut,
-    env: Env,
-    behavior: String,
-) -> Result<Response, ContractError> {
-
-    match behavior.as_str() {
-        "multi_propose" => trigger_multi_propose(deps, env),
-        "key1_diff_val" => trigger_key1_diff_val(deps, env),
-        "abort" => trigger_abort(deps, &env),
-        "done" => trigger_done(deps, env),
-        "done_2" => trigger_done_2(deps, env),
-        _ => Ok(Response::new()
-                .add_attribute("action", "trigger")
-                .add_attribute("trigger_behavior", "unknown"))
+// sudo entry_point is only reachable through chain governance
+#[cfg_attr(not(feature = "library"), entry_point)]
+pub fn sudo(deps: DepsMut, _env: Env, msg: SudoMsg) -> Result<Response, ContractError> {
+    match msg {
+        SudoMsg::RecomputeQuorum {} => {
+            let n = recompute_quorum(deps.storage)?;
+            Ok(Response::new()
+                .add_attribute("action", "sudo")
+                .add_attribute("msg_type", "recompute_quorum")
+                .add_attribute("n", n.to_string()))
+        },
+        SudoMsg::SetQuorum { n, f, three_chain_demo } => {
+            let mut config = CONFIG.load(deps.storage)?;
+            let three_chain_demo = three_chain_demo.unwrap_or_default();
+            validate_quorum(config.chain_id, n, f, three_chain_demo)?;
+            config.n = n;
+            config.f = f;
+            config.three_chain_demo = three_chain_demo;
+            CONFIG.save(deps.storage, &config)?;
+            Ok(Response::new()
+                .add_attribute("action", "sudo")
+                .add_attribute("msg_type", "set_quorum")
+                .add_attribute("n", n.to_string())
+                .add_attribute("f", f.to_string()))
+        },
+        SudoMsg::ProposeReconfig { reconfig } => {
+            let n = reconfig.n;
+            propose(deps.storage, reconfig)?;
+            Ok(Response::new()
+                .add_attribute("action", "sudo")
+                .add_attribute("msg_type", "propose_reconfig")
+                .add_attribute("n", n.to_string()))
+        },
+        SudoMsg::SetMembers { members } => {
+            set_members(deps.storage, members)?;
+            Ok(Response::new()
+                .add_attribute("action", "sudo")
+                .add_attribute("msg_type", "set_members"))
+        },
     }
 }
 
 pub fn handle_execute_input(
     deps: DepsMut,
     env: Env,
-    _info: MessageInfo,
-    input: InputType,
+    info: MessageInfo,
+    instance_id: u64,
+    input: TBInput,
 ) -> Result<Response, ContractError> {
     // set timeout for broadcasting
     let timeout: IbcTimeout = get_timeout(&env);
@@ -225,43 +366,118 @@ pub fn handle_execute_input(
         .keys(deps.storage, None, None, Order::Ascending)
         .map(|id| HIGHEST_REQ.save(deps.storage, id?, &0)? );
     */
+    input.validate(deps.api)?;
+    check_fresh(deps.storage, &env, &input)?;
+    check_executable(deps.storage, &input)?;
+    deposit(deps.storage, &info, &input)?;
 
-    // Initialization
-    init_receive_map(deps.storage)?;
-    // Re-init
-    let mut state = STATE.load(deps.storage)?;
-    state.re_init(input, env.block.time.clone());
-
-    // Store values to state
-    STATE.save(deps.storage, &state)?;
+    // Initialization, the input is proposed together with the oldest inputs of the mempool
+    let batch = take_batch(deps.storage, Some(input))?;
+    start_instance(deps.storage, &env, instance_id, batch)?;
 
     // By calling view_change(), Request messages will be delivered to all chains that we established a channel with
-    view_change(deps.storage, timeout.clone(), &env, deps.api)
+    view_change(deps.storage, instance_id, timeout.clone(), &env, deps.api)
 
 }
 
 pub fn handle_execute_preinput(
     deps: DepsMut,
     env: Env,
-    _info: MessageInfo,
-    input: InputType,
+    info: MessageInfo,
+    instance_id: u64,
+    input: TBInput,
 ) -> Result<Response, ContractError> {
+    // starts an instance without proposing the mempool, for tests driven by hand
+    ensure_owner(deps.storage, &info.sender)?;
+    input.validate(deps.api)?;
+    check_fresh(deps.storage, &env, &input)?;
+    check_executable(deps.storage, &input)?;
+    deposit(deps.storage, &info, &input)?;
     // Initialization
-    init_receive_map(deps.storage)?;
+    start_instance(deps.storage, &env, instance_id, input.into())?;
+
+    Ok(Response::new()
+        .add_attribute("action", "execute")
+        .add_attribute("msg_type", "pre_input")
+        .add_attribute("instance_id", instance_id.to_string()))
+}
+
+// The input has to be executable once it is decided: its target is registered and the sender
+// can be added to its payload
+fn check_executable(storage: &dyn Storage, input: &TBInput) -> Result<(), ContractError> {
+    target_of(storage, input)?;
+    with_sender(&input.binary, &CONFIG.load(storage)?.sender_field, &input.sender()?)?;
+    Ok(())
+}
 
-    // Re-init
-    let mut state = STATE.load(deps.storage)?;
-    state.re_init(input, env.block.time.clone());
-    // Store values to state
-    STATE.save(deps.storage, &state)?;
+// Create the local state of a new instance, an instance can only be started once and only
+// in the next free slot
+fn start_instance(
+    storage: &mut dyn Storage,
+    env: &Env,
+    instance_id: u64,
+    input: InputType,
+) -> Result<(), ContractError> {
+    if STATE.has(storage, instance_id) {
+        return Err(ContractError::CustomError {
+            val: format!("Instance {} already started", instance_id),
+        });
+    }
+    let next = next_free_slot(storage)?;
+    if instance_id != next {
+        return Err(ContractError::CustomError {
+            val: format!("Instance {} is not the next free slot {}", instance_id, next),
+        });
+    }
+    if !can_start(storage, instance_id)? {
+        return Err(ContractError::CustomError {
+            val: format!("Instance {} is not part of a known replica set of this chain", instance_id),
+        });
+    }
+    if !has_channel_quorum(storage)? {
+        return Err(ContractError::CustomError {
+            val: "Not enough replicas connected to reach a quorum".to_string(),
+        });
+    }
+    init_instance(storage, env, instance_id, input)?;
+    Ok(())
+}
+
+// Add an input to the mempool, with pipelining it is proposed in the next free slot
+pub fn handle_execute_submit(
+    deps: DepsMut,
+    env: Env,
+    info: MessageInfo,
+    input: TBInput,
+) -> Result<Response, ContractError> {
+    let config = CONFIG.load(deps.storage)?;
+    input.validate(deps.api)?;
+    check_fresh(deps.storage, &env, &input)?;
+    check_executable(deps.storage, &input)?;
+    deposit(deps.storage, &info, &input)?;
+    submit(deps.storage, input)?;
+    if config.pipeline_depth == 0 {
+        return Ok(Response::new()
+            .add_attribute("action", "execute")
+            .add_attribute("msg_type", "submit"));
+    }
+
+    let timeout: IbcTimeout = get_timeout(&env);
+    let mut queue: Vec<Vec<Msg>> = new_queue(deps.storage)?;
+    fill_pipeline(deps.storage, timeout.clone(), &mut queue, &env, deps.api)?;
+    let wasm_msgs = execute_decided(deps.storage, &env, deps.api)?;
+    queue_reports(deps.storage, &mut queue)?;
+    let msgs = convert_queue_to_ibc_msgs(deps.storage, &queue, timeout)?;
 
     Ok(Response::new()
+        .add_messages(msgs)
+        .add_submessages(wasm_msgs)
         .add_attribute("action", "execute")
-        .add_attribute("msg_type", "pre_input"))
+        .add_attribute("msg_type", "submit"))
 }
 
-pub fn handle_execute_abort(deps: DepsMut, env: Env) -> Result<Response, ContractError> {
-    let state = STATE.load(deps.storage)?;
+pub fn handle_execute_abort(deps: DepsMut, env: Env, instance_id: u64) -> Result<Response, ContractError> {
+    let state = STATE.load(deps.storage, instance_id)?;
 
 
     match state.done {
@@ -275,6 +491,7 @@ pub fn handle_execute_abort(deps: DepsMut, env: Env) -> Result<Response, Contrac
     match env.block.time.cmp(&end_time) {
         Ordering::Greater => {
             let abort_packet = Msg::Abort {
+                instance_id,
                 view: state.view,
                 chain_id: state.chain_id,
             };
@@ -284,7 +501,7 @@ pub fn handle_execute_abort(deps: DepsMut, env: Env) -> Result<Response, Contrac
             let response = receive_queue(
                 deps.storage,
                 get_timeout(&env),
-                Some("ABORT_UNUSED_CHANNEL".to_string()),
+                Some(ABORT_CHANNEL.to_string()),
                 vec![abort_packet.clone()],
                 &mut queue,
                 &env,
@@ -312,23 +529,40 @@ pub fn handle_execute_abort(deps: DepsMut, env: Env) -> Result<Response, Contrac
 #[cfg_attr(not(feature = "library"), entry_point)]
 pub fn query(deps: Deps, env: Env, msg: QueryMsg) -> StdResult<Binary> {
     match msg {
-        QueryMsg::GetState {} => to_binary(&query_state(deps)?),
-        QueryMsg::GetStateProgress {} => to_binary(&query_state_progress(deps)?),
+        QueryMsg::GetState { instance_id } => to_binary(&query_state(deps, instance_id)?),
+        QueryMsg::GetStateProgress { instance_id } => to_binary(&query_state_progress(deps, instance_id)?),
+        QueryMsg::GetMembership {} => to_binary(&membership(deps.storage)?),
+        QueryMsg::GetOutstandingPackets {} => to_binary(&outstanding(deps.storage)?),
+        QueryMsg::GetReconfigs {} => to_binary(&ReconfigsResponse {
+            pending: PENDING_RECONFIG.may_load(deps.storage)?,
+            approved: APPROVED_RECONFIG.may_load(deps.storage)?,
+            scheduled: scheduled(deps.storage)?,
+        }),
+        QueryMsg::GetMempool {} => to_binary(&PENDING_INPUTS.load(deps.storage)?),
+        QueryMsg::GetInstances { start_after, limit } => to_binary(&query_instances(deps, start_after, limit)?),
+        QueryMsg::GetConfig {} => to_binary(&CONFIG.load(deps.storage)?),
+        QueryMsg::GetTargets {} => to_binary(&list_targets(deps.storage)?),
+        QueryMsg::GetReceipts { slot } => to_binary(&ReceiptsResponse { receipts: slot_receipts(deps.storage, slot)? }),
+        QueryMsg::GetInputReceipt { sender, nonce } => to_binary(&input_receipt(deps.storage, sender, nonce)?),
+        QueryMsg::GetOwnership {} => to_binary(&OWNERSHIP.load(deps.storage)?),
+        QueryMsg::GetEscrow { sender, nonce } => to_binary(&ESCROWS.may_load(deps.storage, (sender, nonce))?),
+        QueryMsg::GetSlotReports { slot } => to_binary(&slot_reports(deps.storage, slot)?),
+        QueryMsg::GetLog { start_after, limit } => to_binary(&query_log(deps, start_after, limit)?),
         QueryMsg::GetChannels {} => to_binary(&query_channels(deps)?),
         QueryMsg::GetTest {} => to_binary(&query_test(deps)?),
-        QueryMsg::GetHighestReq {} => to_binary(&query_highest_request(deps)?),
-        QueryMsg::GetReceivedSuggest {} => to_binary(&query_received_suggest(deps)?),
-        QueryMsg::GetSendAllUpon {} => to_binary(&query_send_all_upon(deps)?),
+        QueryMsg::GetHighestReq { instance_id } => to_binary(&query_highest_request(deps, instance_id)?),
+        QueryMsg::GetReceivedSuggest { instance_id } => to_binary(&query_received_suggest(deps, instance_id)?),
+        QueryMsg::GetSendAllUpon { instance_id } => to_binary(&query_send_all_upon(deps, instance_id)?),
         QueryMsg::GetTestQueue {} => to_binary(&query_test_queue(deps)?),
-        QueryMsg::GetEcho {} => to_binary(&query_echo(deps)?),
-        QueryMsg::GetKey1 {} => to_binary(&query_key1(deps)?),
-        QueryMsg::GetKey2 {} => to_binary(&query_key2(deps)?),
-        QueryMsg::GetKey3 {} => to_binary(&query_key3(deps)?),
-        QueryMsg::GetLock {} => to_binary(&query_lock(deps)?),
-        QueryMsg::GetDone {} => to_binary(&query_done(deps)?),
-        QueryMsg::GetAbortInfo {} => to_binary(&query_abort_info(deps, env)?),
+        QueryMsg::GetEcho { instance_id } => to_binary(&query_echo(deps, instance_id)?),
+        QueryMsg::GetKey1 { instance_id } => to_binary(&query_key1(deps, instance_id)?),
+        QueryMsg::GetKey2 { instance_id } => to_binary(&query_key2(deps, instance_id)?),
+        QueryMsg::GetKey3 { instance_id } => to_binary(&query_key3(deps, instance_id)?),
+        QueryMsg::GetLock { instance_id } => to_binary(&query_lock(deps, instance_id)?),
+        QueryMsg::GetDone { instance_id } => to_binary(&query_done(deps, instance_id)?),
+        QueryMsg::GetAbortInfo { instance_id } => to_binary(&query_abort_info(deps, env, instance_id)?),
         QueryMsg::GetDebug {} => to_binary(&query_debug(deps)?),
-        QueryMsg::GetHighestAbort {} => to_binary(&query_highest_abort(deps)?),
+        QueryMsg::GetHighestAbort { instance_id } => to_binary(&query_highest_abort(deps, instance_id)?),
         QueryMsg::GetIbcDebug {} => to_binary(&query_ibc_debug(deps)?),
         QueryMsg::GetDebugReceive{} => to_binary(&query_debug_receive(deps)?),
         QueryMsg::CheckSignature { val } => to_binary(&check_signature(deps, val)?),
@@ -336,45 +570,57 @@ pub fn query(deps: Deps, env: Env, msg: QueryMsg) -> StdResult<Binary> {
      }
 }
 
-fn query_echo(deps: Deps) -> StdResult<EchoQueryResponse> {
+fn query_echo(deps: Deps, instance_id: u64) -> StdResult<EchoQueryResponse> {
     let query: StdResult<Vec<_>> = RECEIVED_ECHO
+        .prefix(instance_id)
         .range(deps.storage, None, None, Order::Ascending)
+        .map(|item| item.map(|(digest, chains)| (Binary::from(digest), chains)))
         .collect();
     Ok(EchoQueryResponse { echo: query? })
 }
-fn query_key1(deps: Deps) -> StdResult<Key1QueryResponse> {
+fn query_key1(deps: Deps, instance_id: u64) -> StdResult<Key1QueryResponse> {
     let query: StdResult<Vec<_>> = RECEIVED_KEY1
+        .prefix(instance_id)
         .range(deps.storage, None, None, Order::Ascending)
+        .map(|item| item.map(|(digest, chains)| (Binary::from(digest), chains)))
         .collect();
     Ok(Key1QueryResponse { key1: query? })
 }
-fn query_key2(deps: Deps) -> StdResult<Key2QueryResponse> {
+fn query_key2(deps: Deps, instance_id: u64) -> StdResult<Key2QueryResponse> {
     let query: StdResult<Vec<_>> = RECEIVED_KEY2
+        .prefix(instance_id)
         .range(deps.storage, None, None, Order::Ascending)
+        .map(|item| item.map(|(digest, chains)| (Binary::from(digest), chains)))
         .collect();
     Ok(Key2QueryResponse { key2: query? })
 }
-fn query_key3(deps: Deps) -> StdResult<Key3QueryResponse> {
+fn query_key3(deps: Deps, instance_id: u64) -> StdResult<Key3QueryResponse> {
     let query: StdResult<Vec<_>> = RECEIVED_KEY3
+        .prefix(instance_id)
         .range(deps.storage, None, None, Order::Ascending)
+        .map(|item| item.map(|(digest, chains)| (Binary::from(digest), chains)))
         .collect();
     Ok(Key3QueryResponse { key3: query? })
 }
-fn query_lock(deps: Deps) -> StdResult<LockQueryResponse> {
+fn query_lock(deps: Deps, instance_id: u64) -> StdResult<LockQueryResponse> {
     let query: StdResult<Vec<_>> = RECEIVED_LOCK
+        .prefix(instance_id)
         .range(deps.storage, None, None, Order::Ascending)
+        .map(|item| item.map(|(digest, chains)| (Binary::from(digest), chains)))
         .collect();
     Ok(LockQueryResponse { lock: query? })
 }
-fn query_done(deps: Deps) -> StdResult<DoneQueryResponse> {
+fn query_done(deps: Deps, instance_id: u64) -> StdResult<DoneQueryResponse> {
     let query: StdResult<Vec<_>> = RECEIVED_DONE
+        .prefix(instance_id)
         .range(deps.storage, None, None, Order::Ascending)
+        .map(|item| item.map(|(digest, chains)| (Binary::from(digest), chains)))
         .collect();
     Ok(DoneQueryResponse { done: query? })
 }
 
-fn query_state(deps: Deps) -> StdResult<StateResponse> {
-    let state = STATE.load(deps.storage)?;
+fn query_state(deps: Deps, instance_id: u64) -> StdResult<StateResponse> {
+    let state = STATE.load(deps.storage, instance_id)?;
     Ok(
         match state.done {           
             Some(val) => {
@@ -397,7 +643,7 @@ fn query_state(deps: Deps) -> StdResult<StateResponse> {
                 };
 
                 StateResponse::Done { 
-                decided_val: val.binary,
+                decided_vals: val.inputs.into_iter().map(|input| input.binary).collect(),
                 decided_timestamp: state.done_timestamp,
                 block_height: state.done_block_height,
                 start_time: state.start_time,
@@ -409,11 +655,30 @@ fn query_state(deps: Deps) -> StdResult<StateResponse> {
     })
 }
 
-fn query_state_progress(deps: Deps) -> StdResult<StateResponse> {
-    let state = STATE.load(deps.storage)?;
+fn query_state_progress(deps: Deps, instance_id: u64) -> StdResult<StateResponse> {
+    let state = STATE.load(deps.storage, instance_id)?;
     return Ok(StateResponse::InProgress { state });
 }
 
+fn query_instances(deps: Deps, start_after: Option<u64>, limit: Option<u32>) -> StdResult<InstancesResponse> {
+    let limit = limit.unwrap_or(DEFAULT_LIMIT).min(MAX_LIMIT) as usize;
+    let start = start_after.map(Bound::exclusive);
+    let instances: StdResult<Vec<_>> = STATE
+        .range(deps.storage, start, None, Order::Ascending)
+        .take(limit)
+        .map(|item| item.map(|(instance_id, state)| (instance_id, state.done.is_some())))
+        .collect();
+    Ok(InstancesResponse { instances: instances? })
+}
+
+fn query_log(deps: Deps, start_after: Option<u64>, limit: Option<u32>) -> StdResult<LogResponse> {
+    let limit = limit.unwrap_or(DEFAULT_LIMIT).min(MAX_LIMIT) as usize;
+    Ok(LogResponse {
+        length: LOG_LENGTH.load(deps.storage)?,
+        entries: read_log(deps.storage, start_after, limit)?,
+    })
+}
+
 fn query_test_queue(deps: Deps) -> StdResult<TestQueueResponse> {
     let req: StdResult<Vec<_>> = TEST_QUEUE
         .range(deps.storage, None, None, Order::Ascending)
@@ -421,8 +686,9 @@ fn query_test_queue(deps: Deps) -> StdResult<TestQueueResponse> {
     Ok(TestQueueResponse { test_queue: req? })
 }
 
-fn query_send_all_upon(deps: Deps) -> StdResult<SendAllUponResponse> {
+fn query_send_all_upon(deps: Deps, instance_id: u64) -> StdResult<SendAllUponResponse> {
     let req: StdResult<Vec<_>> = SEND_ALL_UPON
+        .prefix(instance_id)
         .range(deps.storage, None, None, Order::Ascending)
         .collect();
     Ok(SendAllUponResponse {
@@ -430,18 +696,19 @@ fn query_send_all_upon(deps: Deps) -> StdResult<SendAllUponResponse> {
     })
 }
 
-fn query_received_suggest(deps: Deps) -> StdResult<ReceivedSuggestResponse> {
+fn query_received_suggest(deps: Deps, instance_id: u64) -> StdResult<ReceivedSuggestResponse> {
     // let req: StdResult<Vec<_>> = RECEIVED_SUGGEST
     //     .range(deps.storage, None, None, Order::Ascending)
     //     .collect();
-    let req: StdResult<HashSet<_>> = RECEIVED.load(deps.storage, "Suggest".to_string());
+    let req: StdResult<HashSet<_>> = RECEIVED.load(deps.storage, (instance_id, "Suggest".to_string()));
     Ok(ReceivedSuggestResponse {
         received_suggest: req?,
     })
 }
 
-fn query_highest_request(deps: Deps) -> StdResult<HighestReqResponse> {
+fn query_highest_request(deps: Deps, instance_id: u64) -> StdResult<HighestReqResponse> {
     let req: StdResult<Vec<_>> = HIGHEST_REQ
+        .prefix(instance_id)
         .range(deps.storage, None, None, Order::Ascending)
         .collect();
     Ok(HighestReqResponse {
@@ -449,8 +716,9 @@ fn query_highest_request(deps: Deps) -> StdResult<HighestReqResponse> {
     })
 }
 
-fn query_highest_abort(deps: Deps) -> StdResult<HighestAbortResponse> {
+fn query_highest_abort(deps: Deps, instance_id: u64) -> StdResult<HighestAbortResponse> {
     let req: StdResult<Vec<_>> = HIGHEST_ABORT
+        .prefix(instance_id)
         .range(deps.storage, None, None, Order::Ascending)
         .collect();
     Ok(HighestAbortResponse {
@@ -470,14 +738,18 @@ fn query_channels(deps: Deps) -> StdResult<ChannelsResponse> {
     let channels: StdResult<Vec<_>> = CHANNELS
         .range(deps.storage, None, None, Order::Ascending)
         .collect();
+    let disconnected: StdResult<Vec<_>> = DISCONNECTED
+        .keys(deps.storage, None, None, Order::Ascending)
+        .collect();
     // let channels = channels?;
     Ok(ChannelsResponse {
         port_chan_pair: channels?,
+        disconnected: disconnected?,
     })
 }
 
-fn query_abort_info(deps: Deps, env: Env) -> StdResult<AbortResponse> {
-    let state = STATE.load(deps.storage)?;
+fn query_abort_info(deps: Deps, env: Env, instance_id: u64) -> StdResult<AbortResponse> {
+    let state = STATE.load(deps.storage, instance_id)?;
     // let channels = channels?;
 
     let end_time = state.start_time.plus_seconds(VIEW_TIMEOUT_SECONDS);
@@ -524,58 +796,49 @@ fn query_debug_receive(deps: Deps) -> StdResult<Vec<(String, Vec<String>)>> {
 
 
 // https://github.com/CosmWasm/cosmwasm/blob/main/contracts/crypto-verify/src/contract.rs#L90-L107
-fn check_signature(deps: Deps, val: InputType) -> StdResult<Vec<bool>> {
-    let mut result: Vec<bool> = Vec::new();
-
-    // Hashing
-    let hash = Sha256::digest(val.binary);
-
-    // Verification
-    let verify_result = deps
-        .api
-        .secp256k1_verify(hash.as_ref(), &val.signature, &val.public_key)?;
-
-    result.push(verify_result);
-    Ok(result)
+fn check_signature(deps: Deps, val: TBInput) -> StdResult<Vec<bool>> {
+    Ok(vec![val.validate(deps.api).is_ok()])
 }
 
-// https://github.com/CosmWasm/cosmwasm/blob/main/contracts/crypto-verify/src/contract.rs#L90-L107
-fn get_address(deps: Deps, val: InputType) -> StdResult<Addr> {
-   let result = derive_addr_from_pubkey(&val.public_key);
-    Ok(result.unwrap())
+// the address the input is executed for, derived according to its signature scheme
+fn get_address(_deps: Deps, val: TBInput) -> StdResult<Addr> {
+    val.sender()
 }
 
 
 // entry_point for sub-messages
 #[cfg_attr(not(feature = "library"), entry_point)]
-pub fn reply(deps: DepsMut, env: Env, msg: Reply) -> StdResult<Response> {
+pub fn reply(deps: DepsMut, env: Env, msg: Reply) -> Result<Response, ContractError> {
     match msg.id {
         // REQUEST_REPLY_ID => handle_request_reply(deps, get_timeout(env), msg),
         REQUEST_REPLY_ID => Ok(Response::new()),
         SUGGEST_REPLY_ID => Ok(Response::new()),
-        1234 => handle_wasm_exec(deps, msg),
-        id => Err(StdError::generic_err(format!("Unknown reply id: {}", id))),
+        EXECUTE_REPLY_ID => handle_wasm_exec(deps, env, msg),
+        id => Err(StdError::generic_err(format!("Unknown reply id: {}", id)).into()),
     }
 }
 
-fn handle_wasm_exec(deps: DepsMut, msg: Reply) -> StdResult<Response> {
-    // Upon sucessfully delivered the Suggest Message
-    // Load the state
-    // let _state = STATE.load(deps.storage)?;
-    
-    
-    let res: Response = Response::new();
-
-    let subMsgResult = msg.result;
-    if subMsgResult.is_err() {
-        let str = subMsgResult.unwrap_err();
-        DEBUG.save(deps.storage, 12341234, &str.clone())?;
-        Err(StdError::generic_err(&str.clone()))
-    } else {
-        DEBUG.save(deps.storage, 12341111, &"OK".to_string())?;
-        Ok(res)
-    }
-    // Add consecutive submessages
+// Record the outcome of a decided input, a failed target must not revert the decision.
+// The last reply of a slot reports its receipts to the other chains.
+fn handle_wasm_exec(deps: DepsMut, env: Env, msg: Reply) -> Result<Response, ContractError> {
+    let receipt = record_reply(deps.storage, &env, msg.result)?;
+    let status = match receipt.status {
+        ReceiptStatus::Failed { .. } => "failed",
+        _ => "succeeded",
+    };
+    // the coins of a failed call are back in this contract
+    let refund = settle(deps.storage, &receipt)?;
+    let mut queue = new_queue(deps.storage)?;
+    queue_reports(deps.storage, &mut queue)?;
+    let msgs = convert_queue_to_ibc_msgs(deps.storage, &queue, get_timeout(&env))?;
+    Ok(Response::new()
+        .add_messages(refund)
+        .add_messages(msgs)
+        .add_attribute("action", "reply")
+        .add_attribute("msg_type", "execute_input")
+        .add_attribute("slot", receipt.slot.to_string())
+        .add_attribute("index", receipt.index.to_string())
+        .add_attribute("status", status))
 }
 
 #[cfg(test)]
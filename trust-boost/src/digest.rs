@@ -0,0 +1,62 @@
+use cosmwasm_std::Binary;
+use sha2::{Digest, Sha256};
+
+use crate::codec::put_batch;
+use crate::state::Batch;
+
+// Values are identified by the SHA-256 of a canonical encoding, the same on every chain
+// whatever toolchain the contract was built with. The encoding is versioned and every
+// variable length field is prefixed with its length (u32, big endian), so two different
+// batches never encode to the same bytes:
+//
+//   "tb-batch-v5" | #inputs | input* | reconfig
+//   input = binary | public_key | signature scheme | domain nonce expires contract #coins (denom amount)*
+//   scheme = 0 (secp256k1) | 1 (ed25519) | 2 signer (adr036)
+//   expires = 0 height | 1 nanoseconds
+//   amount = u128, big endian
+//   reconfig = 0 | 1 n f three_chain_demo #members (chain_id port_id connection_id)*
+//
+// An absent contract, port_id or connection_id is a single 0 byte, a present one is 1 followed by the string.
+// The same batch layout, without the domain, is used by the compact wire encoding in codec.rs.
+
+const DOMAIN: &[u8] = b"tb-batch-v5";
+
+impl Batch {
+    pub fn digest(&self) -> Binary {
+        Binary::from(Sha256::digest(self.encode_canonical()).to_vec())
+    }
+
+    pub fn encode_canonical(&self) -> Vec<u8> {
+        let mut out = DOMAIN.to_vec();
+        put_batch(&mut out, self);
+        out
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::state::{Member, Reconfig, TBInput};
+
+    fn input(binary: &str, public_key: Vec<u8>) -> TBInput {
+        TBInput { binary: binary.to_string(), public_key, domain: "tb-test".to_string(), ..TBInput::default() }
+    }
+
+    #[test]
+    fn digest_is_stable_and_unambiguous() {
+        // fixed so that a change of the encoding shows up here before it splits the replicas
+        assert_eq!(
+            Batch::noop().digest().to_base64(),
+            Binary::from(Sha256::digest(b"tb-batch-v5\0\0\0\0\0").to_vec()).to_base64()
+        );
+        // moving bytes between fields changes the digest
+        let a = Batch::from(input("ab", vec![1]));
+        let b = Batch::from(input("a", vec![b'b', 1]));
+        assert_ne!(a.digest(), b.digest());
+        assert_eq!(a.digest(), a.clone().digest());
+
+        let reconfig = Reconfig { n: 4, f: 1, three_chain_demo: false, members: vec![(3, Member::default())] };
+        let c = Batch { inputs: a.inputs.clone(), reconfig: Some(reconfig) };
+        assert_ne!(a.digest(), c.digest());
+    }
+}
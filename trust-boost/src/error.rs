@@ -11,6 +11,54 @@ pub enum ContractError {
 
     #[error("Custom Error val: {val:?}")]
     CustomError { val: String },
+
+    #[error("Input is signed for domain {got:?}, this deployment is {expected:?}")]
+    WrongDomain { expected: String, got: String },
+
+    #[error("Input expired")]
+    Expired {},
+
+    #[error("Nonce {nonce} of {sender} was already used")]
+    NonceUsed { sender: String, nonce: u64 },
+
+    #[error("Input binary is not base64: {reason}")]
+    InvalidBinary { reason: String },
+
+    #[error("Invalid public key: {reason}")]
+    InvalidPublicKey { reason: String },
+
+    #[error("Invalid signature: {reason}")]
+    InvalidSignature { reason: String },
+
+    #[error("Payload is not an execute msg of the target contract: {reason}")]
+    InvalidPayload { reason: String },
+
+    #[error("Contract {contract} is not a registered target")]
+    TargetNotAllowed { contract: String },
+
+    #[error("Invalid funds: {reason}")]
+    InvalidFunds { reason: String },
+
+    #[error("Sent {sent:?}, the input asks for {expected:?}")]
+    FundsMismatch { expected: String, sent: String },
+
+    #[error("Funds for nonce {nonce} of {sender} are already escrowed")]
+    AlreadyEscrowed { sender: String, nonce: u64 },
+
+    #[error("No funds escrowed for nonce {nonce} of {sender}")]
+    NoEscrow { sender: String, nonce: u64 },
+
+    #[error("Escrow cannot be refunded yet: {reason}")]
+    NotRefundable { reason: String },
+
+    #[error("Mempool is full, {size} inputs are waiting for a slot")]
+    MempoolFull { size: u32 },
+
+    #[error("No ownership transfer is pending")]
+    NoPendingTransfer {},
+
+    #[error("Ownership transfer expired")]
+    TransferExpired {},
     // Add any other custom errors you like here.
     // Look at https://docs.rs/thiserror/1.0.21/thiserror/ for details.
 }
@@ -0,0 +1,160 @@
+use cosmwasm_std::{BankMsg, Coin, Env, MessageInfo, StdResult, Storage};
+
+use crate::error::ContractError;
+use crate::state::{Escrow, Receipt, ReceiptStatus, TBInput, ESCROWS, USED_NONCES};
+
+// A user pays for an action by signing the coins it sends along into TBInput::funds and
+// attaching them to the Input or Submit that hands the input to a chain. That chain holds
+// them under the (sender, nonce) of the input. When the slot is executed, a chain holding
+// the escrow forwards the coins to the target with the WasmMsg, a chain without one skips
+// the input, so nothing is executed unpaid. The coins go back to the depositor when the
+// target fails, when the input is skipped, or through ExecuteMsg::Refund once the input
+// expired or its nonce was used by another input. An input without funds needs no escrow.
+
+// Coins as the bank orders them: denoms sorted and unique, amounts not zero
+pub fn check_funds(funds: &[Coin]) -> Result<(), ContractError> {
+    let invalid = |reason: String| Err(ContractError::InvalidFunds { reason });
+    for coin in funds {
+        let mut chars = coin.denom.chars();
+        let denom_ok = (3..=128).contains(&coin.denom.len())
+            && chars.next().is_some_and(|c| c.is_ascii_alphabetic())
+            && chars.all(|c| c.is_ascii_alphanumeric() || "/:._-".contains(c));
+        if !denom_ok {
+            return invalid(format!("invalid denom {:?}", coin.denom));
+        }
+        if coin.amount.is_zero() {
+            return invalid(format!("zero amount of {}", coin.denom));
+        }
+    }
+    if funds.windows(2).any(|pair| pair[0].denom >= pair[1].denom) {
+        return invalid("denoms are not sorted and unique".to_string());
+    }
+    Ok(())
+}
+
+// "100stake,5uatom", as in the signed text
+pub fn coins_text(funds: &[Coin]) -> String {
+    funds.iter().map(|coin| format!("{}{}", coin.amount, coin.denom)).collect::<Vec<_>>().join(",")
+}
+
+fn key(input: &TBInput) -> StdResult<(String, u64)> {
+    Ok((input.sender()?.to_string(), input.nonce))
+}
+
+// Hold the coins sent with the msg that hands `input` to this chain
+pub fn deposit(store: &mut dyn Storage, info: &MessageInfo, input: &TBInput) -> Result<(), ContractError> {
+    if info.funds.is_empty() {
+        return Ok(());
+    }
+    let mut sent = info.funds.clone();
+    sent.sort_by(|a, b| a.denom.cmp(&b.denom));
+    if sent != input.funds {
+        return Err(ContractError::FundsMismatch { expected: coins_text(&input.funds), sent: coins_text(&sent) });
+    }
+    let (sender, nonce) = key(input)?;
+    if ESCROWS.has(store, (sender.clone(), nonce)) {
+        return Err(ContractError::AlreadyEscrowed { sender, nonce });
+    }
+    ESCROWS.save(store, (sender, nonce), &Escrow { depositor: info.sender.clone(), input: input.clone() })?;
+    Ok(())
+}
+
+// Whether this chain holds the coins of `input`
+pub fn is_escrowed(store: &dyn Storage, input: &TBInput) -> StdResult<bool> {
+    Ok(ESCROWS.may_load(store, key(input)?)?.is_some_and(|escrow| escrow.input == *input))
+}
+
+fn give_back(store: &mut dyn Storage, key: (String, u64), escrow: Escrow) -> BankMsg {
+    ESCROWS.remove(store, key);
+    BankMsg::Send { to_address: escrow.depositor.to_string(), amount: escrow.input.funds }
+}
+
+// Refund the coins of a skipped input, none if this chain does not hold them
+pub fn refund(store: &mut dyn Storage, input: &TBInput) -> StdResult<Option<BankMsg>> {
+    let key = key(input)?;
+    match ESCROWS.may_load(store, key.clone())? {
+        Some(escrow) if escrow.input == *input => Ok(Some(give_back(store, key, escrow))),
+        _ => Ok(None),
+    }
+}
+
+// Once the target replied the coins were either spent or came back with the failed call
+pub fn settle(store: &mut dyn Storage, receipt: &Receipt) -> StdResult<Option<BankMsg>> {
+    let sender = match (&receipt.sender, receipt.funds.is_empty()) {
+        (Some(sender), false) => sender.to_string(),
+        _ => return Ok(None),
+    };
+    let key = (sender, receipt.nonce);
+    let escrow = match ESCROWS.may_load(store, key.clone())? {
+        Some(escrow) => escrow,
+        None => return Ok(None),
+    };
+    match receipt.status {
+        ReceiptStatus::Failed { .. } => Ok(Some(give_back(store, key, escrow))),
+        _ => {
+            ESCROWS.remove(store, key);
+            Ok(None)
+        },
+    }
+}
+
+// Refund an escrow whose input can no longer be executed with it on this chain
+pub fn refund_unused(store: &mut dyn Storage, env: &Env, sender: String, nonce: u64) -> Result<BankMsg, ContractError> {
+    let key = (sender.clone(), nonce);
+    let escrow = ESCROWS.may_load(store, key.clone())?.ok_or(ContractError::NoEscrow { sender, nonce })?;
+    if !escrow.input.expires.is_expired(env) && !USED_NONCES.has(store, key.clone()) {
+        return Err(ContractError::NotRefundable { reason: "the input has not expired".to_string() });
+    }
+    Ok(give_back(store, key, escrow))
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use cosmwasm_std::testing::{mock_dependencies, mock_env, mock_info};
+    use cosmwasm_std::coins;
+    use crate::state::Expiry;
+
+    fn input(nonce: u64, funds: Vec<Coin>) -> TBInput {
+        TBInput { public_key: vec![2; 33], nonce, expires: Expiry::AtHeight(12_400), funds, ..TBInput::default() }
+    }
+
+    #[test]
+    fn escrow_is_held_for_its_input_only() {
+        let mut deps = mock_dependencies();
+        let store = &mut deps.storage;
+        let paid = input(1, coins(100, "stake"));
+
+        assert!(matches!(deposit(store, &mock_info("payer", &coins(99, "stake")), &paid), Err(ContractError::FundsMismatch { .. })));
+        deposit(store, &mock_info("payer", &coins(100, "stake")), &paid).unwrap();
+        assert!(matches!(deposit(store, &mock_info("payer", &coins(100, "stake")), &paid), Err(ContractError::AlreadyEscrowed { .. })));
+        // handed to a chain without coins, the escrow is kept wherever it was made
+        deposit(store, &mock_info("payer", &[]), &paid).unwrap();
+        assert!(is_escrowed(store, &paid).unwrap());
+
+        // another input with the same nonce does not get the coins
+        let other = TBInput { binary: "e31=".to_string(), ..paid.clone() };
+        assert!(!is_escrowed(store, &other).unwrap());
+        assert_eq!(refund(store, &other).unwrap(), None);
+
+        // not refundable while the input may still be executed
+        let sender = paid.sender().unwrap().to_string();
+        let mut env = mock_env();
+        assert!(matches!(refund_unused(store, &env, sender.clone(), 1), Err(ContractError::NotRefundable { .. })));
+        env.block.height = 12_400;
+        let refund = refund_unused(store, &env, sender.clone(), 1).unwrap();
+        assert_eq!(refund, BankMsg::Send { to_address: "payer".to_string(), amount: coins(100, "stake") });
+        assert!(matches!(refund_unused(store, &env, sender, 1), Err(ContractError::NoEscrow { .. })));
+    }
+
+    #[test]
+    fn funds_have_to_be_canonical() {
+        assert!(check_funds(&[]).is_ok());
+        assert!(check_funds(&[Coin::new(1, "ibc/27394FB092D2ECCD56123C74F36E4C1F926001CEADA9CA97EA622B25F41E5EB2"), Coin::new(5, "stake")]).is_ok());
+        assert!(check_funds(&[Coin::new(5, "stake"), Coin::new(1, "atom")]).is_err());
+        assert!(check_funds(&[Coin::new(5, "stake"), Coin::new(1, "stake")]).is_err());
+        assert!(check_funds(&[Coin::new(0, "stake")]).is_err());
+        assert!(check_funds(&[Coin::new(1, "st\nake")]).is_err());
+        assert!(check_funds(&[Coin::new(1, "1stake")]).is_err());
+    }
+}
@@ -1,27 +1,29 @@
-use std::convert::TryInto;
 
 use cosmwasm_std::{
-    entry_point, from_slice, to_binary, Binary, DepsMut, Env, Event, StdResult,
+    entry_point, from_slice, to_binary, Binary, DepsMut, Env, Event, StdError, StdResult,
 };
 use cosmwasm_std::{
     IbcBasicResponse, IbcChannelCloseMsg, IbcChannelConnectMsg, IbcChannelOpenMsg, IbcMsg, IbcPacketAckMsg, IbcPacketReceiveMsg, IbcPacketTimeoutMsg, IbcReceiveResponse,
 };
 
 use crate::ibc_msg::{
-    AcknowledgementMsg, PacketMsg, WhoAmIResponse, ProofResponse, EchoResponse, Key1Response, Key2Response, Key3Response, LockResponse, DoneResponse, Msg,
+    AcknowledgementMsg, MsgQueueResponse, PacketMsg, WhoAmIResponse, ProofResponse, EchoResponse, Key1Response, Key2Response, Key3Response, LockResponse, DoneResponse, Msg,
 };
 
 use crate::state::{
-    CHANNELS, STATE, HIGHEST_ABORT, IBC_MSG_SEND_DEBUG, InputType,
+    ChannelPeer, CHANNEL_ENCODING, CHANNEL_PEERS, CONFIG, IBC_MSG_SEND_DEBUG, InputType,
 };
-use crate::utils::{get_timeout};
+use crate::codec::{channel_encoding, decode_packet, encode_for_channel, encode_packet};
+use crate::membership::{bind_peer, disconnect_channel};
+use crate::retransmit::{acked, is_transient, resend_to, retry, timed_out, UNBOUND_CHANNEL};
+use crate::utils::{get_timeout, new_queue, verify_channel, find_chain_id};
 use crate::queue_handler::{receive_queue};
 
 #[entry_point]
 /// enforces ordering and versioing constraints
-pub fn ibc_channel_open(_deps: DepsMut, _env: Env, _msg: IbcChannelOpenMsg) -> StdResult<()> {
-    // verify_channel(msg)?;
-    Ok(())
+pub fn ibc_channel_open(deps: DepsMut, _env: Env, msg: IbcChannelOpenMsg) -> StdResult<()> {
+    let config = CONFIG.load(deps.storage)?;
+    verify_channel(&config, msg.channel(), msg.counterparty_version()).map(|_| ())
 }
 
 #[entry_point]
@@ -36,11 +38,16 @@ pub fn ibc_channel_connect(
     let channel_id = &channel.endpoint.channel_id;
 
     // Keep a record of connected channels
-    let mut state = STATE.load(deps.storage)?;
-    state.channel_ids.push(channel_id.to_string());
-    // increment the total no of chains
-    state.n += 1;
-    STATE.save(deps.storage, &state)?;
+    let mut config = CONFIG.load(deps.storage)?;
+    let encoding = verify_channel(&config, channel, msg.counterparty_version())?;
+    CHANNEL_ENCODING.save(deps.storage, channel_id.to_string(), &encoding)?;
+    let peer = ChannelPeer {
+        port_id: channel.counterparty_endpoint.port_id.clone(),
+        connection_id: channel.connection_id.clone(),
+    };
+    CHANNEL_PEERS.save(deps.storage, channel_id.to_string(), &peer)?;
+    config.channel_ids.push(channel_id.to_string());
+    CONFIG.save(deps.storage, &config)?;
     // let dst_port =  &channel.counterparty_endpoint.port_id;
 
 
@@ -58,11 +65,11 @@ pub fn ibc_channel_connect(
 
     // construct a packet to send, using the WhoAmI specification
     let packet = PacketMsg::WhoAmI {
-        chain_id: state.chain_id,
+        chain_id: config.chain_id,
     };
     let msg = IbcMsg::SendPacket {
         channel_id: channel_id.clone(),
-        data: to_binary(&packet)?,
+        data: encode_packet(&packet, encoding)?,
         timeout: get_timeout(&env)
     };
 
@@ -73,15 +80,16 @@ pub fn ibc_channel_connect(
 }
 
 #[entry_point]
-/// On closed channel, simply delete the channel_id local state
+/// On closed channel, mark the peer behind it as disconnected, n is left unchanged
 pub fn ibc_channel_close(
-    _deps: DepsMut,
+    deps: DepsMut,
     _env: Env,
     msg: IbcChannelCloseMsg,
 ) -> StdResult<IbcBasicResponse> {
     // fetch the connected channel_id
     let channel = msg.channel();
     let channel_id = &channel.endpoint.channel_id;
+    let chain_id = disconnect_channel(deps.storage, channel_id)?;
     // Remove the channel_ids stored in CHANNELS
     // CHANNELS.remove(deps.storage, dst_port.to_string());
 
@@ -102,9 +110,13 @@ pub fn ibc_channel_close(
     // STATE.save(deps.storage, &state)?;
     // accounts(deps.storage).remove(channel_id.as_bytes());
 
-    Ok(IbcBasicResponse::new()
+    let mut response = IbcBasicResponse::new()
         .add_attribute("action", "ibc_close")
-        .add_attribute("channel_id", channel_id))
+        .add_attribute("channel_id", channel_id);
+    if let Some(chain_id) = chain_id {
+        response = response.add_attribute("disconnected_chain_id", chain_id.to_string());
+    }
+    Ok(response)
 }
 
 // This encode an error or error message into a proper acknowledgement to the recevier
@@ -123,17 +135,22 @@ pub fn ibc_packet_receive(
         let packet = msg.packet;
         // which local channel did this packet come on
         let dest_channel_id = packet.dest.channel_id;
-        let msg: PacketMsg = from_slice(&packet.data)?;
+        let msg = decode_packet(&packet.data, channel_encoding(deps.storage, &dest_channel_id)?)?;
         match msg {
             PacketMsg::MsgQueue(q) => 
             {
-                let state = STATE.load(deps.storage)?;
-                let mut queue: Vec<Vec<Msg>> = vec!(Vec::new(); state.n.try_into().unwrap());
+                // a channel whose WhoAmI was rejected or has not arrived yet has no sender to count the msgs for
+                if find_chain_id(deps.storage, &dest_channel_id).is_none() {
+                    return Err(StdError::generic_err(format!("Channel {} {}", dest_channel_id, UNBOUND_CHANNEL)));
+                }
+                let mut queue: Vec<Vec<Msg>> = new_queue(deps.storage)?;
                 let result = receive_queue(deps.storage, get_timeout(&env), Some(dest_channel_id), q, &mut queue, &env, deps.api);
-                IBC_MSG_SEND_DEBUG.save(deps.storage, "ibc_packet_receive".to_string(), &result.as_ref().unwrap().messages)?;
+                if let Ok(res) = &result {
+                    IBC_MSG_SEND_DEBUG.save(deps.storage, "ibc_packet_receive".to_string(), &res.messages)?;
+                }
                 return result;
             },
-            PacketMsg::WhoAmI { chain_id } => receive_who_am_i(deps, dest_channel_id, chain_id),
+            PacketMsg::WhoAmI { chain_id } => receive_who_am_i(deps, env, dest_channel_id, chain_id),
         }
     })()
     .or_else(|e| {
@@ -150,17 +167,17 @@ pub fn ibc_packet_receive(
 // processes PacketMsg::WhoAmI
 fn receive_who_am_i(
     deps: DepsMut,
+    env: Env,
     channel_id: String,
     chain_id: u32,
 ) -> StdResult<IbcReceiveResponse> {
-    let action = |_| -> StdResult<String> { Ok(channel_id.to_string()) };
-    CHANNELS.update(deps.storage, chain_id, action)?;
+    let restored = bind_peer(deps.storage, chain_id, &channel_id)?;
+    // what timed out or was queued while the peer was away goes out on the new channel
+    let resent = resend_to(deps.storage, chain_id, get_timeout(&env))?;
 
     // initialize the highest_request of that chain
     // let action = |_| -> StdResult<u32> { Ok(0) };
     // HIGHEST_REQ.update(deps.storage, chain_id, action)?;
-    // initialize the highest_request of that chain
-    HIGHEST_ABORT.save(deps.storage, chain_id, &-1)?;
 
     let response = WhoAmIResponse {};
     let acknowledgement = to_binary(&AcknowledgementMsg::Ok(response))?;
@@ -168,31 +185,87 @@ fn receive_who_am_i(
     Ok(IbcReceiveResponse::new()
         .set_ack(acknowledgement)
         .add_attribute("action", "receive_who_am_i")
-        .add_attribute("chain_id", chain_id.to_string()))
+        .add_attribute("chain_id", chain_id.to_string())
+        .add_attribute("restored", restored.to_string())
+        .add_messages(resent))
 }
 
 
 #[entry_point]
+/// MsgQueue packets acked with an error the peer may not return later are sent again
 pub fn ibc_packet_ack(
-    _deps: DepsMut,
-    _env: Env,
+    deps: DepsMut,
+    env: Env,
     msg: IbcPacketAckMsg,
 ) -> StdResult<IbcBasicResponse> {
-    let packet: PacketMsg = from_slice(&msg.original_packet.data)?;
+    let original = msg.original_packet;
+    let packet = decode_packet(&original.data, channel_encoding(deps.storage, &original.src.channel_id)?)?;
     match packet {
-        PacketMsg::MsgQueue(_q) => Ok(IbcBasicResponse::new()),
+        PacketMsg::MsgQueue(_q) => {
+            let channel_id = original.src.channel_id;
+            let res = IbcBasicResponse::new()
+                .add_attribute("action", "ibc_packet_ack")
+                .add_attribute("channel_id", channel_id.clone());
+            let ack: StdResult<AcknowledgementMsg<MsgQueueResponse>> = from_slice(&msg.acknowledgement.data);
+            let error = match ack {
+                Ok(AcknowledgementMsg::Ok(_)) => None,
+                Ok(AcknowledgementMsg::Err(error)) => Some(error),
+                Err(e) => Some(e.to_string()),
+            };
+            match error {
+                None => {
+                    acked(deps.storage, &channel_id, &original.data)?;
+                    Ok(res)
+                },
+                // the peer did not accept our WhoAmI, announce this chain again ahead of the queue
+                Some(error) if is_transient(&error) => {
+                    let timeout = get_timeout(&env);
+                    match retry(deps.storage, &channel_id, &original.data, timeout.clone())? {
+                        Some(resend) => {
+                            let who_am_i = PacketMsg::WhoAmI { chain_id: CONFIG.load(deps.storage)?.chain_id };
+                            let data = encode_for_channel(deps.storage, &channel_id, &who_am_i)?;
+                            Ok(res
+                                .add_message(IbcMsg::SendPacket { channel_id: channel_id.clone(), data, timeout })
+                                .add_message(resend)
+                                .add_attribute("resent", "true"))
+                        },
+                        None => Ok(res.add_attribute("resent", "false")),
+                    }
+                },
+                // the same payload would fail again
+                Some(error) => {
+                    acked(deps.storage, &channel_id, &original.data)?;
+                    Ok(res.add_attribute("resent", "false").add_attribute("error", error))
+                },
+            }
+        },
         PacketMsg::WhoAmI { chain_id: _ } => Ok(IbcBasicResponse::new()),
     }
 }
 
 #[entry_point]
-/// This will never be called
+/// A timeout closes the ordered channel, MsgQueue packets are sent again once the peer is back
 pub fn ibc_packet_timeout(
-    _deps: DepsMut,
-    _env: Env,
-    _msg: IbcPacketTimeoutMsg,
+    deps: DepsMut,
+    env: Env,
+    msg: IbcPacketTimeoutMsg,
 ) -> StdResult<IbcBasicResponse> {
-    Ok(IbcBasicResponse::new().add_attribute("action", "ibc_packet_timeout"))
+    let original = msg.packet;
+    let channel_id = original.src.channel_id;
+    let packet = decode_packet(&original.data, channel_encoding(deps.storage, &channel_id)?)?;
+    let res = IbcBasicResponse::new()
+        .add_attribute("action", "ibc_packet_timeout")
+        .add_attribute("channel_id", channel_id.clone());
+    match packet {
+        PacketMsg::MsgQueue(_q) => {
+            let msgs = timed_out(deps.storage, &channel_id, &original.data, get_timeout(&env))?;
+            Ok(res.add_attribute("resent", (!msgs.is_empty()).to_string()).add_messages(msgs))
+        },
+        PacketMsg::WhoAmI { chain_id: _ } => {
+            disconnect_channel(deps.storage, &channel_id)?;
+            Ok(res)
+        },
+    }
 }
 
 
@@ -623,3 +696,157 @@ mod tests {
 }
 
 */
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::contract::instantiate;
+    use crate::msg::InstantiateMsg;
+    use crate::retransmit::{outstanding, send_queue};
+    use crate::state::{Batch, Encoding, PENDING_MSGS};
+    use crate::utils::{IBC_APP_VERSION, IBC_APP_VERSION_COMPACT};
+
+    use cosmwasm_std::testing::{
+        mock_dependencies, mock_env, mock_ibc_channel, mock_ibc_channel_connect_ack, mock_ibc_channel_open_init,
+        mock_ibc_channel_open_try, mock_ibc_packet_ack, mock_ibc_packet_recv, mock_info, MockApi, MockQuerier, MockStorage,
+    };
+    use cosmwasm_std::{CosmosMsg, IbcAcknowledgement, IbcOrder, OwnedDeps};
+
+    fn setup(allowed_ports: Option<Vec<String>>, allowed_connections: Option<Vec<String>>) -> OwnedDeps<MockStorage, MockApi, MockQuerier> {
+        let mut deps = mock_dependencies();
+        let msg = InstantiateMsg {
+            chain_id: 0,
+            n: 4,
+            f: 1,
+            three_chain_demo: None,
+            contract_addr: "target".to_string(),
+            pipeline_depth: None,
+            max_batch_size: None,
+            max_mempool_size: None,
+            allowed_ports,
+            allowed_connections,
+            members: None,
+            first_slot: None,
+            domain: "tb-test".to_string(),
+            sender_field: None,
+            owner: None,
+            targets: None,
+        };
+        instantiate(deps.as_mut(), mock_env(), mock_info("creator", &[]), msg).unwrap();
+        deps
+    }
+
+    #[test]
+    fn rejects_wrong_version_and_ordering() {
+        let mut deps = setup(None, None);
+        let bad_version = mock_ibc_channel_open_init("channel-1", IbcOrder::Ordered, "ics20-1");
+        assert!(ibc_channel_open(deps.as_mut(), mock_env(), bad_version).is_err());
+        let unordered = mock_ibc_channel_open_init("channel-1", IbcOrder::Unordered, IBC_APP_VERSION);
+        assert!(ibc_channel_open(deps.as_mut(), mock_env(), unordered).is_err());
+        let bad_counterparty = IbcChannelOpenMsg::new_try(mock_ibc_channel("channel-1", IbcOrder::Ordered, IBC_APP_VERSION), "ics20-1");
+        assert!(ibc_channel_open(deps.as_mut(), mock_env(), bad_counterparty).is_err());
+        let bad_ack = IbcChannelConnectMsg::new_ack(mock_ibc_channel("channel-1", IbcOrder::Ordered, IBC_APP_VERSION), "ics20-1");
+        assert!(ibc_channel_connect(deps.as_mut(), mock_env(), bad_ack).is_err());
+
+        let ok = mock_ibc_channel_open_try("channel-1", IbcOrder::Ordered, IBC_APP_VERSION);
+        ibc_channel_open(deps.as_mut(), mock_env(), ok).unwrap();
+        let res = ibc_channel_connect(deps.as_mut(), mock_env(), mock_ibc_channel_connect_ack("channel-1", IbcOrder::Ordered, IBC_APP_VERSION)).unwrap();
+        // the WhoAmI packet
+        assert_eq!(res.messages.len(), 1);
+        assert_eq!(CONFIG.load(&deps.storage).unwrap().channel_ids, vec!["channel-1".to_string()]);
+    }
+
+    #[test]
+    fn channel_version_selects_the_encoding() {
+        let mut deps = setup(None, None);
+        // both ends have to agree on the version
+        let mismatch = IbcChannelOpenMsg::new_try(mock_ibc_channel("channel-1", IbcOrder::Ordered, IBC_APP_VERSION_COMPACT), IBC_APP_VERSION);
+        assert!(ibc_channel_open(deps.as_mut(), mock_env(), mismatch).is_err());
+
+        let open = mock_ibc_channel_open_try("channel-1", IbcOrder::Ordered, IBC_APP_VERSION_COMPACT);
+        ibc_channel_open(deps.as_mut(), mock_env(), open).unwrap();
+        let connect = mock_ibc_channel_connect_ack("channel-1", IbcOrder::Ordered, IBC_APP_VERSION_COMPACT);
+        let res = ibc_channel_connect(deps.as_mut(), mock_env(), connect).unwrap();
+        assert_eq!(channel_encoding(&deps.storage, "channel-1").unwrap(), Encoding::Compact);
+        match &res.messages[0].msg {
+            CosmosMsg::Ibc(IbcMsg::SendPacket { data, .. }) => {
+                assert_eq!(decode_packet(data, Encoding::Compact).unwrap(), PacketMsg::WhoAmI { chain_id: 0 });
+            },
+            msg => panic!("unexpected {:?}", msg),
+        }
+        // channels never recorded are JSON
+        assert_eq!(channel_encoding(&deps.storage, "channel-2").unwrap(), Encoding::Json);
+    }
+
+    #[test]
+    fn msg_queue_before_who_am_i_is_error_acked() {
+        let mut deps = setup(None, None);
+        let open = mock_ibc_channel_open_try("channel-1", IbcOrder::Ordered, IBC_APP_VERSION);
+        ibc_channel_open(deps.as_mut(), mock_env(), open).unwrap();
+        ibc_channel_connect(deps.as_mut(), mock_env(), mock_ibc_channel_connect_ack("channel-1", IbcOrder::Ordered, IBC_APP_VERSION)).unwrap();
+
+        let proof = Msg::Proof { instance_id: 0, key1: 0, key1_val: Batch::noop(), prev_key1: -1, view: 0 };
+        let queue = PacketMsg::MsgQueue(vec![proof]);
+        let res = ibc_packet_receive(deps.as_mut(), mock_env(), mock_ibc_packet_recv("channel-1", &queue).unwrap()).unwrap();
+        let ack: AcknowledgementMsg<MsgQueueResponse> = from_slice(&res.acknowledgement).unwrap();
+        assert!(matches!(ack, AcknowledgementMsg::Err(_)));
+        assert!(PENDING_MSGS.may_load(&deps.storage, (0, "channel-1".to_string())).unwrap().is_none());
+
+        let who_am_i = PacketMsg::WhoAmI { chain_id: 1 };
+        ibc_packet_receive(deps.as_mut(), mock_env(), mock_ibc_packet_recv("channel-1", &who_am_i).unwrap()).unwrap();
+        let res = ibc_packet_receive(deps.as_mut(), mock_env(), mock_ibc_packet_recv("channel-1", &queue).unwrap()).unwrap();
+        let ack: AcknowledgementMsg<MsgQueueResponse> = from_slice(&res.acknowledgement).unwrap();
+        assert!(matches!(ack, AcknowledgementMsg::Ok(_)));
+        // the instance is not started here yet, so the proof waits for it
+        assert_eq!(PENDING_MSGS.load(&deps.storage, (0, "channel-1".to_string())).unwrap().len(), 1);
+    }
+
+    #[test]
+    fn only_transient_error_acks_are_retried() {
+        let mut deps = setup(None, None);
+        let open = mock_ibc_channel_open_try("channel-1", IbcOrder::Ordered, IBC_APP_VERSION);
+        ibc_channel_open(deps.as_mut(), mock_env(), open).unwrap();
+        ibc_channel_connect(deps.as_mut(), mock_env(), mock_ibc_channel_connect_ack("channel-1", IbcOrder::Ordered, IBC_APP_VERSION)).unwrap();
+        let who_am_i = PacketMsg::WhoAmI { chain_id: 1 };
+        ibc_packet_receive(deps.as_mut(), mock_env(), mock_ibc_packet_recv("channel-1", &who_am_i).unwrap()).unwrap();
+
+        let queue = PacketMsg::MsgQueue(vec![Msg::Request { instance_id: 0, view: 1, chain_id: 0 }]);
+        let error_ack = |error: &str| IbcAcknowledgement::new(to_binary(&AcknowledgementMsg::<MsgQueueResponse>::Err(error.to_string())).unwrap());
+        send_queue(deps.as_mut().storage, 1, vec![Msg::Request { instance_id: 0, view: 1, chain_id: 0 }], get_timeout(&mock_env())).unwrap();
+        let ack = mock_ibc_packet_ack("channel-1", &queue, error_ack("invalid packet: boom")).unwrap();
+        let res = ibc_packet_ack(deps.as_mut(), mock_env(), ack).unwrap();
+        assert!(res.messages.is_empty());
+        assert!(outstanding(&deps.storage).unwrap().is_empty());
+
+        // the peer lost our WhoAmI, it is sent again ahead of the queue
+        send_queue(deps.as_mut().storage, 1, vec![Msg::Request { instance_id: 0, view: 1, chain_id: 0 }], get_timeout(&mock_env())).unwrap();
+        let unbound = format!("invalid packet: Channel channel-0 {}", UNBOUND_CHANNEL);
+        let ack = mock_ibc_packet_ack("channel-1", &queue, error_ack(&unbound)).unwrap();
+        let res = ibc_packet_ack(deps.as_mut(), mock_env(), ack).unwrap();
+        let sent: Vec<PacketMsg> = res.messages
+            .iter()
+            .map(|msg| match &msg.msg {
+                CosmosMsg::Ibc(IbcMsg::SendPacket { data, .. }) => decode_packet(data, Encoding::Json).unwrap(),
+                msg => panic!("unexpected {:?}", msg),
+            })
+            .collect();
+        assert_eq!(sent, vec![PacketMsg::WhoAmI { chain_id: 0 }, queue]);
+        assert_eq!(outstanding(&deps.storage).unwrap()[0].retries, 1);
+    }
+
+    #[test]
+    fn rejects_counterparty_outside_allow_lists() {
+        let mut deps = setup(Some(vec!["wasm.peer".to_string()]), None);
+        // mock channels come from "their_port"
+        let open = mock_ibc_channel_open_init("channel-1", IbcOrder::Ordered, IBC_APP_VERSION);
+        assert!(ibc_channel_open(deps.as_mut(), mock_env(), open).is_err());
+
+        let mut deps = setup(Some(vec!["their_port".to_string()]), Some(vec!["connection-0".to_string()]));
+        let open = mock_ibc_channel_open_init("channel-1", IbcOrder::Ordered, IBC_APP_VERSION);
+        assert!(ibc_channel_open(deps.as_mut(), mock_env(), open).is_err());
+
+        let mut deps = setup(Some(vec!["their_port".to_string()]), Some(vec!["connection-2".to_string()]));
+        let open = mock_ibc_channel_open_init("channel-1", IbcOrder::Ordered, IBC_APP_VERSION);
+        ibc_channel_open(deps.as_mut(), mock_env(), open).unwrap();
+    }
+}
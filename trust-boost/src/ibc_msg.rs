@@ -1,8 +1,8 @@
-use cosmwasm_std::{ContractResult};
+use cosmwasm_std::{Binary, ContractResult};
 use schemars::JsonSchema;
 use serde::{Deserialize, Serialize};
 
-use crate::state::InputType;
+use crate::state::{InputType, ReceiptStatus};
 
 /// Messages that will be sent over the IBC channel
 #[derive(Serialize, Deserialize, Clone, Debug, PartialEq, JsonSchema)]
@@ -20,15 +20,18 @@ pub enum PacketMsg {
     // },
 }
 
+/// Consensus messages, each one tagged with the IT-HS instance it belongs to
 #[derive(Serialize, Deserialize, Clone, Debug, PartialEq, JsonSchema)]
 #[serde(rename_all = "snake_case")]
 pub enum Msg {
 
     Request { 
+        instance_id: u64,
         view: u32, 
         chain_id: u32 
     },
     Suggest { 
+        instance_id: u64,
         chain_id: u32,
         view: u32,
         key2: u32,
@@ -38,44 +41,68 @@ pub enum Msg {
         key3_val: InputType
     },
     Proof {
+        instance_id: u64,
         key1: u32,
         key1_val: InputType,
         prev_key1: i32,
         view: u32
     },
     Abort {
+        instance_id: u64,
         view: u32,
         chain_id: u32,
     },
     Propose { 
+        instance_id: u64,
         chain_id: u32,
         k: u32, 
         v: InputType,
         view: u32 
     },
+    // votes carry the digest of the value, see values.rs
     Echo {
+        instance_id: u64,
         // chain_id: u32,
-        val: InputType,
+        digest: Binary,
         view: u32
     },
     Key1 {
-        val: InputType,
+        instance_id: u64,
+        digest: Binary,
         view: u32
     },
     Key2 {
-        val: InputType,
+        instance_id: u64,
+        digest: Binary,
         view: u32
     },
     Key3 {
-        val: InputType,
+        instance_id: u64,
+        digest: Binary,
         view: u32
     },
     Lock {
-        val: InputType,
+        instance_id: u64,
+        digest: Binary,
         view: u32
     },
     Done {
-        val: InputType
+        instance_id: u64,
+        digest: Binary
+    },
+    // ask the sender of a vote for the body of its digest
+    Fetch {
+        instance_id: u64,
+        digest: Binary,
+    },
+    Value {
+        instance_id: u64,
+        val: InputType,
+    },
+    // what became of the inputs of decided slot instance_id on the sender, see reports.rs
+    Report {
+        instance_id: u64,
+        statuses: Vec<ReceiptStatus>,
     },
 }
 
@@ -83,17 +110,64 @@ impl Msg {
     // name return the static str version of the Msg type
     pub(crate) fn name(&self) -> &'static str {
         match self {
-            Msg::Request { view: _, chain_id: _ } => stringify!(Request),
-            Msg::Suggest { chain_id: _, view: _, key2: _, key2_val: _, prev_key2: _, key3: _, key3_val: _ } => stringify!(Suggest),
-            Msg::Proof { key1: _, key1_val: _, prev_key1: _, view: _ } => stringify!(Proof),
-            Msg::Abort { view: _, chain_id : _} => stringify!(Abort),
-            Msg::Propose { chain_id: _, k: _, v: _, view: _ } => stringify!(Propose),
-            Msg::Echo { val: _, view : _} => stringify!(Echo),
-            Msg::Key1 { val: _, view : _} => stringify!(Key1),
-            Msg::Key2 { val: _, view : _} => stringify!(Key2),
-            Msg::Key3 { val: _, view : _} => stringify!(Key3),
-            Msg::Lock { val: _, view : _} => stringify!(Lock),
-            Msg::Done { val: _ } => stringify!(Done),
+            Msg::Request { .. } => stringify!(Request),
+            Msg::Suggest { .. } => stringify!(Suggest),
+            Msg::Proof { .. } => stringify!(Proof),
+            Msg::Abort { .. } => stringify!(Abort),
+            Msg::Propose { .. } => stringify!(Propose),
+            Msg::Echo { .. } => stringify!(Echo),
+            Msg::Key1 { .. } => stringify!(Key1),
+            Msg::Key2 { .. } => stringify!(Key2),
+            Msg::Key3 { .. } => stringify!(Key3),
+            Msg::Lock { .. } => stringify!(Lock),
+            Msg::Done { .. } => stringify!(Done),
+            Msg::Fetch { .. } => stringify!(Fetch),
+            Msg::Value { .. } => stringify!(Value),
+            Msg::Report { .. } => stringify!(Report),
+        }
+    }
+
+    // instance_id returns the id of the IT-HS instance the Msg belongs to
+    pub fn instance_id(&self) -> u64 {
+        match self {
+            Msg::Request { instance_id, .. }
+            | Msg::Suggest { instance_id, .. }
+            | Msg::Proof { instance_id, .. }
+            | Msg::Abort { instance_id, .. }
+            | Msg::Propose { instance_id, .. }
+            | Msg::Echo { instance_id, .. }
+            | Msg::Key1 { instance_id, .. }
+            | Msg::Key2 { instance_id, .. }
+            | Msg::Key3 { instance_id, .. }
+            | Msg::Lock { instance_id, .. }
+            | Msg::Done { instance_id, .. }
+            | Msg::Fetch { instance_id, .. }
+            | Msg::Value { instance_id, .. }
+            | Msg::Report { instance_id, .. } => *instance_id,
+        }
+    }
+
+    // the chain a msg names as its sender, None for msgs that do not name one
+    pub fn chain_id(&self) -> Option<u32> {
+        match self {
+            Msg::Request { chain_id, .. }
+            | Msg::Suggest { chain_id, .. }
+            | Msg::Abort { chain_id, .. }
+            | Msg::Propose { chain_id, .. } => Some(*chain_id),
+            _ => None,
+        }
+    }
+
+    // digest of the value a vote is for, None for msgs that are not votes
+    pub fn vote_digest(&self) -> Option<&Binary> {
+        match self {
+            Msg::Echo { digest, .. }
+            | Msg::Key1 { digest, .. }
+            | Msg::Key2 { digest, .. }
+            | Msg::Key3 { digest, .. }
+            | Msg::Lock { digest, .. }
+            | Msg::Done { digest, .. } => Some(digest),
+            _ => None,
         }
     }
 }
@@ -165,3 +239,64 @@ pub struct DoneResponse {
 #[derive(Serialize, Deserialize, Clone, Debug, PartialEq, JsonSchema)]
 pub struct MsgQueueResponse {
 }
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use cosmwasm_std::to_binary;
+    use crate::state::{Batch, TBInput};
+
+    // Vote msgs as they were before they carried only the digest
+    #[derive(Serialize)]
+    #[serde(rename_all = "snake_case")]
+    enum FullValueMsg {
+        Echo { instance_id: u64, val: InputType, view: u32 },
+        Key1 { instance_id: u64, val: InputType, view: u32 },
+        Key2 { instance_id: u64, val: InputType, view: u32 },
+        Key3 { instance_id: u64, val: InputType, view: u32 },
+        Lock { instance_id: u64, val: InputType, view: u32 },
+        Done { instance_id: u64, val: InputType },
+    }
+
+    #[derive(Serialize)]
+    #[serde(rename_all = "snake_case")]
+    enum FullValuePacket {
+        MsgQueue(Vec<FullValueMsg>),
+    }
+
+    #[test]
+    fn digest_votes_shrink_msg_queues() {
+        // a batch of 4 inputs with a 33 byte compressed public key and a 64 byte signature
+        let input = TBInput {
+            binary: Binary::from(br#"{"register":{"name":"alice"}}"#).to_base64(),
+            public_key: vec![2; 33],
+            signature: vec![7; 64],
+            domain: "tb-test".to_string(),
+            ..TBInput::default()
+        };
+        let val = Batch { inputs: vec![input; 4], reconfig: None };
+        let digest = val.digest();
+        let (instance_id, view) = (0, 0);
+
+        let full = vec![
+            FullValueMsg::Echo { instance_id, val: val.clone(), view },
+            FullValueMsg::Key1 { instance_id, val: val.clone(), view },
+            FullValueMsg::Key2 { instance_id, val: val.clone(), view },
+            FullValueMsg::Key3 { instance_id, val: val.clone(), view },
+            FullValueMsg::Lock { instance_id, val: val.clone(), view },
+            FullValueMsg::Done { instance_id, val },
+        ];
+        let votes = vec![
+            Msg::Echo { instance_id, digest: digest.clone(), view },
+            Msg::Key1 { instance_id, digest: digest.clone(), view },
+            Msg::Key2 { instance_id, digest: digest.clone(), view },
+            Msg::Key3 { instance_id, digest: digest.clone(), view },
+            Msg::Lock { instance_id, digest: digest.clone(), view },
+            Msg::Done { instance_id, digest },
+        ];
+        let before = to_binary(&FullValuePacket::MsgQueue(full)).unwrap().len();
+        let after = to_binary(&PacketMsg::MsgQueue(votes)).unwrap().len();
+        println!("MsgQueue with one vote of every phase: {} bytes with full values, {} bytes with digests", before, after);
+        assert!(after * 10 < before);
+    }
+}
@@ -4,10 +4,32 @@ pub mod ibc_msg;
 mod error;
 pub mod msg;
 pub mod state;
+pub mod digest;
+pub mod codec;
+pub mod signature;
+pub mod replay;
+pub mod payload;
+pub mod targets;
+pub mod ownership;
+pub mod escrow;
+pub mod receipts;
+pub mod reports;
+pub mod values;
 pub mod utils;
 pub mod queue_handler;
 pub mod view_change;
 pub mod abort;
-pub mod malicious_trigger;
+pub mod log;
+pub mod mempool;
+pub mod membership;
+pub mod pipeline;
+pub mod retransmit;
+pub mod reconfig;
+#[cfg(test)]
+mod sim;
+#[cfg(test)]
+mod properties;
+#[cfg(any(test, feature = "byzantine"))]
+pub mod byzantine;
 
 pub use crate::error::ContractError;
@@ -0,0 +1,208 @@
+use cosmwasm_std::{Api, Env, Order, StdResult, Storage, SubMsg, WasmMsg};
+use cw_storage_plus::Bound;
+
+use crate::reconfig::{activate, schedule};
+use crate::replay::consume_nonce;
+use crate::state::{LogEntry, TBInput, CONFIG, EXEC_CURSOR, LOG, LOG_LENGTH, STATE};
+use crate::payload::with_sender;
+use crate::targets::target_of;
+use crate::receipts::{expect_reply, skip};
+use crate::escrow::{is_escrowed, refund};
+use crate::contract::EXECUTE_REPLY_ID;
+
+// Instance ids double as log slots: slot k holds the value decided by instance k.
+// Instances may decide out of order, so the log only grows over the contiguous
+// prefix of decided instances. That keeps it append-only and identical on every chain.
+
+// Append every decided instance that directly follows the end of the log.
+// Returns the newly appended slots in order. Reconfigurations in the appended slots are
+// scheduled, and those the log has reached become active.
+pub fn append_decided(store: &mut dyn Storage) -> StdResult<Vec<u64>> {
+    let mut length = LOG_LENGTH.load(store)?;
+    let mut appended = Vec::new();
+    while let Some(state) = STATE.may_load(store, length)? {
+        let value = match state.done {
+            Some(val) => val,
+            None => break,
+        };
+        let entry = LogEntry {
+            slot: length,
+            value,
+            view: state.view,
+            block_height: state.done_block_height.unwrap_or_default(),
+            timestamp: state.done_timestamp.unwrap_or(state.start_time),
+        };
+        schedule(store, length, &entry.value)?;
+        LOG.save(store, length, &entry)?;
+        appended.push(length);
+        length += 1;
+    }
+    LOG_LENGTH.save(store, &length)?;
+    activate(store)?;
+    Ok(appended)
+}
+
+// Execute the logged values that have not been executed yet, strictly in slot order.
+// Inputs with a bad signature or that expired are skipped. The msgs are the executions and the refunds of
+// skipped inputs this chain holds funds for.
+pub fn execute_decided(store: &mut dyn Storage, env: &Env, api: &dyn Api) -> StdResult<Vec<SubMsg>> {
+    let length = LOG_LENGTH.load(store)?;
+    let mut cursor = EXEC_CURSOR.load(store)?;
+    let mut vec_msgs: Vec<SubMsg> = Vec::new();
+    while cursor < length {
+        let mut state = STATE.load(store, cursor)?;
+        let batch = LOG.load(store, cursor)?.value;
+        if !state.done_executed {
+            // the inputs of a batch are executed in the order they were proposed
+            for (index, val) in (0u32..).zip(batch.inputs) {
+                if !val.verify(api).unwrap_or(false) {
+                    skip_and_refund(store, env, cursor, index, &val, "invalid signature".to_string(), &mut vec_msgs)?;
+                    continue;
+                }
+                // the nonce stays unused, the sender can sign the action again
+                if val.expires.is_expired(env) {
+                    skip_and_refund(store, env, cursor, index, &val, "expired".to_string(), &mut vec_msgs)?;
+                    continue;
+                }
+                let config = CONFIG.load(store)?;
+                // a payload that does not fit is skipped like a bad signature, on every chain alike
+                let msg = match with_sender(&val.binary, &config.sender_field, &val.sender()?) {
+                    Ok(msg) => msg,
+                    Err(err) => {
+                        skip_and_refund(store, env, cursor, index, &val, err.to_string(), &mut vec_msgs)?;
+                        continue;
+                    },
+                };
+                // the target may have been removed from this chain's registry since the input was submitted
+                let contract_addr = match target_of(store, &val) {
+                    Ok(contract_addr) => contract_addr,
+                    Err(err) => {
+                        skip_and_refund(store, env, cursor, index, &val, err.to_string(), &mut vec_msgs)?;
+                        continue;
+                    },
+                };
+                // the escrow of a replay belongs to the first input with the nonce
+                if !consume_nonce(store, &val, cursor)? {
+                    skip(store, env, cursor, index, &val, "replayed nonce or foreign domain".to_string())?;
+                    continue;
+                }
+                if !val.funds.is_empty() && !is_escrowed(store, &val)? {
+                    skip(store, env, cursor, index, &val, "funds not escrowed on this chain".to_string())?;
+                    continue;
+                }
+                expect_reply(store, env, cursor, index, &val, &contract_addr)?;
+                let wasm_msg = WasmMsg::Execute {
+                    contract_addr: contract_addr.to_string(),
+                    msg,
+                    funds: val.funds,
+                };
+                vec_msgs.push(SubMsg::reply_always(wasm_msg, EXECUTE_REPLY_ID));
+            }
+        }
+        state.done_executed = true;
+        STATE.save(store, cursor, &state)?;
+        cursor += 1;
+    }
+    EXEC_CURSOR.save(store, &cursor)?;
+    Ok(vec_msgs)
+}
+
+fn skip_and_refund(
+    store: &mut dyn Storage,
+    env: &Env,
+    slot: u64,
+    index: u32,
+    input: &TBInput,
+    reason: String,
+    msgs: &mut Vec<SubMsg>,
+) -> StdResult<()> {
+    skip(store, env, slot, index, input, reason)?;
+    if let Some(refund) = refund(store, input)? {
+        msgs.push(SubMsg::new(refund));
+    }
+    Ok(())
+}
+
+pub fn read_log(store: &dyn Storage, start_after: Option<u64>, limit: usize) -> StdResult<Vec<LogEntry>> {
+    let start = start_after.map(Bound::exclusive);
+    LOG.range(store, start, None, Order::Ascending)
+        .take(limit)
+        .map(|item| item.map(|(_, entry)| entry))
+        .collect()
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use cosmwasm_std::testing::{mock_dependencies, mock_env};
+    use cosmwasm_std::Addr;
+    use cosmwasm_std::Empty;
+    use crate::receipts::slot_receipts;
+    use crate::sim::{sign, signed, DOMAIN};
+    use crate::state::{Batch, Config, Expiry, ReceiptStatus, State, TBInput, CONFIG, TARGETS, USED_NONCES};
+
+    fn input(binary: &str) -> Batch {
+        TBInput { binary: binary.to_string(), ..TBInput::default() }.into()
+    }
+
+    fn decide(store: &mut dyn Storage, instance_id: u64, binary: &str, height: u64) {
+        let env = mock_env();
+        let config = Config::new(0, Addr::unchecked("target"));
+        let mut state = State::new(instance_id, &config, input(binary), env.block.time);
+        state.done = Some(input(binary));
+        state.done_block_height = Some(height);
+        STATE.save(store, instance_id, &state).unwrap();
+    }
+
+    #[test]
+    fn log_grows_over_contiguous_prefix_only() {
+        let mut deps = mock_dependencies();
+        let store = deps.as_mut().storage;
+        LOG_LENGTH.save(store, &0).unwrap();
+
+        // slot 1 decides first, it has to wait for slot 0
+        decide(store, 1, "b", 10);
+        assert!(append_decided(store).unwrap().is_empty());
+
+        decide(store, 0, "a", 11);
+        assert_eq!(append_decided(store).unwrap(), vec![0, 1]);
+        assert_eq!(LOG_LENGTH.load(store).unwrap(), 2);
+
+        // an instance that is started but not decided stops the log
+        let config = Config::new(0, Addr::unchecked("target"));
+        STATE.save(store, 2, &State::new(2, &config, input("c"), mock_env().block.time)).unwrap();
+        assert!(append_decided(store).unwrap().is_empty());
+
+        let entries = read_log(store, None, 10).unwrap();
+        let values: Vec<_> = entries.iter().map(|e| (e.slot, e.value.inputs[0].binary.as_str(), e.block_height)).collect();
+        assert_eq!(values, vec![(0, "a", 11), (1, "b", 10)]);
+        assert_eq!(read_log(store, Some(0), 10).unwrap()[0].slot, 1);
+    }
+
+    #[test]
+    fn inputs_expired_by_execution_are_skipped() {
+        let mut deps = mock_dependencies();
+        let env = mock_env();
+        let store = &mut deps.storage;
+        let mut config = Config::new(0, Addr::unchecked("target"));
+        config.domain = DOMAIN.to_string();
+        CONFIG.save(store, &config).unwrap();
+        TARGETS.save(store, &config.contract_addr, &Empty {}).unwrap();
+        LOG_LENGTH.save(store, &0).unwrap();
+        EXEC_CURSOR.save(store, &0).unwrap();
+
+        // decided while still current, executed once the chain is past its height
+        let expired = sign(TBInput { expires: Expiry::AtHeight(env.block.height), ..signed(r#"{"register":{"name":"a"}}"#) });
+        let current = signed(r#"{"register":{"name":"b"}}"#);
+        let batch = Batch { inputs: vec![expired.clone(), current], reconfig: None };
+        let mut state = State::new(0, &config, batch.clone(), env.block.time);
+        state.done = Some(batch);
+        STATE.save(store, 0, &state).unwrap();
+        append_decided(store).unwrap();
+
+        assert_eq!(execute_decided(store, &env, &deps.api).unwrap().len(), 1);
+        let receipts = slot_receipts(store, 0).unwrap();
+        assert_eq!(receipts[0].status, ReceiptStatus::Skipped { reason: "expired".to_string() });
+        assert!(!USED_NONCES.has(store, (expired.sender().unwrap().to_string(), expired.nonce)));
+    }
+}
@@ -0,0 +1,288 @@
+use cosmwasm_std::{Order, StdError, StdResult, Storage};
+
+use crate::msg::MemberStatus;
+use crate::reconfig::{max_n, scheduled_member};
+use crate::state::{ChannelPeer, Member, CHANNELS, CHANNEL_PEERS, CONFIG, DISCONNECTED, MEMBERS};
+use crate::retransmit::forget;
+use crate::values::move_parked;
+
+// n and f are declared at instantiate and only change through governance (SudoMsg).
+// A closed channel only marks its peer as disconnected: the chain keeps its chain_id and
+// still counts in n, so the quorum of running instances is unchanged. Msgs to it are dropped
+// until a new channel to the same chain completes WhoAmI. Shrinking n to the connected
+// replicas is a separate, governed step (see SudoMsg::RecomputeQuorum).
+//
+// A chain_id is only bound to a channel whose counterparty matches the configured member,
+// and never taken over from a connected channel, so a peer cannot impersonate another replica.
+// Members of a scheduled reconfiguration are accepted before it is active.
+
+pub fn is_connected(store: &dyn Storage, chain_id: u32) -> bool {
+    CHANNELS.has(store, chain_id) && !DISCONNECTED.has(store, chain_id)
+}
+
+// n >= 3f + 1, or the 3 chain demo setup with f = 1 when explicitly asked for
+pub fn validate_quorum(chain_id: u32, n: u32, f: u32, three_chain_demo: bool) -> StdResult<()> {
+    if chain_id >= n {
+        return Err(StdError::generic_err(format!("chain_id {} must be below n = {}", chain_id, n)));
+    }
+    let demo = three_chain_demo && n == 3 && f == 1;
+    if n < 3 * f + 1 && !demo {
+        return Err(StdError::generic_err(format!(
+            "n = {} cannot tolerate f = {}, n must be at least 3f + 1", n, f
+        )));
+    }
+    Ok(())
+}
+
+// An instance can only make progress once n - f replicas, this chain included, are connected
+pub fn has_channel_quorum(store: &dyn Storage) -> StdResult<bool> {
+    let config = CONFIG.load(store)?;
+    let connected = CHANNELS
+        .keys(store, None, None, Order::Ascending)
+        .collect::<StdResult<Vec<_>>>()?
+        .into_iter()
+        .filter(|chain_id| !DISCONNECTED.has(store, *chain_id))
+        .count() as u32;
+    Ok(connected + 1 >= config.n - config.f)
+}
+
+// Mark the peer behind a closed local channel as disconnected, returns its chain_id if it was bound
+pub fn disconnect_channel(store: &mut dyn Storage, channel_id: &str) -> StdResult<Option<u32>> {
+    let mut config = CONFIG.load(store)?;
+    config.channel_ids.retain(|id| id != channel_id);
+    CONFIG.save(store, &config)?;
+
+    let chain_id = CHANNELS
+        .range(store, None, None, Order::Ascending)
+        .filter_map(|item| item.ok())
+        .find(|(_, id)| id == channel_id)
+        .map(|(chain_id, _)| chain_id);
+    if let Some(chain_id) = chain_id {
+        DISCONNECTED.save(store, chain_id, &channel_id.to_string())?;
+    }
+    CHANNEL_PEERS.remove(store, channel_id.to_string());
+    Ok(chain_id)
+}
+
+pub fn set_members(store: &mut dyn Storage, members: Vec<(u32, Member)>) -> StdResult<()> {
+    let old: Vec<u32> = MEMBERS
+        .keys(store, None, None, Order::Ascending)
+        .collect::<StdResult<Vec<_>>>()?;
+    for chain_id in old {
+        MEMBERS.remove(store, chain_id);
+    }
+    for (chain_id, member) in members {
+        MEMBERS.save(store, chain_id, &member)?;
+    }
+    Ok(())
+}
+
+fn matches(member: &Member, peer: &ChannelPeer) -> bool {
+    member.port_id.iter().all(|port_id| *port_id == peer.port_id)
+        && member.connection_id.iter().all(|connection_id| *connection_id == peer.connection_id)
+}
+
+// Check a WhoAmI claim against the membership and the existing bindings
+fn check_claim(store: &dyn Storage, chain_id: u32, channel_id: &str) -> StdResult<()> {
+    let config = CONFIG.load(store)?;
+    if chain_id == config.chain_id {
+        return Err(StdError::generic_err(format!("Chain id {} is this chain", chain_id)));
+    }
+    let n = max_n(store)?;
+    if chain_id >= n {
+        return Err(StdError::generic_err(format!("Chain id {} is outside of n = {}", chain_id, n)));
+    }
+    let configured = MEMBERS
+        .keys(store, None, None, Order::Ascending)
+        .next()
+        .is_some();
+    let scheduled = scheduled_member(store, chain_id)?;
+    if configured || scheduled.is_some() {
+        let member = scheduled.or(MEMBERS.may_load(store, chain_id)?)
+            .ok_or_else(|| StdError::generic_err(format!("Chain id {} is not a member", chain_id)))?;
+        let peer = CHANNEL_PEERS.may_load(store, channel_id.to_string())?
+            .ok_or_else(|| StdError::generic_err(format!("Channel {} is not connected", channel_id)))?;
+        if !matches(&member, &peer) {
+            return Err(StdError::generic_err(format!(
+                "Channel {} does not lead to the configured member {}", channel_id, chain_id
+            )));
+        }
+    }
+    if let Some(bound) = CHANNELS.may_load(store, chain_id)? {
+        if bound != channel_id && !DISCONNECTED.has(store, chain_id) {
+            return Err(StdError::generic_err(format!(
+                "Chain id {} is already bound to {}", chain_id, bound
+            )));
+        }
+    }
+    let other = CHANNELS
+        .range(store, None, None, Order::Ascending)
+        .filter_map(|item| item.ok())
+        .find(|(id, bound)| bound == channel_id && *id != chain_id);
+    if let Some((other, _)) = other {
+        return Err(StdError::generic_err(format!(
+            "Channel {} is already bound to chain id {}", channel_id, other
+        )));
+    }
+    Ok(())
+}
+
+// Bind chain_id to channel_id on WhoAmI, a disconnected peer coming back on a new channel
+// takes its old place
+pub fn bind_peer(store: &mut dyn Storage, chain_id: u32, channel_id: &str) -> StdResult<bool> {
+    check_claim(store, chain_id, channel_id)?;
+    let closed = DISCONNECTED.may_load(store, chain_id)?;
+    if let Some(closed) = &closed {
+        DISCONNECTED.remove(store, chain_id);
+        move_parked(store, closed, channel_id)?;
+    }
+    let restored = closed.is_some();
+    CHANNELS.save(store, chain_id, &channel_id.to_string())?;
+    Ok(restored)
+}
+
+// Set n to this chain plus its connected peers and forget the disconnected ones.
+// Applies to instances started afterwards. Chain ids index the msg queues, so the
+// connected replicas have to be exactly the chain ids below the new n.
+pub fn recompute_quorum(store: &mut dyn Storage) -> StdResult<u32> {
+    let mut config = CONFIG.load(store)?;
+    let connected: Vec<u32> = CHANNELS
+        .keys(store, None, None, Order::Ascending)
+        .collect::<StdResult<Vec<_>>>()?
+        .into_iter()
+        .filter(|chain_id| !DISCONNECTED.has(store, *chain_id))
+        .collect();
+    let n = connected.len() as u32 + 1;
+    if config.chain_id >= n || connected.iter().any(|chain_id| *chain_id >= n) {
+        return Err(StdError::generic_err(format!(
+            "Connected chain ids do not form 0..{}, cannot shrink the quorum", n
+        )));
+    }
+    validate_quorum(config.chain_id, n, config.f, config.three_chain_demo)?;
+    let disconnected: Vec<u32> = DISCONNECTED
+        .keys(store, None, None, Order::Ascending)
+        .collect::<StdResult<Vec<_>>>()?;
+    for chain_id in disconnected {
+        DISCONNECTED.remove(store, chain_id);
+        CHANNELS.remove(store, chain_id);
+        forget(store, chain_id)?;
+    }
+    config.n = n;
+    CONFIG.save(store, &config)?;
+    Ok(n)
+}
+
+// Configured members next to the chain ids actually bound to a channel
+pub fn membership(store: &dyn Storage) -> StdResult<Vec<MemberStatus>> {
+    let mut chain_ids: Vec<u32> = MEMBERS
+        .keys(store, None, None, Order::Ascending)
+        .chain(CHANNELS.keys(store, None, None, Order::Ascending))
+        .collect::<StdResult<Vec<_>>>()?;
+    chain_ids.sort_unstable();
+    chain_ids.dedup();
+    chain_ids
+        .into_iter()
+        .map(|chain_id| {
+            let channel_id = CHANNELS.may_load(store, chain_id)?;
+            let peer = match &channel_id {
+                Some(channel_id) => CHANNEL_PEERS.may_load(store, channel_id.clone())?,
+                None => None,
+            };
+            Ok(MemberStatus {
+                chain_id,
+                configured: MEMBERS.may_load(store, chain_id)?,
+                connected: is_connected(store, chain_id),
+                channel_id,
+                peer,
+            })
+        })
+        .collect()
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use cosmwasm_std::testing::mock_dependencies;
+    use cosmwasm_std::Addr;
+    use crate::state::Config;
+
+    // what ibc_channel_connect and WhoAmI do for a new channel
+    fn reconnect(store: &mut dyn Storage, chain_id: u32, channel_id: &str) -> bool {
+        let mut config = CONFIG.load(store).unwrap();
+        config.channel_ids.push(channel_id.to_string());
+        CONFIG.save(store, &config).unwrap();
+        bind_peer(store, chain_id, channel_id).unwrap()
+    }
+
+    #[test]
+    fn closed_peer_is_restored_or_dropped_by_recompute() {
+        let mut deps = mock_dependencies();
+        let store = deps.as_mut().storage;
+        let mut config = Config::new(0, Addr::unchecked("target"));
+        for chain_id in 1..4 {
+            config.channel_ids.push(format!("channel-{}", chain_id));
+            config.n += 1;
+            CHANNELS.save(store, chain_id, &format!("channel-{}", chain_id)).unwrap();
+        }
+        CONFIG.save(store, &config).unwrap();
+
+        assert_eq!(disconnect_channel(store, "channel-3").unwrap(), Some(3));
+        assert!(!is_connected(store, 3));
+        assert_eq!(CONFIG.load(store).unwrap().n, 4);
+
+        // re-open on a new channel
+        assert!(reconnect(store, 3, "channel-7"));
+        assert!(is_connected(store, 3));
+        assert_eq!(CONFIG.load(store).unwrap().n, 4);
+
+        // chain 1 leaves the middle of the id range, quorum cannot shrink
+        disconnect_channel(store, "channel-1").unwrap();
+        assert!(recompute_quorum(store).is_err());
+
+        disconnect_channel(store, "channel-7").unwrap();
+        assert!(reconnect(store, 1, "channel-8"));
+        assert_eq!(recompute_quorum(store).unwrap(), 3);
+        assert!(!CHANNELS.has(store, 3));
+    }
+
+    #[test]
+    fn who_am_i_cannot_impersonate_or_take_over() {
+        let mut deps = mock_dependencies();
+        let store = deps.as_mut().storage;
+        let mut config = Config::new(0, Addr::unchecked("target"));
+        config.n = 4;
+        CONFIG.save(store, &config).unwrap();
+        let member = |port: &str| Member { port_id: Some(port.to_string()), connection_id: None };
+        set_members(store, vec![(1, member("wasm.one")), (2, member("wasm.two"))]).unwrap();
+        for (channel_id, port_id) in [("channel-1", "wasm.one"), ("channel-2", "wasm.two"), ("channel-9", "wasm.evil")] {
+            let peer = ChannelPeer { port_id: port_id.to_string(), connection_id: "connection-0".to_string() };
+            CHANNEL_PEERS.save(store, channel_id.to_string(), &peer).unwrap();
+        }
+
+        // unknown chain ids, our own id and a port claiming another member are rejected
+        assert!(bind_peer(store, 3, "channel-9").is_err());
+        assert!(bind_peer(store, 0, "channel-1").is_err());
+        assert!(bind_peer(store, 1, "channel-9").is_err());
+        assert!(!bind_peer(store, 1, "channel-1").unwrap());
+        // a bound channel cannot claim a second chain id
+        assert!(bind_peer(store, 2, "channel-1").is_err());
+
+        // the member's own port on a second channel cannot take over while the first is connected
+        CHANNEL_PEERS.save(store, "channel-5".to_string(), &ChannelPeer { port_id: "wasm.one".to_string(), connection_id: "connection-0".to_string() }).unwrap();
+        assert!(bind_peer(store, 1, "channel-5").is_err());
+        assert_eq!(CHANNELS.load(store, 1).unwrap(), "channel-1");
+
+        let status = membership(store).unwrap();
+        assert_eq!(status.len(), 2);
+        assert!(status[0].connected && !status[1].connected);
+    }
+
+    #[test]
+    fn quorum_needs_3f_plus_1_unless_demo() {
+        assert!(validate_quorum(0, 4, 1, false).is_ok());
+        assert!(validate_quorum(0, 3, 1, false).is_err());
+        assert!(validate_quorum(0, 3, 1, true).is_ok());
+        assert!(validate_quorum(0, 6, 2, true).is_err());
+        assert!(validate_quorum(4, 4, 1, false).is_err());
+    }
+}
@@ -0,0 +1,99 @@
+use cosmwasm_std::{StdResult, Storage};
+
+use crate::error::ContractError;
+use crate::state::{Batch, TBInput, CONFIG, PENDING_INPUTS, PENDING_RECONFIG, STATE};
+
+// Mempool of inputs users submitted to this chain. A replica proposes a batch of at most
+// config.max_batch_size inputs, and inputs that did not make it into the decided batch
+// go back to the front of the pool so they are proposed again. The pool is loaded and saved
+// as a whole, so it holds at most config.max_mempool_size submitted inputs; requeued ones
+// can push it past that by less than a batch.
+
+pub const DEFAULT_MAX_MEMPOOL_SIZE: u32 = 100;
+
+pub fn submit(store: &mut dyn Storage, input: TBInput) -> Result<(), ContractError> {
+    let max_mempool_size = CONFIG.load(store)?.max_mempool_size;
+    let mut inputs = PENDING_INPUTS.load(store)?;
+    if inputs.len() >= max_mempool_size as usize {
+        return Err(ContractError::MempoolFull { size: inputs.len() as u32 });
+    }
+    inputs.push(input);
+    Ok(PENDING_INPUTS.save(store, &inputs)?)
+}
+
+// Build the batch to start a slot with, `first` goes in front of the pooled inputs
+pub fn take_batch(store: &mut dyn Storage, first: Option<TBInput>) -> StdResult<Batch> {
+    let max_batch_size = CONFIG.load(store)?.max_batch_size as usize;
+    let mut inputs = PENDING_INPUTS.load(store)?;
+    let mut batch: Vec<TBInput> = first.into_iter().collect();
+    let take = max_batch_size.saturating_sub(batch.len()).min(inputs.len());
+    batch.extend(inputs.drain(..take));
+    PENDING_INPUTS.save(store, &inputs)?;
+    let reconfig = PENDING_RECONFIG.may_load(store)?;
+    PENDING_RECONFIG.remove(store);
+    Ok(Batch { inputs: batch, reconfig })
+}
+
+// Once a slot is decided its inputs leave the pool, and the inputs this replica
+// proposed that were not decided are queued again
+pub fn retire_batch(store: &mut dyn Storage, instance_id: u64) -> StdResult<()> {
+    let state = STATE.load(store, instance_id)?;
+    let decided = match state.done {
+        Some(val) => val,
+        None => return Ok(()),
+    };
+    let mut inputs = PENDING_INPUTS.load(store)?;
+    inputs.retain(|input| !decided.inputs.contains(input));
+    let requeue: Vec<TBInput> = state.input.inputs
+        .into_iter()
+        .filter(|input| !decided.inputs.contains(input) && !inputs.contains(input))
+        .collect();
+    inputs.splice(0..0, requeue);
+    PENDING_INPUTS.save(store, &inputs)?;
+    // a reconfiguration this replica proposed that lost the slot is proposed again
+    if let Some(reconfig) = state.input.reconfig {
+        if decided.reconfig.as_ref() != Some(&reconfig) && PENDING_RECONFIG.may_load(store)?.is_none() {
+            PENDING_RECONFIG.save(store, &reconfig)?;
+        }
+    }
+    Ok(())
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use cosmwasm_std::testing::{mock_dependencies, mock_env};
+    use cosmwasm_std::Addr;
+    use crate::state::{Config, State};
+
+    fn input(binary: &str) -> TBInput {
+        TBInput { binary: binary.to_string(), ..TBInput::default() }
+    }
+
+    #[test]
+    fn batches_are_capped_and_undecided_inputs_requeued() {
+        let mut deps = mock_dependencies();
+        let store = deps.as_mut().storage;
+        let mut config = Config::new(0, Addr::unchecked("target"));
+        config.max_batch_size = 2;
+        config.max_mempool_size = 4;
+        CONFIG.save(store, &config).unwrap();
+        PENDING_INPUTS.save(store, &Vec::new()).unwrap();
+        for binary in ["a", "b", "c", "d"] {
+            submit(store, input(binary)).unwrap();
+        }
+        assert!(matches!(submit(store, input("e")), Err(ContractError::MempoolFull { size: 4 })));
+
+        let batch = take_batch(store, Some(input("x"))).unwrap();
+        assert_eq!(batch.inputs, vec![input("x"), input("a")]);
+        let batch = take_batch(store, None).unwrap();
+        assert_eq!(batch.inputs, vec![input("b"), input("c")]);
+
+        // another replica's batch containing "d" and "b" wins the slot
+        let mut state = State::new(0, &config, batch, mock_env().block.time);
+        state.done = Some(Batch { inputs: vec![input("d"), input("b")], reconfig: None });
+        STATE.save(store, 0, &state).unwrap();
+        retire_batch(store, 0).unwrap();
+        assert_eq!(PENDING_INPUTS.load(store).unwrap(), vec![input("c")]);
+    }
+}
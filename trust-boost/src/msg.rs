@@ -1,65 +1,188 @@
 use std::{collections::HashSet, fmt, str};
 
-use cosmwasm_std::{Timestamp, to_binary, Binary};
+use cosmwasm_std::{Addr, Timestamp, to_binary, Binary};
 use schemars::JsonSchema;
 use serde::{Deserialize, Serialize};
 
-use crate::{ibc_msg::Msg, state::{State, InputType}};
+use crate::{ibc_msg::Msg, state::{State, InputType, LogEntry, TBInput, Member, ChannelPeer, Reconfig, Receipt, ReceiptStatus, Expiry}};
 
 #[derive(Serialize, Deserialize, Clone, Debug, PartialEq, JsonSchema)]
 pub struct InstantiateMsg {
     pub chain_id: u32,
-    pub input: InputType,
+    /// number of replicas, chain ids are 0..n
+    pub n: u32,
+    /// number of faulty replicas tolerated, n has to be at least 3f + 1
+    pub f: u32,
+    /// accept n = 3 with f = 1 for the 3 chain demo setup
+    pub three_chain_demo: Option<bool>,
     pub contract_addr: String,
+    /// slots started automatically from submitted inputs, none or 0 disables pipelining
+    pub pipeline_depth: Option<u64>,
+    /// max number of inputs decided together in one slot, defaults to 1
+    pub max_batch_size: Option<u32>,
+    /// max number of submitted inputs waiting for a slot, defaults to 100
+    pub max_mempool_size: Option<u32>,
+    /// counterparty ports allowed to open a channel, none allows any
+    pub allowed_ports: Option<Vec<String>>,
+    /// connections channels may be opened on, none allows any
+    pub allowed_connections: Option<Vec<String>>,
+    /// expected replicas, a WhoAmI for any other chain_id or channel is rejected. none accepts any
+    pub members: Option<Vec<(u32, Member)>>,
+    /// first log slot of a chain that joins through a reconfiguration, its activation slot
+    pub first_slot: Option<u64>,
+    /// name of the deployment, the same on every replica and signed into every input
+    pub domain: String,
+    /// field the sender of an input is added as to the target contract's execute msg, defaults to "tb_user"
+    pub sender_field: Option<String>,
+    /// owner of the contract, see ownership.rs, defaults to the instantiating address
+    pub owner: Option<String>,
+    /// contracts inputs may name as their target, contract_addr is registered as well
+    pub targets: Option<Vec<String>>,
     // pub msg: ContractExecuteMsg
 }
 
 #[derive(Serialize, Deserialize, Clone, Debug, PartialEq, JsonSchema)]
 #[serde(rename_all = "snake_case")]
 pub enum ExecuteMsg {
-    Input { value: InputType },
-    PreInput { value: InputType},
+    /// Input starts an instance with value, followed by inputs from the mempool.
+    /// Coins sent along are escrowed for value, they have to be its funds
+    Input { instance_id: u64, value: TBInput },
+    PreInput { instance_id: u64, value: TBInput},
+    /// Submit adds an input to the mempool, with pipelining it takes the next free slot.
+    /// Coins sent along are escrowed as with Input
+    Submit { value: TBInput },
+    /// Refund sends escrowed coins back once their input expired or its nonce was used
+    Refund { sender: String, nonce: u64 },
     ForceAbort {},
-    Abort {},
-    Trigger { behavior: String },
-    Key3 {val: InputType,view: u32,local_channel_id: String},
-    Lock {val: InputType,view: u32,local_channel_id: String},
-    Done {val: InputType,view: u32,local_channel_id: String},
+    Abort { instance_id: u64 },
+    /// SetFaults makes this chain send corrupted msgs, only with the "byzantine" feature, owner only
+    #[cfg(any(test, feature = "byzantine"))]
+    SetFaults { faults: Vec<crate::state::Fault> },
+    Key3 {instance_id: u64, val: InputType,view: u32,local_channel_id: String},
+    Lock {instance_id: u64, val: InputType,view: u32,local_channel_id: String},
+    Done {instance_id: u64, val: InputType,view: u32,local_channel_id: String},
+    /// SetContractAddr changes the default target, owner only
     SetContractAddr {addr: String},
+    /// AddTarget lets inputs name this contract as their target, owner only
+    AddTarget { contract: String },
+    /// RemoveTarget stops inputs for this contract, also those already decided, owner only
+    RemoveTarget { contract: String },
+    /// UpdateOwnership proposes, accepts or renounces the ownership of the contract
+    UpdateOwnership(OwnershipAction),
+}
+
+#[derive(Serialize, Deserialize, Clone, Debug, PartialEq, JsonSchema)]
+#[serde(rename_all = "snake_case")]
+pub enum OwnershipAction {
+    /// by the owner, new_owner has to accept before expiry
+    TransferOwnership { new_owner: String, expiry: Option<Expiry> },
+    /// by the pending owner
+    AcceptOwnership {},
+    /// by the owner, leaves the contract without one
+    RenounceOwnership {},
+}
+
+/// SudoMsg is sent by chain governance
+#[derive(Serialize, Deserialize, Clone, Debug, PartialEq, JsonSchema)]
+#[serde(rename_all = "snake_case")]
+pub enum SudoMsg {
+    /// RecomputeQuorum shrinks n to the connected replicas and forgets the disconnected ones
+    RecomputeQuorum {},
+    /// SetMembers replaces the configured membership, existing bindings are kept
+    SetMembers { members: Vec<(u32, Member)> },
+    /// SetQuorum changes n and f for instances started afterwards
+    SetQuorum { n: u32, f: u32, three_chain_demo: Option<bool> },
+    /// ProposeReconfig approves a new replica set and puts it up for agreement in the next slot this
+    /// chain starts, proposals carrying it are only echoed by chains that approved it too
+    ProposeReconfig { reconfig: Reconfig },
 }
 
 #[derive(Serialize, Deserialize, Clone, Debug, PartialEq, JsonSchema)]
 #[serde(rename_all = "snake_case")]
 pub enum QueryMsg {
-    /// GetValue querys value for given key, GetState returns the current state, GetTx returns tx with tx_id
-    GetState { },
-    GetStateProgress { },
+    /// GetValue querys value for given key, GetState returns the state of an instance, GetTx returns tx with tx_id
+    GetState { instance_id: u64 },
+    GetStateProgress { instance_id: u64 },
+    /// GetMempool lists the inputs submitted to this chain that are not decided yet
+    GetMempool { },
+    /// GetInstances lists the started instances and whether they are decided
+    GetInstances { start_after: Option<u64>, limit: Option<u32> },
+    GetConfig { },
+    /// GetLog pages through the decided values in slot order
+    GetLog { start_after: Option<u64>, limit: Option<u32> },
     GetChannels { },
+    /// GetMembership lists the configured members next to the connected chains
+    GetMembership { },
+    /// GetOutstandingPackets lists the sent msg queues that are not acked yet
+    GetOutstandingPackets { },
+    /// GetReconfigs returns the local reconfiguration proposal and the decided ones not active yet
+    GetReconfigs { },
+    /// GetTargets lists the contracts inputs may be executed on
+    GetTargets { },
+    /// GetReceipts returns what became of every input of a decided slot on this chain
+    GetReceipts { slot: u64 },
+    /// GetInputReceipt returns the receipt of the input sender executed with nonce, null if it was not executed here
+    GetInputReceipt { sender: String, nonce: u64 },
+    /// GetEscrow returns the coins this chain holds for the input of sender with nonce, null if none
+    GetEscrow { sender: String, nonce: u64 },
+    /// GetOwnership returns the owner and the pending ownership transfer
+    GetOwnership {},
+    /// GetSlotReports returns what every chain reported of the execution of a decided slot
+    GetSlotReports { slot: u64 },
     GetTest { },
-    GetHighestReq { },
-    GetHighestAbort { },
-    GetReceivedSuggest { },
-    GetSendAllUpon { },
+    GetHighestReq { instance_id: u64 },
+    GetHighestAbort { instance_id: u64 },
+    GetReceivedSuggest { instance_id: u64 },
+    GetSendAllUpon { instance_id: u64 },
     GetTestQueue { },
-    GetEcho { },
-    GetKey1 { },
-    GetKey2 { },
-    GetKey3 { },
-    GetLock { },
-    GetDone { },
-    GetAbortInfo { },
+    GetEcho { instance_id: u64 },
+    GetKey1 { instance_id: u64 },
+    GetKey2 { instance_id: u64 },
+    GetKey3 { instance_id: u64 },
+    GetLock { instance_id: u64 },
+    GetDone { instance_id: u64 },
+    GetAbortInfo { instance_id: u64 },
     GetDebug { },
     GetIbcDebug {},
     GetDebugReceive {},
     CheckSignature {
-        val: InputType
+        val: TBInput
     },
     GetAddress {
-        val: InputType
+        val: TBInput
     }
 }
 
 // We define a custom struct for each query response
+#[derive(Serialize, Deserialize, Clone, Debug, PartialEq, JsonSchema)]
+pub struct ReceiptsResponse {
+    pub receipts: Vec<Receipt>,
+}
+
+#[derive(Serialize, Deserialize, Clone, Debug, PartialEq, JsonSchema)]
+pub struct ChainReport {
+    pub chain_id: u32,
+    /// outcome of every input of the slot, in batch order
+    pub statuses: Vec<ReceiptStatus>,
+}
+
+#[derive(Serialize, Deserialize, Clone, Debug, PartialEq, JsonSchema)]
+pub struct SlotReportsResponse {
+    pub slot: u64,
+    pub reports: Vec<ChainReport>,
+    /// replicas of the slot that have not reported yet
+    pub missing: Vec<u32>,
+    /// indices of the inputs that succeeded, failed or were skipped on some chains but not on others
+    pub divergent: Vec<u32>,
+}
+
+#[derive(Serialize, Deserialize, Clone, Debug, PartialEq, JsonSchema)]
+pub struct TargetsResponse {
+    /// target of inputs that do not name one
+    pub default_target: Addr,
+    pub targets: Vec<Addr>,
+}
+
 #[derive(Serialize, Deserialize, Clone, Debug, PartialEq, JsonSchema)]
 pub enum ValueResponse {
     KeyFound {
@@ -76,7 +199,7 @@ pub enum StateResponse {
         state: State
     },
     Done {
-        decided_val: String,
+        decided_vals: Vec<String>,
         decided_timestamp: Option<Timestamp>,
         block_height: Option<u64>,
         start_time: Timestamp,
@@ -85,9 +208,44 @@ pub enum StateResponse {
     }
 }
 
+#[derive(Serialize, Deserialize, Clone, Debug, PartialEq, JsonSchema)]
+pub struct InstancesResponse {
+    /// (instance_id, is_done) pairs
+    pub instances: Vec<(u64, bool)>
+}
+
+#[derive(Serialize, Deserialize, Clone, Debug, PartialEq, JsonSchema)]
+pub struct LogResponse {
+    /// total number of slots in the log
+    pub length: u64,
+    pub entries: Vec<LogEntry>
+}
+
+#[derive(Serialize, Deserialize, Clone, Debug, PartialEq, JsonSchema)]
+pub struct ReconfigsResponse {
+    pub pending: Option<Reconfig>,
+    /// approved by governance on this chain and not decided yet, proposals carrying it are echoed
+    pub approved: Option<Reconfig>,
+    /// decided reconfigurations by the slot they activate at
+    pub scheduled: Vec<(u64, Reconfig)>,
+}
+
 #[derive(Serialize, Deserialize, Clone, Debug, PartialEq, JsonSchema)]
 pub struct ChannelsResponse {
-    pub port_chan_pair: Vec<(u32,String)>
+    pub port_chan_pair: Vec<(u32,String)>,
+    /// chain ids whose channel was closed
+    pub disconnected: Vec<u32>
+}
+
+#[derive(Serialize, Deserialize, Clone, Debug, PartialEq, JsonSchema)]
+pub struct MemberStatus {
+    pub chain_id: u32,
+    /// none if the chain is not part of the configured membership
+    pub configured: Option<Member>,
+    /// local channel the chain_id is bound to
+    pub channel_id: Option<String>,
+    pub peer: Option<ChannelPeer>,
+    pub connected: bool,
 }
 
 #[derive(Serialize, Deserialize, Clone, Debug, PartialEq, JsonSchema)]
@@ -111,27 +269,27 @@ pub struct TestQueueResponse {
 }
 #[derive(Serialize, Deserialize, Clone, Debug, PartialEq, JsonSchema)]
 pub struct EchoQueryResponse { 
-    pub echo: Vec<(u64, HashSet<u32>)>
+    pub echo: Vec<(Binary, HashSet<u32>)>
 }
 #[derive(Serialize, Deserialize, Clone, Debug, PartialEq, JsonSchema)]
 pub struct Key1QueryResponse { 
-    pub key1: Vec<(u64, HashSet<u32>)>
+    pub key1: Vec<(Binary, HashSet<u32>)>
 }
 #[derive(Serialize, Deserialize, Clone, Debug, PartialEq, JsonSchema)]
 pub struct Key2QueryResponse { 
-    pub key2: Vec<(u64, HashSet<u32>)>
+    pub key2: Vec<(Binary, HashSet<u32>)>
 }
 #[derive(Serialize, Deserialize, Clone, Debug, PartialEq, JsonSchema)]
 pub struct Key3QueryResponse { 
-    pub key3: Vec<(u64, HashSet<u32>)>
+    pub key3: Vec<(Binary, HashSet<u32>)>
 }
 #[derive(Serialize, Deserialize, Clone, Debug, PartialEq, JsonSchema)]
 pub struct LockQueryResponse { 
-    pub lock: Vec<(u64, HashSet<u32>)>
+    pub lock: Vec<(Binary, HashSet<u32>)>
 }
 #[derive(Serialize, Deserialize, Clone, Debug, PartialEq, JsonSchema)]
 pub struct DoneQueryResponse { 
-    pub done: Vec<(u64, HashSet<u32>)>
+    pub done: Vec<(Binary, HashSet<u32>)>
 }
 
 #[derive(Serialize, Deserialize, Clone, Debug, PartialEq, JsonSchema)]
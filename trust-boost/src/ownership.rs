@@ -0,0 +1,97 @@
+use cosmwasm_std::{Addr, Api, Env, StdResult, Storage};
+
+use crate::error::ContractError;
+use crate::msg::OwnershipAction;
+use crate::state::{Expiry, Ownership, OWNERSHIP};
+
+// The owner configures the contract: the default target and the registered targets, and
+// with the byzantine feature the faults, and is the only one who may drive an instance by
+// hand through PreInput and the Key3, Lock and Done debug msgs. Everything else about the
+// replica set is changed by governance through sudo. Ownership moves in two steps as with
+// cw-ownable: the owner proposes a new owner, optionally until an expiry, and the proposal
+// takes effect when the new owner accepts it, so a typo cannot lock the owner out. Until
+// then the old owner stays in charge and may propose someone else. Renouncing leaves the
+// contract without an owner and closes the gated entry points for good.
+
+pub fn initialize_owner(store: &mut dyn Storage, owner: Addr) -> StdResult<()> {
+    OWNERSHIP.save(store, &Ownership { owner: Some(owner), pending_owner: None, pending_expiry: None })
+}
+
+pub fn ensure_owner(store: &dyn Storage, sender: &Addr) -> Result<(), ContractError> {
+    if OWNERSHIP.load(store)?.owner.as_ref() != Some(sender) {
+        return Err(ContractError::Unauthorized {});
+    }
+    Ok(())
+}
+
+pub fn update_ownership(
+    store: &mut dyn Storage,
+    api: &dyn Api,
+    env: &Env,
+    sender: &Addr,
+    action: OwnershipAction,
+) -> Result<Ownership, ContractError> {
+    let mut ownership = OWNERSHIP.load(store)?;
+    match action {
+        OwnershipAction::TransferOwnership { new_owner, expiry } => {
+            ensure_owner(store, sender)?;
+            if expiry.as_ref().is_some_and(|expiry| expiry.is_expired(env)) {
+                return Err(ContractError::TransferExpired {});
+            }
+            ownership.pending_owner = Some(api.addr_validate(&new_owner)?);
+            ownership.pending_expiry = expiry;
+        },
+        OwnershipAction::AcceptOwnership {} => {
+            let pending_owner = ownership.pending_owner.take().ok_or(ContractError::NoPendingTransfer {})?;
+            if pending_owner != *sender {
+                return Err(ContractError::Unauthorized {});
+            }
+            if ownership.pending_expiry.take().as_ref().is_some_and(|expiry: &Expiry| expiry.is_expired(env)) {
+                return Err(ContractError::TransferExpired {});
+            }
+            ownership.owner = Some(pending_owner);
+        },
+        OwnershipAction::RenounceOwnership {} => {
+            ensure_owner(store, sender)?;
+            ownership = Ownership { owner: None, pending_owner: None, pending_expiry: None };
+        },
+    }
+    OWNERSHIP.save(store, &ownership)?;
+    Ok(ownership)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use cosmwasm_std::testing::{mock_dependencies, mock_env};
+
+    #[test]
+    fn ownership_moves_in_two_steps() {
+        let mut deps = mock_dependencies();
+        let (store, api) = (&mut deps.storage, &deps.api);
+        let mut env = mock_env();
+        let (creator, alice, mallory) = (Addr::unchecked("creator"), Addr::unchecked("alice"), Addr::unchecked("mallory"));
+        initialize_owner(store, creator.clone()).unwrap();
+        let transfer = |new_owner: &str, expiry| OwnershipAction::TransferOwnership { new_owner: new_owner.to_string(), expiry };
+
+        assert!(matches!(update_ownership(store, api, &env, &mallory, transfer("mallory", None)), Err(ContractError::Unauthorized {})));
+        assert!(matches!(update_ownership(store, api, &env, &alice, OwnershipAction::AcceptOwnership {}), Err(ContractError::NoPendingTransfer {})));
+
+        // the proposal can be replaced, and expires
+        let at = Some(Expiry::AtHeight(env.block.height + 10));
+        update_ownership(store, api, &env, &creator, transfer("mallory", None)).unwrap();
+        update_ownership(store, api, &env, &creator, transfer("alice", at)).unwrap();
+        assert!(matches!(update_ownership(store, api, &env, &mallory, OwnershipAction::AcceptOwnership {}), Err(ContractError::Unauthorized {})));
+        env.block.height += 10;
+        assert!(matches!(update_ownership(store, api, &env, &alice, OwnershipAction::AcceptOwnership {}), Err(ContractError::TransferExpired {})));
+        assert!(ensure_owner(store, &creator).is_ok());
+
+        update_ownership(store, api, &env, &creator, transfer("alice", None)).unwrap();
+        let ownership = update_ownership(store, api, &env, &alice, OwnershipAction::AcceptOwnership {}).unwrap();
+        assert_eq!(ownership, Ownership { owner: Some(alice.clone()), pending_owner: None, pending_expiry: None });
+        assert!(matches!(ensure_owner(store, &creator), Err(ContractError::Unauthorized {})));
+
+        update_ownership(store, api, &env, &alice, OwnershipAction::RenounceOwnership {}).unwrap();
+        assert!(matches!(ensure_owner(store, &alice), Err(ContractError::Unauthorized {})));
+    }
+}
@@ -0,0 +1,135 @@
+use std::fmt;
+
+use cosmwasm_std::{Addr, Binary};
+use serde::de::{Deserializer, Error as _, MapAccess, Visitor};
+use serde::ser::{SerializeMap, Serializer};
+use serde::{Deserialize, Serialize};
+use serde_json::value::{to_raw_value, RawValue};
+
+use crate::error::ContractError;
+
+// A decided input is executed as an ExecuteMsg of the target contract, with the address
+// that signed it added to the fields of the variant under Config::sender_field:
+//
+//   {"register":{"name":"alice"}}  ->  {"register":{"name":"alice","tb_user":"wasm1..."}}
+//
+// The payload has to be what serde writes for a struct variant, an object with a single key
+// whose value is an object, and must not set the sender field itself. Only these two levels
+// are parsed, field values are copied verbatim, so no number is ever read as a float.
+
+pub const DEFAULT_SENDER_FIELD: &str = "tb_user";
+
+pub fn with_sender(binary: &str, field: &str, sender: &Addr) -> Result<Binary, ContractError> {
+    let invalid = |reason: String| ContractError::InvalidPayload { reason };
+    let json = Binary::from_base64(binary).map_err(|err| invalid(err.to_string()))?;
+    let Fields(mut variant) = serde_json::from_slice(&json).map_err(|err| invalid(err.to_string()))?;
+    if variant.len() != 1 {
+        return Err(invalid(format!("expected one execute variant, found {} keys", variant.len())));
+    }
+    let (name, body) = variant.remove(0);
+    let Fields(mut fields) = serde_json::from_str(body.get())
+        .map_err(|_| invalid(format!("variant {:?} is not an object of fields", name)))?;
+    if fields.iter().any(|(key, _)| key == field) {
+        return Err(invalid(format!("variant {:?} already has a {:?} field", name, field)));
+    }
+    fields.push((field.to_string(), to_raw(&sender.as_str())?));
+    let msg = Fields(vec![(name, to_raw(&Fields(fields))?)]);
+    Ok(Binary(serde_json::to_vec(&msg).map_err(|err| invalid(err.to_string()))?))
+}
+
+fn to_raw<T: Serialize>(value: &T) -> Result<Box<RawValue>, ContractError> {
+    to_raw_value(value).map_err(|err| ContractError::InvalidPayload { reason: err.to_string() })
+}
+
+// The entries of a JSON object in their original order. A duplicate key is an error, the
+// target contract might read the other one.
+struct Fields(Vec<(String, Box<RawValue>)>);
+
+impl<'de> Deserialize<'de> for Fields {
+    fn deserialize<D: Deserializer<'de>>(deserializer: D) -> Result<Self, D::Error> {
+        struct FieldsVisitor;
+
+        impl<'de> Visitor<'de> for FieldsVisitor {
+            type Value = Fields;
+
+            fn expecting(&self, f: &mut fmt::Formatter) -> fmt::Result {
+                f.write_str("a JSON object")
+            }
+
+            fn visit_map<A: MapAccess<'de>>(self, mut map: A) -> Result<Fields, A::Error> {
+                let mut entries: Vec<(String, Box<RawValue>)> = Vec::new();
+                while let Some((key, value)) = map.next_entry::<String, Box<RawValue>>()? {
+                    if entries.iter().any(|(seen, _)| *seen == key) {
+                        return Err(A::Error::custom(format!("duplicate key {:?}", key)));
+                    }
+                    entries.push((key, value));
+                }
+                Ok(Fields(entries))
+            }
+        }
+
+        deserializer.deserialize_map(FieldsVisitor)
+    }
+}
+
+impl Serialize for Fields {
+    fn serialize<S: Serializer>(&self, serializer: S) -> Result<S::Ok, S::Error> {
+        let mut map = serializer.serialize_map(Some(self.0.len()))?;
+        for (key, value) in &self.0 {
+            map.serialize_entry(key, value)?;
+        }
+        map.end()
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn rewrite(json: &str, field: &str) -> Result<String, ContractError> {
+        let binary = Binary::from(json.as_bytes()).to_base64();
+        let out = with_sender(&binary, field, &Addr::unchecked("wasm1user"))?;
+        Ok(String::from_utf8(out.0).unwrap())
+    }
+
+    #[test]
+    fn sender_is_added_to_the_variant() {
+        assert_eq!(
+            rewrite(r#"{"register":{"name":"alice"}}"#, "tb_user").unwrap(),
+            r#"{"register":{"name":"alice","tb_user":"wasm1user"}}"#
+        );
+        // whitespace, field order, nested values and big numbers survive, strings stay escaped
+        assert_eq!(
+            rewrite(" {\n \"transfer\" : { \"to\":\"b\\\"ob\", \"amount\": 340282366920938463463374607431768211455, \"meta\": {\"a\": [1, 2.5]} } }\n", "from").unwrap(),
+            r#"{"transfer":{"to":"b\"ob","amount":340282366920938463463374607431768211455,"meta":{"a": [1, 2.5]},"from":"wasm1user"}}"#
+        );
+        assert_eq!(rewrite(r#"{"increment":{}}"#, "tb_user").unwrap(), r#"{"increment":{"tb_user":"wasm1user"}}"#);
+    }
+
+    #[test]
+    fn payloads_that_do_not_fit_are_refused() {
+        let refused = [
+            // the sender is set by the user
+            r#"{"register":{"name":"alice","tb_user":"wasm1someone"}}"#,
+            // not a struct variant
+            r#""increment""#,
+            r#"{"register":"alice"}"#,
+            r#"{"register":["alice"]}"#,
+            // more or less than one variant
+            r#"{"register":{"name":"a"},"transfer":{"to":"b"}}"#,
+            r#"{}"#,
+            // duplicate keys, at either level
+            r#"{"register":{"name":"a"},"register":{"name":"b"}}"#,
+            r#"{"register":{"name":"a","name":"b"}}"#,
+            // not JSON, trailing data
+            r#"{"register":{"name":"alice"}"#,
+            r#"{"register":{"name":"alice"}} x"#,
+        ];
+        for json in refused {
+            assert!(matches!(rewrite(json, "tb_user"), Err(ContractError::InvalidPayload { .. })), "{}", json);
+        }
+        assert!(matches!(with_sender("not base64!", "tb_user", &Addr::unchecked("a")), Err(ContractError::InvalidPayload { .. })));
+        // another field name lets a tb_user field through as data
+        assert!(rewrite(r#"{"register":{"tb_user":"x"}}"#, "sender").is_ok());
+    }
+}
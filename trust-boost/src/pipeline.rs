@@ -0,0 +1,124 @@
+use cosmwasm_std::{Api, Env, IbcTimeout, Order, StdError, StdResult, Storage};
+
+use crate::ibc_msg::Msg;
+use crate::queue_handler::replay_pending_msgs;
+use crate::mempool::take_batch;
+use crate::membership::has_channel_quorum;
+use crate::reconfig::can_start;
+use crate::state::{CONFIG, LOG_LENGTH, PENDING_MSGS, STATE};
+use crate::utils::init_instance;
+use crate::view_change::append_queue_view_change;
+
+// Pipelined mode: slots are started automatically from the local mempool.
+// Slot k + 1 is started once slot k has sent its Key1 (or is decided), and at most
+// config.pipeline_depth slots past the end of the log run at the same time.
+// A replica without queued inputs joins a slot with an empty batch as soon as a peer
+// has started it, so one user submission is enough to drive the slot on every chain.
+
+pub fn fill_pipeline(
+    store: &mut dyn Storage,
+    timeout: IbcTimeout,
+    queue: &mut Vec<Vec<Msg>>,
+    env: &Env,
+    api: &dyn Api,
+) -> StdResult<()> {
+    let config = CONFIG.load(store)?;
+    if config.pipeline_depth == 0 || !has_channel_quorum(store)? {
+        return Ok(());
+    }
+    let log_length = LOG_LENGTH.load(store)?;
+    for slot in log_length..log_length + config.pipeline_depth {
+        if STATE.has(store, slot) {
+            continue;
+        }
+        // a chain removed by a reconfiguration stops at its activation slot
+        if !can_start(store, slot)? {
+            break;
+        }
+        // the previous slot has to be past its Echo phase
+        if slot > log_length {
+            match STATE.may_load(store, slot - 1)? {
+                Some(prev) if prev.done.is_some() || prev.sent.contains("Key1") => (),
+                _ => break,
+            }
+        }
+
+        let started_by_peer = PENDING_MSGS
+            .prefix(slot)
+            .keys(store, None, None, Order::Ascending)
+            .next()
+            .is_some();
+        let batch = take_batch(store, None)?;
+        if batch.is_noop() && !started_by_peer {
+            break;
+        }
+
+        init_instance(store, env, slot, batch)?;
+        append_queue_view_change(store, queue, slot, timeout.clone(), env, api)
+            .map_err(|msg| StdError::generic_err(msg.to_string()))?;
+        // Catch up with msgs other chains sent before this slot was started
+        replay_pending_msgs(store, slot, timeout.clone(), queue, env, api)?;
+    }
+    Ok(())
+}
+
+// The lowest slot past the log this chain has not started. Slots are started in order, one
+// started out of order would leave a gap the log never grows past.
+pub fn next_free_slot(store: &dyn Storage) -> StdResult<u64> {
+    let mut slot = LOG_LENGTH.load(store)?;
+    while STATE.has(store, slot) {
+        slot += 1;
+    }
+    Ok(slot)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use cosmwasm_std::testing::{mock_dependencies, mock_env, MockApi};
+    use cosmwasm_std::Addr;
+    use crate::state::{Batch, Config, State, TBInput, CHANNELS, DEBUG_CTR, PENDING_INPUTS};
+    use crate::utils::get_timeout;
+
+    fn input(binary: &str) -> TBInput {
+        TBInput { binary: binary.to_string(), ..TBInput::default() }
+    }
+
+    #[test]
+    fn starts_next_slot_once_previous_sent_key1() {
+        let mut deps = mock_dependencies();
+        let env = mock_env();
+        let api = MockApi::default();
+        let store = deps.as_mut().storage;
+        let mut config = Config::new(0, Addr::unchecked("target"));
+        config.n = 4;
+        config.pipeline_depth = 2;
+        CONFIG.save(store, &config).unwrap();
+        for chain_id in 1..4 {
+            CHANNELS.save(store, chain_id, &format!("channel-{}", chain_id)).unwrap();
+        }
+        LOG_LENGTH.save(store, &0).unwrap();
+        DEBUG_CTR.save(store, &0).unwrap();
+        PENDING_INPUTS.save(store, &vec![input("a"), input("b"), input("c")]).unwrap();
+
+        let mut queue = vec![Vec::new(); 4];
+        fill_pipeline(store, get_timeout(&env), &mut queue, &env, &api).unwrap();
+        // only slot 0 runs until it gets past Echo
+        assert!(STATE.has(store, 0));
+        assert!(!STATE.has(store, 1));
+
+        let mut state: State = STATE.load(store, 0).unwrap();
+        state.sent.insert("Key1".to_string());
+        STATE.save(store, 0, &state).unwrap();
+        fill_pipeline(store, get_timeout(&env), &mut queue, &env, &api).unwrap();
+        assert_eq!(STATE.load(store, 1).unwrap().key1_val, Batch::from(input("b")));
+
+        // slot 2 is outside the pipeline depth until slot 0 is in the log
+        let mut state: State = STATE.load(store, 1).unwrap();
+        state.sent.insert("Key1".to_string());
+        STATE.save(store, 1, &state).unwrap();
+        fill_pipeline(store, get_timeout(&env), &mut queue, &env, &api).unwrap();
+        assert!(!STATE.has(store, 2));
+        assert_eq!(PENDING_INPUTS.load(store).unwrap(), vec![input("c")]);
+    }
+}
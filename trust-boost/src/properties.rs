@@ -0,0 +1,248 @@
+// Property tests over randomized schedules, driven through the simulator in sim.rs.
+// A case is a number of replicas, up to f of them Byzantine, and a script of network
+// choices: which channel delivers next, how long packets are delayed, which are dropped
+// or duplicated, and when a chain times out an instance. Once the script runs out the
+// network is synchronous (FIFO, nothing lost) and chains keep aborting undecided
+// instances, so every correct replica has to decide.
+//
+// Checked properties:
+// - agreement: no two correct replicas decide different values for an instance
+// - validity: every decided input was submitted to some replica
+// - liveness: after the script, every correct replica decides every instance
+//
+// proptest shrinks a failing case to a minimal script and prints it.
+
+use std::collections::VecDeque;
+
+use proptest::prelude::*;
+
+use crate::contract::VIEW_TIMEOUT_SECONDS;
+use crate::msg::ExecuteMsg;
+use crate::sim::{signed, Delivery, Packet, Scheduler, Sim};
+use crate::state::{Fault, TBInput};
+
+const INSTANCES: u64 = 2;
+// rounds of timeouts after the script before liveness is given up on
+const LIVENESS_ROUNDS: usize = 6;
+// steps after which a network that does not go quiet counts as a livelock
+const MAX_STEPS: usize = 20_000;
+
+#[derive(Clone, Debug)]
+enum Choice {
+    // deliver the ready packet picked by the index, after delaying packets sent meanwhile
+    Deliver { pick: usize, delay: u64 },
+    Drop { pick: usize },
+    Duplicate { pick: usize },
+    // the chain gives up on the current view of its undecided instances
+    Timeout { chain: usize },
+}
+
+#[derive(Clone, Debug)]
+struct Case {
+    n: u32,
+    f: u32,
+    // (chain index, fault), at most f distinct chains
+    byzantine: Vec<(usize, Fault)>,
+    script: Vec<Choice>,
+}
+
+// Follows the script, then falls back to FIFO without delays or losses
+struct Script {
+    choices: VecDeque<Choice>,
+    delay: u64,
+    delivery: Delivery,
+}
+
+impl Scheduler for Script {
+    fn delay(&mut self, _src: u32, _dst: u32) -> u64 {
+        self.delay
+    }
+
+    fn pick(&mut self, ready: &[&Packet]) -> usize {
+        self.delivery = Delivery::Deliver;
+        match self.choices.pop_front() {
+            Some(Choice::Deliver { pick, delay }) => {
+                self.delay = delay;
+                pick % ready.len()
+            },
+            Some(Choice::Drop { pick }) => {
+                self.delivery = Delivery::Drop;
+                pick % ready.len()
+            },
+            Some(Choice::Duplicate { pick }) => {
+                self.delivery = Delivery::Duplicate;
+                pick % ready.len()
+            },
+            // timeouts are handled by run_case
+            Some(Choice::Timeout { .. }) | None => {
+                self.delay = 0;
+                0
+            },
+        }
+    }
+
+    fn delivery(&mut self, _packet: &Packet) -> Delivery {
+        self.delivery
+    }
+}
+
+fn fault() -> impl Strategy<Value = Fault> {
+    let msg_types = prop::sample::subsequence(vec!["Propose", "Suggest", "Proof", "Echo", "Key1", "Key2", "Key3", "Lock", "Done", "Fetch", "Value", "Report"], 1..4)
+        .prop_map(|types| types.into_iter().map(String::from).collect());
+    prop_oneof![
+        Just(Fault::EquivocatingPrimary),
+        msg_types.prop_map(|msg_types| Fault::Withhold { msg_types }),
+        (1..3u32).prop_map(|by| Fault::StaleView { by }),
+        (0..4u32, -1..3i32, 0..4u32).prop_map(|(key2, prev_key2, key3)| Fault::ForgeSuggest { key2, prev_key2, key3 }),
+        Just(Fault::ReplayDone { from: 0 }),
+    ]
+}
+
+fn choice(n: u32) -> impl Strategy<Value = Choice> {
+    prop_oneof![
+        6 => (0..16usize, 0..4u64).prop_map(|(pick, delay)| Choice::Deliver { pick, delay }),
+        1 => (0..16usize).prop_map(|pick| Choice::Drop { pick }),
+        1 => (0..16usize).prop_map(|pick| Choice::Duplicate { pick }),
+        1 => (0..n as usize).prop_map(|chain| Choice::Timeout { chain }),
+    ]
+}
+
+fn case() -> impl Strategy<Value = Case> {
+    sized_case(prop_oneof![Just((4u32, 1u32)), Just((7, 2))])
+}
+
+fn sized_case(sizes: impl Strategy<Value = (u32, u32)>) -> impl Strategy<Value = Case> {
+    sizes.prop_flat_map(|(n, f)| {
+        (
+            Just(n),
+            Just(f),
+            prop::collection::vec((0..n as usize, fault()), 0..=f as usize),
+            prop::collection::vec(choice(n), 0..200),
+        )
+            .prop_map(|(n, f, byzantine, script)| Case { n, f, byzantine, script })
+    })
+}
+
+fn input(instance_id: u64, chain_id: u32) -> TBInput {
+    signed(&format!("{{\"register\":{{\"name\":\"i{}_{}\"}}}}", instance_id, chain_id))
+}
+
+// Abort every undecided instance of the chain, once its view timed out
+fn time_out(sim: &mut Sim, chain_id: u32) {
+    for instance_id in 0..INSTANCES {
+        if sim.done(chain_id, instance_id).is_none() {
+            let _ = sim.try_execute(chain_id, ExecuteMsg::Abort { instance_id });
+        }
+    }
+}
+
+// Deliver until the network is quiet
+fn settle(sim: &mut Sim) -> Result<(), TestCaseError> {
+    for _ in 0..MAX_STEPS {
+        if !sim.step() {
+            return Ok(());
+        }
+    }
+    Err(TestCaseError::fail(format!("network still busy after {} steps", MAX_STEPS)))
+}
+
+fn run_case(case: &Case) -> Result<(), TestCaseError> {
+    let script: VecDeque<Choice> = case.script.iter().cloned().collect();
+    let timeouts: Vec<(usize, u32)> = case.script
+        .iter()
+        .enumerate()
+        .filter_map(|(step, choice)| match choice {
+            Choice::Timeout { chain } => Some((step, *chain as u32)),
+            _ => None,
+        })
+        .collect();
+    let mut sim = Sim::new(case.n, case.f);
+    sim.scheduler = Box::new(Script { choices: script, delay: 0, delivery: Delivery::Deliver });
+
+    let mut faulty: Vec<u32> = Vec::new();
+    for (chain, fault) in &case.byzantine {
+        let chain_id = *chain as u32;
+        if !faulty.contains(&chain_id) {
+            faulty.push(chain_id);
+        }
+        let faults = vec![fault.clone()];
+        sim.try_execute_as(chain_id, "creator", ExecuteMsg::SetFaults { faults }).unwrap();
+    }
+    let correct: Vec<u32> = (0..case.n).filter(|chain_id| !faulty.contains(chain_id)).collect();
+
+    // a Byzantine chain can keep retrying inputs nobody else proposes, so only correct chains get any
+    let mut submitted = Vec::new();
+    for instance_id in 0..INSTANCES {
+        for chain_id in correct.iter().copied() {
+            let value = input(instance_id, chain_id);
+            submitted.push(value.clone());
+            sim.execute(chain_id, ExecuteMsg::Input { instance_id, value });
+        }
+    }
+
+    // asynchronous period, the script drives the network
+    for step in 0..case.script.len() {
+        for (_, chain_id) in timeouts.iter().filter(|(at, _)| *at == step) {
+            sim.tick(VIEW_TIMEOUT_SECONDS + 1);
+            time_out(&mut sim, *chain_id);
+        }
+        if !sim.step() {
+            break;
+        }
+    }
+
+    // synchronous from here on, correct chains time out instances that are stuck
+    settle(&mut sim)?;
+    for _ in 0..LIVENESS_ROUNDS {
+        let decided = correct
+            .iter()
+            .all(|chain_id| (0..INSTANCES).all(|instance_id| sim.done(*chain_id, instance_id).is_some()));
+        if decided {
+            break;
+        }
+        sim.tick(VIEW_TIMEOUT_SECONDS + 1);
+        for chain_id in &correct {
+            time_out(&mut sim, *chain_id);
+        }
+        settle(&mut sim)?;
+    }
+
+    for instance_id in 0..INSTANCES {
+        let decided: Vec<_> = correct
+            .iter()
+            .map(|chain_id| (*chain_id, sim.done(*chain_id, instance_id)))
+            .collect();
+        for (chain_id, val) in &decided {
+            let val = match val {
+                Some(val) => val,
+                None => return Err(TestCaseError::fail(format!("chain {} did not decide instance {}", chain_id, instance_id))),
+            };
+            prop_assert_eq!(Some(val), decided[0].1.as_ref(), "agreement on instance {}", instance_id);
+            for input in &val.inputs {
+                prop_assert!(submitted.contains(input), "chain {} decided an input nobody submitted", chain_id);
+            }
+        }
+    }
+    Ok(())
+}
+
+proptest! {
+    #![proptest_config(ProptestConfig { cases: 24, ..ProptestConfig::default() })]
+
+    #[test]
+    fn safety_and_liveness_over_random_schedules(case in case()) {
+        run_case(&case)?;
+    }
+}
+
+proptest! {
+    #![proptest_config(ProptestConfig { cases: 4, ..ProptestConfig::default() })]
+
+    // thresholds that hard-code f = 1 only show up with more faults
+    #[test]
+    fn safety_and_liveness_with_f_at_least_two(case in sized_case(prop_oneof![Just((7u32, 2u32)), Just((10, 3))])) {
+        run_case(&case)?;
+    }
+}
+
+
@@ -1,6 +1,6 @@
 
 use cosmwasm_std::{
-    StdResult, IbcReceiveResponse, to_binary, IbcMsg, StdError, Storage, IbcTimeout, Env, wasm_execute, WasmMsg, Binary, SubMsg, Api
+    StdResult, IbcReceiveResponse, to_binary, IbcMsg, StdError, Storage, IbcTimeout, Env, wasm_execute, Binary, Api, Order
 };
 use serde_json::to_string;
 
@@ -8,19 +8,19 @@ use std::collections::HashSet;
 use std::convert::TryInto;
 use std::hash::Hash;
 
-use crate::ContractError;
-use crate::state::{RECEIVED_DONE, InputType, TBInput};
-use crate::utils::{get_id_channel_pair_from_storage, get_chain_id, check_signature, append_binary_string, derive_addr_from_pubkey};
-use crate::ibc_msg::{Msg,AcknowledgementMsg, MsgQueueResponse, PacketMsg};
+use crate::state::{RECEIVED_DONE, InputType};
+use crate::utils::{get_instance_peers, find_chain_id, ABORT_CHANNEL};
+use crate::ibc_msg::{Msg,AcknowledgementMsg, MsgQueueResponse};
 use crate::{state::{
-    HIGHEST_REQ, STATE, SEND_ALL_UPON, CHANNELS, TEST_QUEUE, TEST, RECEIVED, RECEIVED_ECHO, RECEIVED_KEY1, RECEIVED_KEY2, RECEIVED_KEY3,
-    DEBUG, RECEIVED_LOCK, DEBUG_RECEIVE_MSG
-}, abort::handle_abort};
+    HIGHEST_REQ, STATE, SEND_ALL_UPON, TEST_QUEUE, TEST, RECEIVED, RECEIVED_ECHO, RECEIVED_KEY1, RECEIVED_KEY2, RECEIVED_KEY3,
+    DEBUG, RECEIVED_LOCK, DEBUG_RECEIVE_MSG, CONFIG, DEBUG_CTR, PENDING_MSGS
+}, abort::handle_abort, log::{append_decided, execute_decided}, reports::{queue_reports, record_report}, pipeline::fill_pipeline, mempool::retire_batch, reconfig::may_echo, replay::check_current, retransmit::send_queue, values::{lookup, park, remember, unpark}};
 
 // Handle Propose
 fn handle_propose(
     store: &mut dyn Storage,
     queue: &mut Vec<Vec<Msg>>,
+    instance_id: u64,
     timeout: IbcTimeout,
     _local_channel_id: Option<String>,
     chain_id: u32,
@@ -30,7 +30,7 @@ fn handle_propose(
     env: &Env,
     api: &dyn Api,
 ) -> StdResult<()> {
-    let mut state = STATE.load(store)?;
+    let mut state = STATE.load(store, instance_id)?;
 
     // ignore messages from other views, other than abort, done and request messages
     if view != state.view {
@@ -40,21 +40,29 @@ fn handle_propose(
             // RECEIVED_PROPOSE.save(store, chain_id, &true)?;
             let mut broadcast = false;
             state.received_propose = true;
-            STATE.save(store, &state)?;
+            STATE.save(store, instance_id, &state)?;
             
-            // First case we should broadcast Echo message
-            if state.lock == 0 || v == state.lock_val {
+            if !v.is_valid(api) {
+                // a value with a malformed or forged input is never echoed, whoever proposed it
+            } else if v.inputs.len() > CONFIG.load(store)?.max_batch_size as usize {
+                // neither is a batch over the local cap, a primary cannot force bigger slots on the others
+            } else if !may_echo(store, &v)? {
+                // nor a replica set this chain's governance did not approve
+            } else if !v.inputs.iter().all(|input| check_current(store, env, input).is_ok()) {
+                // nor inputs signed for another deployment or expired
+            } else if state.lock == 0 || v == state.lock_val {
+                // First case we should broadcast Echo message
                 broadcast = true;
             } else if view > k && k >= state.lock {
                 // upon open_lock(proofs) == true
                 // Second case we should broadcast Echo message
-                if open_lock(store, state.proofs)? {
+                if open_lock(store, instance_id, state.proofs)? {
                     broadcast = true;
                 }
             }
             // send_all_upon_join_queue(<echo, k, v, view>)
             if broadcast {
-                let echo_packet = Msg::Echo { val: v, view };
+                let echo_packet = Msg::Echo { instance_id, digest: v.digest(), view };
                 send_all_upon_join_queue(store, queue, echo_packet, timeout, env, api)?;
             }
             // send_all_upon_join_queue(<echo, k, v, view>)/
@@ -68,21 +76,23 @@ fn handle_propose(
 fn handle_request(
     store: &mut dyn Storage,
     queue: &mut Vec<Vec<Msg>>,
+    instance_id: u64,
     view: u32,
     chain_id: u32,
     api: &dyn Api,
 ) -> StdResult<()> {
-    let mut state = STATE.load(store)?;
+    let mut state = STATE.load(store, instance_id)?;
 
     // state.key2_proofs.push((state.current_tx_id,"received_request".to_string(), chain_id as i32));
     // STATE.save(store, &state)?;
     // Update stored highest_request for that blockchain accordingly
-    let highest_request = HIGHEST_REQ.load(store, chain_id)?;
+    let highest_request = HIGHEST_REQ.load(store, (instance_id, chain_id))?;
     if highest_request < view {
-        HIGHEST_REQ.save(store, chain_id, &view)?;
+        HIGHEST_REQ.save(store, (instance_id, chain_id), &view)?;
             
         if view == state.view {
             let packet = Msg::Suggest {
+                instance_id,
                 chain_id: state.chain_id,
                 view: state.view,
                 key2: state.key2,
@@ -95,17 +105,17 @@ fn handle_request(
             if chain_id == state.primary && !state.sent.contains(packet.name()) {
                 
                 state.sent.insert(packet.name().to_string());
-                STATE.save(store, &state)?;
+                STATE.save(store, instance_id, &state)?;
                 queue[chain_id as usize].push(packet);
             }
 
             // Check if any pending send_all_upon_join
-            let packets = SEND_ALL_UPON.may_load(store, chain_id)?;
+            let packets = SEND_ALL_UPON.may_load(store, (instance_id, chain_id))?;
             match packets {
                 Some(p) => {
                     // Add to queue and remove from the buffer
                     queue[chain_id as usize].extend(p);
-                    SEND_ALL_UPON.remove(store, chain_id);
+                    SEND_ALL_UPON.remove(store, (instance_id, chain_id));
 
                 },
                 None => (),
@@ -119,6 +129,7 @@ fn handle_request(
 fn handle_suggest(
     store: &mut dyn Storage,
     queue: &mut Vec<Vec<Msg>>,
+    instance_id: u64,
     timeout: IbcTimeout,
     chain_id: u32,
     view: u32,
@@ -130,41 +141,44 @@ fn handle_suggest(
     env: &Env,
     api: &dyn Api,
 ) -> StdResult<()> {
-    let mut state = STATE.load(store)?;
+    let mut state = STATE.load(store, instance_id)?;
 
-    // When I'm the primary
-    if state.primary == state.chain_id {
+    // When I'm the primary, suggestions of other views are ignored
+    if state.primary == state.chain_id && view == state.view {
 
 
-        let mut receive_set= RECEIVED.load(store, "Suggest".to_string())?;
+        let mut receive_set= RECEIVED.load(store, (instance_id, "Suggest".to_string()))?;
         // upon receiving the first suggest message from a chain
         if !receive_set.contains(&chain_id) {
             // Update the state
             receive_set.insert(chain_id);
-            RECEIVED.save(store, "Suggest".to_string(), &receive_set)?;
+            RECEIVED.save(store, (instance_id, "Suggest".to_string()), &receive_set)?;
             // Check if the following conditions hold
             if prev_key2 < key2 as i32 && key2 < view {
                 state.key2_proofs.push((key2, key2_val, prev_key2));
-                STATE.save(store, &state)?;
+                STATE.save(store, instance_id, &state)?;
             }
             if key3 == 0 {
                 state.suggestions.push((key3, key3_val));
-                STATE.save(store, &state)?;
+                STATE.save(store, instance_id, &state)?;
             } else if key3 < view {
                 // Upon accept_key = true
-                if accept_key(key3, key3_val.clone(), state.key2_proofs.clone()) {
+                if accept_key(key3, key3_val.clone(), state.key2_proofs.clone(), state.F) {
                     state.suggestions.push((key3, key3_val.clone()));
-                    STATE.save(store, &state)?;
+                    STATE.save(store, instance_id, &state)?;
                 }
             }
 
             // Check if |suggestions| >= n - f
             if !state.sent.contains("Propose") && state.suggestions.len() >= (state.n - state.F) as usize {
                 state.sent.insert("Propose".to_string());
-                STATE.save(store, &state)?;
-                // Retrive the entry with the largest k
-                let (k, v) = state.suggestions.iter().max_by(|x, y| y.0.cmp(&x.0)).unwrap();
+                STATE.save(store, instance_id, &state)?;
+                // Retrive the entry with the largest k, a real input wins over a no-op on the same k
+                let (k, v) = state.suggestions.iter()
+                    .max_by(|x, y| y.0.cmp(&x.0).then_with(|| y.1.is_noop().cmp(&x.1.is_noop())))
+                    .unwrap();
                 let propose_packet = Msg::Propose {
+                    instance_id,
                     chain_id: state.chain_id,
                     k: k.clone(),
                     v: v.clone(),
@@ -212,6 +226,7 @@ fn handle_suggest(
 // Handle Proof
 fn handle_proof(
     store: &mut dyn Storage,
+    instance_id: u64,
     local_channel_id: Option<String>,
     key1: u32,
     key1_val: InputType,
@@ -220,27 +235,27 @@ fn handle_proof(
     _env: &Env,
     api: &dyn Api,
 ) -> StdResult<()> {
-    let state = STATE.load(store)?;
+    let state = STATE.load(store, instance_id)?;
     // detect if self-send
-    let chain_id = match local_channel_id.clone() {
-        Some(id) => {
-            // Get the chain_id of the sender
-            get_chain_id(store, id)
+    let chain_id = match local_channel_id {
+        Some(id) => match find_chain_id(store, &id) {
+            Some(chain_id) => chain_id,
+            None => return Ok(()),
         },
         None => state.chain_id,
     };
 
     // let received_proof = RECEIVED_PROOF.load(store, chain_id)?;
-    let mut receive_set= RECEIVED.load(store, "Proof".to_string())?;
+    let mut receive_set= RECEIVED.load(store, (instance_id, "Proof".to_string()))?;
     if !receive_set.contains(&chain_id) {
         // Update the state
         receive_set.insert(chain_id);
-        RECEIVED.save(store, "Proof".to_string(), &receive_set)?;
+        RECEIVED.save(store, (instance_id, "Proof".to_string()), &receive_set)?;
         
         if view > key1 && key1 as i32 > prev_key1 {
-            let mut state = STATE.load(store)?;
+            let mut state = STATE.load(store, instance_id)?;
             state.proofs.push((key1, key1_val, prev_key1));
-            STATE.save(store, &state)?;
+            STATE.save(store, instance_id, &state)?;
         } 
         // if condition is met, update the proofs accordingly
         
@@ -253,6 +268,7 @@ fn handle_proof(
 fn handle_echo(
     store: &mut dyn Storage,
     queue: &mut Vec<Vec<Msg>>,
+    instance_id: u64,
     timeout: IbcTimeout,
     local_channel_id: Option<String>,
     val: InputType,
@@ -260,18 +276,18 @@ fn handle_echo(
     env: &Env,
     api: &dyn Api,
 ) -> StdResult<()> {
-    let key1_packet = Msg::Key1 { val: val.clone(), view };
+    let key1_packet = Msg::Key1 { instance_id, digest: val.digest(), view };
 
     // ignore messages from other views, other than abort, done and request messages
     // if this condition holds, we have received Echo from n - f parties on same val
-    if message_transfer_hop(store, val.clone(), view, queue, RECEIVED_ECHO, key1_packet.clone(), timeout.clone(), local_channel_id.clone(), env, api)? {
-        let mut state = STATE.load(store)?;
+    if message_transfer_hop(store, instance_id, val.clone(), view, queue, RECEIVED_ECHO, key1_packet.clone(), timeout.clone(), local_channel_id.clone(), env, api)? {
+        let mut state = STATE.load(store, instance_id)?;
         if state.key1_val != val {
             state.prev_key1 = state.key1 as i32;
             state.key1_val = val;                    
         }
         state.key1 = view;
-        STATE.save(store, &state)?; 
+        STATE.save(store, instance_id, &state)?; 
     }
     
     Ok(())
@@ -281,6 +297,7 @@ fn handle_echo(
 fn handle_key1(
     store: &mut dyn Storage,
     queue: &mut Vec<Vec<Msg>>,
+    instance_id: u64,
     timeout: IbcTimeout,
     local_channel_id: Option<String>,
     val: InputType,
@@ -291,15 +308,15 @@ fn handle_key1(
 
  
     // ignore messages from other views, other than abort, done and request messages
-    let key2_packet = Msg::Key2 { val: val.clone(), view };
-    if message_transfer_hop(store, val.clone(), view, queue, RECEIVED_KEY1, key2_packet.clone(), timeout.clone(), local_channel_id.clone(), env, api)? {
-        let mut state = STATE.load(store)?;
+    let key2_packet = Msg::Key2 { instance_id, digest: val.digest(), view };
+    if message_transfer_hop(store, instance_id, val.clone(), view, queue, RECEIVED_KEY1, key2_packet.clone(), timeout.clone(), local_channel_id.clone(), env, api)? {
+        let mut state = STATE.load(store, instance_id)?;
         if state.key2_val != val {
             state.prev_key2 = state.key2 as i32;
             state.key2_val = val;                    
         }
         state.key2 = view;
-        STATE.save(store, &state)?; 
+        STATE.save(store, instance_id, &state)?; 
     }
     
     Ok(())
@@ -309,6 +326,7 @@ fn handle_key1(
 fn handle_key2(
     store: &mut dyn Storage,
     queue: &mut Vec<Vec<Msg>>,
+    instance_id: u64,
     timeout: IbcTimeout,
     local_channel_id: Option<String>,
     val: InputType,
@@ -316,12 +334,12 @@ fn handle_key2(
     env: &Env,
     api: &dyn Api,
 ) -> StdResult<()> {
-    let key3_packet = Msg::Key3 { val: val.clone(), view };
-    if message_transfer_hop(store, val.clone(), view, queue, RECEIVED_KEY2, key3_packet.clone(),timeout.clone(), local_channel_id.clone(), env, api)? {
-        let mut state = STATE.load(store)?;
+    let key3_packet = Msg::Key3 { instance_id, digest: val.digest(), view };
+    if message_transfer_hop(store, instance_id, val.clone(), view, queue, RECEIVED_KEY2, key3_packet.clone(),timeout.clone(), local_channel_id.clone(), env, api)? {
+        let mut state = STATE.load(store, instance_id)?;
         state.key3 = view;
         state.key3_val = val.clone();
-        STATE.save(store, &state)?;    
+        STATE.save(store, instance_id, &state)?;    
     }
     
     Ok(())
@@ -331,6 +349,7 @@ fn handle_key2(
 fn handle_key3(
     store: &mut dyn Storage,
     queue: &mut Vec<Vec<Msg>>,
+    instance_id: u64,
     timeout: IbcTimeout,
     local_channel_id: Option<String>,
     val: InputType,
@@ -338,14 +357,14 @@ fn handle_key3(
     env: &Env,
     api: &dyn Api,
 ) -> StdResult<()> {
-    let lock_packet = Msg::Lock { val: val.clone(), view }; 
+    let lock_packet = Msg::Lock { instance_id, digest: val.digest(), view };
 
     DEBUG.save(store, 33330, &queue.len().to_string())?;
-    if message_transfer_hop(store, val.clone(), view, queue, RECEIVED_KEY3, lock_packet.clone(), timeout.clone(), local_channel_id.clone(),env, api)? {
-        let mut state = STATE.load(store)?;
+    if message_transfer_hop(store, instance_id, val.clone(), view, queue, RECEIVED_KEY3, lock_packet.clone(), timeout.clone(), local_channel_id.clone(),env, api)? {
+        let mut state = STATE.load(store, instance_id)?;
         state.lock = view;
         state.lock_val = val;
-        STATE.save(store, &state)?;    
+        STATE.save(store, instance_id, &state)?;    
         DEBUG.save(store, 33333, &"HANDLE_KEY_3_TRUE".to_string())?;
     } else {
         DEBUG.save(store, 3333, &"HANDLE_KEY_3_FALSE".to_string())?;
@@ -357,86 +376,51 @@ fn handle_key3(
 fn handle_lock(
     store: &mut dyn Storage,
     queue: &mut Vec<Vec<Msg>>,
+    instance_id: u64,
     timeout: IbcTimeout,
     local_channel_id: Option<String>,
     val: InputType,
     view: u32,
     env: &Env,
     api: &dyn Api,
-) -> Result<Vec<SubMsg>, ContractError> {        
-    let done_packet = Msg::Done { val: val.clone() };
+) -> StdResult<()> {
+    let done_packet = Msg::Done { instance_id, digest: val.digest() };
     // ignore messages from other views, other than abort, done and request messages
-    // upon receiving from n - f parties with the same val
-    let result = message_transfer_hop(store, val.clone(), view, queue, RECEIVED_LOCK, 
-                                            done_packet.clone(), timeout.clone(), local_channel_id.clone(), env, api)?;
-
-    // handle self-execute done
-    // Since 
-    if result {
-        let mut vec_msgs:Vec<SubMsg> = Vec::new();
-        let mut state = STATE.load(store).unwrap();
-        if state.done.is_some() && !state.done_executed && check_signature(api, val.clone()){
-            let address = derive_addr_from_pubkey(&val.public_key).unwrap();
-            let appended_binary = append_binary_string(val.binary, &"tb_user".to_string(), &address.to_string());
-            let stringified_binary = appended_binary.to_string();
-            let wasm_msg = WasmMsg::Execute{
-                contract_addr: state.contract_addr.to_string(),
-                msg: appended_binary,
-                funds: vec![]
-            };
-            let sub_msg: SubMsg = SubMsg::reply_always(wasm_msg, 1234);    
-            state.done_executed = true;
-            STATE.save(store, &state)?;
-            vec_msgs.push(sub_msg);
-            return Ok(vec_msgs);    
-        }
-    }
-
-    // send_all_party(store, queue, done_packet, timeout.clone())?;
-    return Ok(Vec::new());
+    // upon receiving from n - f parties with the same val, <done, val> is sent to every party,
+    // the self-send decides the instance and the decided value is executed once its slot is reached
+    message_transfer_hop(store, instance_id, val, view, queue, RECEIVED_LOCK,
+                                            done_packet, timeout, local_channel_id, env, api)?;
+    Ok(())
 }
 
 // Handle Done
 fn handle_done(
     store: &mut dyn Storage,
     queue: &mut Vec<Vec<Msg>>,
+    instance_id: u64,
     timeout: IbcTimeout,
     local_channel_id: Option<String>,
     val: InputType,
     env: &Env,
     api: &dyn Api,
-) -> Result<Vec<SubMsg>, ContractError> {   
-    let mut state = STATE.load(store).unwrap();
+) -> StdResult<()> {
+    let view = STATE.load(store, instance_id)?.view;
 
     // upon receiving from n - f parties with the same val
-    if message_transfer_hop(store, val.clone(), state.view, queue, RECEIVED_DONE, Msg::Done { val: val.clone() }, timeout.clone(), local_channel_id.clone(), env, api).unwrap() {
+    if message_transfer_hop(store, instance_id, val.clone(), view, queue, RECEIVED_DONE, Msg::Done { instance_id, digest: val.digest() }, timeout, local_channel_id, env, api)? {
         // decide and terminate
-        state.done = Some(val.clone());
-        let mut vec_msgs:Vec<SubMsg> = Vec::new();
-
-        // Only handle if it is not self send..., self send case is handled in handle_lock...
-        if !local_channel_id.is_none() && !state.done_executed && check_signature(api, val.clone()){
-            DEBUG.save(store, 7777777, &"EXECUTED ME HELLO!!!!!".to_string())?;
-            state.done_executed = true;
-            let address = derive_addr_from_pubkey(&val.public_key).unwrap();
-            let appended_binary = append_binary_string(val.binary, &"tb_user".to_string(), &address.to_string());
-            let stringified_binary = appended_binary.to_string();
+        let mut state = STATE.load(store, instance_id)?;
+        state.done = Some(val);
+        if state.done_block_height.is_none() {
             state.done_timestamp = Some(env.block.time);
             state.done_block_height = Some(env.block.height);
-
-            let wasm_msg = WasmMsg::Execute{
-                contract_addr: state.contract_addr.to_string(),
-                msg: appended_binary,
-                funds: vec![]
-            };
-            DEBUG.save(store, 777777777, &stringified_binary)?;
-            let sub_msg = SubMsg::reply_always(wasm_msg, 1234);    
-            vec_msgs.push(sub_msg)
         }
-        STATE.save(store, &state)?;
-        return Ok(vec_msgs);
+        STATE.save(store, instance_id, &state)?;
+        retire_batch(store, instance_id)?;
+        // record the decision in the replicated log
+        append_decided(store)?;
     }
-    return Ok(Vec::new());
+    Ok(())
 }
 
 pub fn receive_queue(
@@ -449,13 +433,168 @@ pub fn receive_queue(
     api: &dyn Api,
 ) -> StdResult<IbcReceiveResponse> {
     // let mut queue: Vec<Vec<Msg>> = vec!(Vec::new(); state.n.try_into().unwrap());
-    if let Some(_) = STATE.load(store)?.done {
-        return Ok(IbcReceiveResponse::new())
+    process_queue(store, timeout.clone(), local_channel_id.clone(), queue_to_process, queue, env, api)?;
+
+    let mut res = IbcReceiveResponse::new();
+
+    match local_channel_id {
+        Some(_) => {
+            // Execute the decided values whose slot has been reached.
+            // Self-sends are nested inside this call, so only the outermost call executes
+            let wasm_exec_messages = execute_decided(store, env, api)?;
+            if !wasm_exec_messages.is_empty() {
+                DEBUG.save(store, 88888888, &"EXECUTED ME HELLO OUTSIDE!!!!!".to_string())?;
+                res = res.add_submessages(wasm_exec_messages);
+            }
+            // slots whose inputs were all skipped are reported right away, the others from the reply
+            queue_reports(store, queue)?;
+
+            // After handling all msgs in queue sucessfully
+            // Generate msg queue to send
+            let mut msgs = Vec::new();
+            // let timeout = get_timeout(env);
+            DEBUG.save(store, 300, &"LOCAL_CHANNEL_ID".to_string())?;
+
+            //// TESTING /////
+            let config = CONFIG.load(store)?;
+            let tx_id = DEBUG_CTR.load(store)?;
+            let mut i = 0;
+            #[cfg(any(test, feature = "byzantine"))]
+            let queue = &crate::byzantine::corrupt(store, queue.clone())?;
+            for (chain_id, msg_queue) in queue.iter().enumerate() {
+                //// TESTING /////
+                let chain_msg_pair = (chain_id as u32, msg_queue.to_vec());
+                let action = |packets: Option<Vec<_>>| -> StdResult<Vec<_>> {
+                    match packets {
+                        Some(mut p) => {
+                            p.push(chain_msg_pair.clone());
+                            Ok(p)
+                        },
+                        None => Ok(vec!(chain_msg_pair.clone())),
+                    }
+                };
+                TEST_QUEUE.update(store, tx_id, action)?;
+                //// TESTING /////
+
+                if chain_id != config.chain_id as usize {
+                    // When chain wish to send some msgs to dest chain, msgs to disconnected chains wait in the outbox
+                    if msg_queue.len() > 0 {
+                        let msg = match send_queue(store, chain_id.try_into().unwrap(), msg_queue.to_vec(), timeout.clone())? {
+                            Some(msg) => msg,
+                            None => continue,
+                        };
+                        i = i+1;
+                        let first_msg_name = msg_queue[0].name();
+                        let debug_str = format!("{} {} FIRST MESSAGE LEN {} TO CHAIN_ID: {}" , 
+                                                        "SEND_PACKET QUEUE SIZE", msg_queue.len(), first_msg_name, chain_id);   
+                        DEBUG.save(store, 400+i, &debug_str)?;
+                        msgs.push(msg);
+                    }
+                }
+            }
+            //// TESTING ////
+            let tx_id = tx_id + 1;
+            DEBUG_CTR.save(store, &tx_id)?;
+            //// TESTING ////
+
+            let acknowledgement = to_binary(&AcknowledgementMsg::Ok(MsgQueueResponse { }))?;            
+            // Add to Response if there are pending messages
+            if msgs.len() > 0 {
+                TEST.save(store, tx_id, &msgs)?;
+                res = res.add_messages(msgs);
+            }
+                    
+            Ok(res
+                .set_ack(acknowledgement)
+                .add_attribute("action", "receive_msg_queue"))
+        },
+        None => { 
+            Ok(res.set_ack(b"{}")
+                .add_attribute("action", "ibc_packet_ack"))
+        }
     }
-    let mut wasm_exec_messages: Vec<SubMsg> = Vec::new();
+}
 
+// Run every msg through its handler, appending outgoing msgs to queue
+pub fn process_queue(
+    store: &mut dyn Storage,
+    timeout: IbcTimeout,
+    local_channel_id: Option<String>,
+    queue_to_process: Vec<Msg>,
+    queue: &mut Vec<Vec<Msg>>,
+    env: &Env,
+    api: &dyn Api,
+) -> StdResult<()> {
     for msg in queue_to_process {
-        let msg_string = msg.name();
+        let instance_id = msg.instance_id();
+        let sender = local_channel_id.as_ref().and_then(|channel_id| find_chain_id(store, channel_id));
+        // msgs of a channel without an accepted WhoAmI are dropped, parked ones included
+        if local_channel_id.as_deref().is_some_and(|id| id != ABORT_CHANNEL) && sender.is_none() {
+            continue;
+        }
+        // a peer only speaks for itself, e.g. no replica can propose in the primary's name
+        if sender.is_some_and(|chain_id| msg.chain_id().is_some_and(|named| named != chain_id)) {
+            continue;
+        }
+        // Bodies are served whatever the state of the instance, a lagging peer may need a decided one
+        if let Msg::Fetch { digest, .. } = &msg {
+            if let (Some(chain_id), Some(val)) = (sender, lookup(store, instance_id, digest)?) {
+                queue[chain_id as usize].push(Msg::Value { instance_id, val });
+            }
+            continue;
+        }
+        // Reports are kept whatever the state of the instance, a peer may execute before this chain decides
+        if let Msg::Report { statuses, .. } = &msg {
+            if let Some(chain_id) = sender {
+                record_report(store, instance_id, chain_id, statuses)?;
+            }
+            continue;
+        }
+        match STATE.may_load(store, instance_id)? {
+            // Instance is not started locally yet, hold on to the msg until it is
+            None => {
+                if let Some(channel_id) = local_channel_id.clone() {
+                    let action = |packets: Option<Vec<Msg>>| -> StdResult<Vec<Msg>> {
+                        let mut p = packets.unwrap_or_default();
+                        p.push(msg.clone());
+                        Ok(p)
+                    };
+                    PENDING_MSGS.update(store, (instance_id, channel_id), action)?;
+                }
+                continue;
+            },
+            // Instance already decided
+            Some(state) if state.done.is_some() => continue,
+            // Msgs from chains outside of the instance's replica set do not count
+            Some(state) => {
+                if sender.is_some_and(|chain_id| chain_id >= state.n) {
+                    continue;
+                }
+            },
+        }
+        match &msg {
+            Msg::Propose { v, .. } => remember(store, instance_id, v)?,
+            Msg::Suggest { key2_val, key3_val, .. } => {
+                remember(store, instance_id, key2_val)?;
+                remember(store, instance_id, key3_val)?;
+            },
+            Msg::Proof { key1_val, .. } => remember(store, instance_id, key1_val)?,
+            _ => (),
+        }
+        // the body a vote is for, unused for other msgs
+        let vote_val = match msg.vote_digest() {
+            Some(digest) => match lookup(store, instance_id, digest)? {
+                Some(val) => val,
+                None => {
+                    park(store, instance_id, digest, local_channel_id.clone(), msg.clone())?;
+                    if let Some(chain_id) = sender {
+                        queue[chain_id as usize].push(Msg::Fetch { instance_id, digest: digest.clone() });
+                    }
+                    continue;
+                },
+            },
+            None => InputType::default(),
+        };
         // TODO skip...
         // let key = msg.name().to_string();
         // if(RECEIVED.load(store,key)?.contains(local_channel_id.unwrap()?)) {
@@ -463,20 +602,23 @@ pub fn receive_queue(
         // }
         let result: StdResult<()> = match msg {
             Msg::Propose {
+                instance_id,
                 chain_id,
                 k,
                 v,
                 view,
             } => { 
-                handle_propose(store, queue, timeout.clone(), local_channel_id.clone(), chain_id, k, v, view, env, api) 
+                handle_propose(store, queue, instance_id, timeout.clone(), local_channel_id.clone(), chain_id, k, v, view, env, api) 
             },
             Msg::Request { 
+                instance_id,
                 view, 
                 chain_id 
             } => {
-                handle_request(store, queue, view, chain_id,api)
+                handle_request(store, queue, instance_id, view, chain_id,api)
             },
             Msg::Suggest {
+                instance_id,
                 chain_id,
                 view,
                 key2,
@@ -485,26 +627,27 @@ pub fn receive_queue(
                 key3,
                 key3_val,
             } => { 
-                handle_suggest(store, queue, timeout.clone(), chain_id,view, key2, key2_val, prev_key2, key3, key3_val, env, api)
+                handle_suggest(store, queue, instance_id, timeout.clone(), chain_id,view, key2, key2_val, prev_key2, key3, key3_val, env, api)
             },
             Msg::Proof {
+                instance_id,
                 key1,
                 key1_val,
                 prev_key1,
                 view,
             } => { 
-                handle_proof(store, local_channel_id.clone(), key1, key1_val, prev_key1, view,env,api)
+                handle_proof(store, instance_id, local_channel_id.clone(), key1, key1_val, prev_key1, view,env,api)
             },
-            Msg::Echo { val, view } => { 
-                handle_echo(store, queue, timeout.clone(), local_channel_id.clone(), val, view,env,api)
+            Msg::Echo { instance_id, view, .. } => { 
+                handle_echo(store, queue, instance_id, timeout.clone(), local_channel_id.clone(), vote_val, view,env,api)
             },
-            Msg::Key1 { val, view } => handle_key1(store, queue, timeout.clone(), local_channel_id.clone(), val, view,env,api),
-            Msg::Key2 { val, view } => handle_key2(store, queue, timeout.clone(), local_channel_id.clone(), val, view,env,api),
-            Msg::Key3 { val, view } => {
+            Msg::Key1 { instance_id, view, .. } => handle_key1(store, queue, instance_id, timeout.clone(), local_channel_id.clone(), vote_val, view,env,api),
+            Msg::Key2 { instance_id, view, .. } => handle_key2(store, queue, instance_id, timeout.clone(), local_channel_id.clone(), vote_val, view,env,api),
+            Msg::Key3 { instance_id, view, .. } => {
                 handle_key3(
-                    store, queue, timeout.clone(), local_channel_id.clone(), val, view,env, api
+                    store, queue, instance_id, timeout.clone(), local_channel_id.clone(), vote_val, view,env, api
             )},
-            Msg::Lock { val, view } => {
+            Msg::Lock { instance_id, view, .. } => {
                 // DEBUG_RECEIVE_MSG.update(store, "handle_lock".to_string(), | mut state| -> Result<_, ContractError> {
                 //     match state {
                 //         Some(mut vec) => {
@@ -516,17 +659,18 @@ pub fn receive_queue(
                 //         }
                 //     }
                 // });                            
-                wasm_exec_messages = handle_lock(store, queue, timeout.clone(), local_channel_id.clone(), val, view,env,api).unwrap();
-                Ok(())
+                handle_lock(store, queue, instance_id, timeout.clone(), local_channel_id.clone(), vote_val, view,env,api)
             },
-            Msg::Done { val } => { 
-                wasm_exec_messages = handle_done(store, queue, timeout.clone(), local_channel_id.clone(), val,env,api).unwrap();
-                Ok(())
+            Msg::Done { instance_id, .. } => { 
+                handle_done(store, queue, instance_id, timeout.clone(), local_channel_id.clone(), vote_val,env,api)
             }
-            Msg::Abort { view, chain_id } => 
+            Msg::Value { instance_id, val } => handle_value(store, queue, instance_id, timeout.clone(), val, env, api),
+            // answered above
+            Msg::Fetch { .. } | Msg::Report { .. } => Ok(()),
+            Msg::Abort { instance_id, view, chain_id } => 
             {
                 DEBUG.save(store, 200+chain_id, &"RECEIVED_ABORT".to_string())?;
-                handle_abort(store, queue, view, chain_id, timeout.clone(), env, api)
+                handle_abort(store, queue, instance_id, view, chain_id, timeout.clone(), env, api)
             },
         };
         
@@ -534,89 +678,57 @@ pub fn receive_queue(
         result?
     }
 
+    // Slots may have become ready to start
+    fill_pipeline(store, timeout, queue, env, api)?;
+    Ok(())
+}
 
-    let mut res = IbcReceiveResponse::new();
-    let mut state = STATE.load(store)?;
-    if let Some(val) = state.done {
-        if wasm_exec_messages.len() > 0 {
-            DEBUG.save(store, 88888888, &"EXECUTED ME HELLO OUTSIDE!!!!!".to_string())?;
-            res = res.add_submessages(wasm_exec_messages);
-        }
-    }   
-
-    match local_channel_id {
-        Some(_) => {
-            // After handling all msgs in queue sucessfully
-            // Generate msg queue to send
-            let mut msgs = Vec::new();
-            // let timeout = get_timeout(env);
-            DEBUG.save(store, 300, &"LOCAL_CHANNEL_ID".to_string())?;
-
-            //// TESTING /////
-            let state = STATE.load(store)?;
-            let mut i = 0;
-            for (chain_id, msg_queue) in queue.iter().enumerate() {
-                //// TESTING /////
-                let chain_msg_pair = (chain_id as u32, msg_queue.to_vec());
-                let action = |packets: Option<Vec<_>>| -> StdResult<Vec<_>> {
-                    match packets {
-                        Some(mut p) => {
-                            p.push(chain_msg_pair.clone());
-                            Ok(p)
-                        },
-                        None => Ok(vec!(chain_msg_pair.clone())),
-                    }
-                };
-                TEST_QUEUE.update(store, state.current_tx_id, action)?;
-                //// TESTING /////
-
-                if chain_id != state.chain_id as usize {
-                    // When chain wish to send some msgs to dest chain
-                    if msg_queue.len() > 0 {
-                        let channel_id = CHANNELS.load(store, chain_id.try_into().unwrap())?;
-                        i = i+1;
-                        let first_msg_name = msg_queue[0].name();
-                        let debug_str = format!("{} {} FIRST MESSAGE LEN {} TO CHAIN_ID: {}" , 
-                                                        "SEND_PACKET QUEUE SIZE", msg_queue.len(), first_msg_name, chain_id);   
-                        DEBUG.save(store, 400+i, &debug_str)?;
-                        let msg = IbcMsg::SendPacket {
-                            channel_id,
-                            data: to_binary(&PacketMsg::MsgQueue ( msg_queue.to_vec() ) )?,
-                            timeout: timeout.clone(),
-                        };
-                        msgs.push(msg);
-                    }
-                }
-            }
-            //// TESTING ////
-            let mut state = STATE.load(store)?;
-            state.current_tx_id += 1;
-            STATE.save(store, &state)?;
-            //// TESTING ////
+// A body fetched for a digest, process the votes that were waiting for it
+fn handle_value(
+    store: &mut dyn Storage,
+    queue: &mut Vec<Vec<Msg>>,
+    instance_id: u64,
+    timeout: IbcTimeout,
+    val: InputType,
+    env: &Env,
+    api: &dyn Api,
+) -> StdResult<()> {
+    // a body is only fetched for parked votes, anything else a peer sends is dropped
+    let votes = unpark(store, instance_id, &val.digest())?;
+    if votes.is_empty() {
+        return Ok(());
+    }
+    remember(store, instance_id, &val)?;
+    for (channel_id, msg) in votes {
+        process_queue(store, timeout.clone(), channel_id, vec![msg], queue, env, api)?;
+    }
+    Ok(())
+}
 
-            let acknowledgement = to_binary(&AcknowledgementMsg::Ok(MsgQueueResponse { }))?;            
-            // Add to Response if there are pending messages
-            if msgs.len() > 0 {
-                TEST.save(store, state.current_tx_id, &msgs)?;
-                // state.current_tx_id += 1;
-                STATE.save(store, &state)?;
-                res = res.add_messages(msgs);
-            }
-                    
-            Ok(res
-                .set_ack(acknowledgement)
-                .add_attribute("action", "receive_msg_queue"))
-        },
-        None => { 
-            Ok(res.set_ack(b"{}")
-                .add_attribute("action", "ibc_packet_ack"))
-        }
+// Process the msgs that arrived before the instance was started locally
+pub fn replay_pending_msgs(
+    store: &mut dyn Storage,
+    instance_id: u64,
+    timeout: IbcTimeout,
+    queue: &mut Vec<Vec<Msg>>,
+    env: &Env,
+    api: &dyn Api,
+) -> StdResult<()> {
+    let pending: StdResult<Vec<_>> = PENDING_MSGS
+        .prefix(instance_id)
+        .range(store, None, None, Order::Ascending)
+        .collect();
+    for (channel_id, msgs) in pending? {
+        PENDING_MSGS.remove(store, (instance_id, channel_id.clone()));
+        process_queue(store, timeout.clone(), Some(channel_id), msgs, queue, env, api)?;
     }
+    Ok(())
 }
 
 
-fn accept_key(key: u32, value: InputType, proofs: Vec<(u32, InputType, i32)>) -> bool {
-    let mut supporting = 0;
+// A key is accepted once f + 1 key2 proofs support it, so at least one comes from a correct chain
+fn accept_key(key: u32, value: InputType, proofs: Vec<(u32, InputType, i32)>, f: u32) -> bool {
+    let mut supporting: u32 = 0;
     for (k, v, pk) in proofs {
         if (key as i32) < pk {
             supporting += 1;
@@ -624,16 +736,13 @@ fn accept_key(key: u32, value: InputType, proofs: Vec<(u32, InputType, i32)>) ->
             supporting += 1;
         }
     }
-    if supporting >= 1 + 1 {
-        return true;
-    }
-    false
+    supporting >= f + 1
 }
 
 
-fn open_lock(store: &mut dyn Storage, proofs: Vec<(u32, InputType, i32)>) -> StdResult<bool> {
+fn open_lock(store: &mut dyn Storage, instance_id: u64, proofs: Vec<(u32, InputType, i32)>) -> StdResult<bool> {
     let mut supporting: u32 = 0;
-    let state = STATE.load(store)?;
+    let state = STATE.load(store, instance_id)?;
     for (k, v, pk) in proofs {
         if (state.lock as i32) <= pk {
             supporting += 1;
@@ -650,26 +759,27 @@ fn open_lock(store: &mut dyn Storage, proofs: Vec<(u32, InputType, i32)>) -> Std
 
 fn message_transfer_hop(
     storage: &mut dyn Storage, 
+    instance_id: u64,
     val: InputType, 
     view: u32,
     queue: &mut Vec<Vec<Msg>>, 
-    message_type: cw_storage_plus::Map<u64, HashSet<u32>>, 
+    message_type: cw_storage_plus::Map<(u64, Vec<u8>), HashSet<u32>>, 
     msg_to_send: Msg, 
     timeout: IbcTimeout, 
     channel_id: Option<String>, 
     env: &Env,
     api: &dyn Api
 ) -> Result<bool, StdError> {
-        let state = STATE.load(storage)?;
+        let state = STATE.load(storage, instance_id)?;
         // ignore messages from other views, other than abort, done and request messages
         if view != state.view && message_type.namespace() != "received_done".as_bytes(){
             return Ok(false);
         }
         // detect if self-send
         let chain_id = match channel_id {
-            Some(id) => {
-                // Get the chain_id of the sender
-                get_chain_id(storage, id)
+            Some(id) => match find_chain_id(storage, &id) {
+                Some(chain_id) => chain_id,
+                None => return Ok(false),
             },
             None => state.chain_id,
         };
@@ -680,19 +790,19 @@ fn message_transfer_hop(
                 None => Ok(HashSet::new()),
             }
         };
-        let val_hash = val.calculate_hash();
-        let mut set = message_type.update(storage, val_hash, action)?;
+        let val_digest = val.digest().to_vec();
+        let mut set = message_type.update(storage, (instance_id, val_digest.clone()), action)?;
         if !set.contains(&chain_id) {
             set.insert(chain_id);
-            message_type.save(storage, val_hash, &set)?;
+            message_type.save(storage, (instance_id, val_digest.clone()), &set)?;
 
             // If received Done, operate accordingly
             if message_type.namespace() == "received_done".as_bytes() {
                 // check if have not sent Done && received from f + 1 parties 
                 if !state.sent.contains(msg_to_send.name()) && set.len() >= (state.F + 1).try_into().unwrap() {
-                    let mut state = STATE.load(storage)?;
+                    let mut state = STATE.load(storage, instance_id)?;
                     state.sent.insert(msg_to_send.name().to_string());
-                    STATE.save(storage, &state)?;
+                    STATE.save(storage, instance_id, &state)?;
                     send_all_party(storage, queue, msg_to_send, timeout.clone(), env, api)?;
                 }
                 // upon receiving from n - f parties with the same val
@@ -703,9 +813,9 @@ fn message_transfer_hop(
             } else {
                 // upon receiving from n - f parties with the same val
                 if !state.sent.contains(msg_to_send.name()) && set.len() >= (state.n - state.F).try_into().unwrap() {
-                    let mut state = STATE.load(storage)?;
+                    let mut state = STATE.load(storage, instance_id)?;
                     state.sent.insert(msg_to_send.name().to_string());
-                    STATE.save(storage, &state)?;
+                    STATE.save(storage, instance_id, &state)?;
                     // if received Lock, ensure we send <done, val> to every party
                     if message_type.namespace() == "received_lock".as_bytes() {
                         send_all_party(storage, queue, msg_to_send, timeout, env, api)?;
@@ -724,13 +834,14 @@ fn message_transfer_hop(
 
 // send_all_upon_join_queue Operation
 pub fn send_all_upon_join_queue(storage: &mut dyn Storage, queue: &mut Vec<Vec<Msg>>, packet_msg: Msg, timeout: IbcTimeout, env: &Env, api: &dyn Api) -> Result<(), StdError> {
-    let state = STATE.load(storage)?;
-    let channel_ids = get_id_channel_pair_from_storage(storage)?;
+    let instance_id = packet_msg.instance_id();
+    let state = STATE.load(storage, instance_id)?;
+    let channel_ids = get_instance_peers(storage, instance_id)?;
     // self-send msg
     receive_queue(storage, timeout, None, vec![packet_msg.clone()], queue, env, api)?;
 
     for (chain_id, _channel_id) in &channel_ids {
-        let highest_request = HIGHEST_REQ.load(storage, chain_id.clone())?;
+        let highest_request = HIGHEST_REQ.load(storage, (instance_id, *chain_id))?;
         if highest_request == state.view {
             //DEBUG.save(storage, 10000000+chain_id, &chain_id.to_string())?;
             queue[*chain_id as usize].push(packet_msg.clone());
@@ -746,14 +857,14 @@ pub fn send_all_upon_join_queue(storage: &mut dyn Storage, queue: &mut Vec<Vec<M
                 }
                 
             };
-            SEND_ALL_UPON.update(storage, *chain_id, action)?;
+            SEND_ALL_UPON.update(storage, (instance_id, *chain_id), action)?;
         }
     }
     Ok(())
 }
 
 pub fn send_all_party(store: &mut dyn Storage, queue: &mut Vec<Vec<Msg>>, packet: Msg, timeout: IbcTimeout, env: &Env, api: &dyn Api) -> Result<(), StdError> {
-    let channel_ids = get_id_channel_pair_from_storage(store)?;
+    let channel_ids = get_instance_peers(store, packet.instance_id())?;
     // self-send msg
     receive_queue(store, timeout, None, vec![packet.clone()], queue, env, api)?;
 
@@ -762,4 +873,22 @@ pub fn send_all_party(store: &mut dyn Storage, queue: &mut Vec<Vec<Msg>>, packet
     }
     
     Ok(())
-}
\ No newline at end of file
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::state::Reconfig;
+
+    #[test]
+    fn accept_key_needs_f_plus_one_proofs() {
+        let val = InputType::noop();
+        let other = InputType { inputs: vec![], reconfig: Some(Reconfig { n: 4, f: 1, three_chain_demo: false, members: vec![] }) };
+        // two proofs of a later key2 and one matching key2, vs. one that supports nothing
+        let proofs = vec![(1, other.clone(), 3), (1, other.clone(), 4), (2, val.clone(), 0), (0, other, -1)];
+        assert!(accept_key(2, val.clone(), proofs[..2].to_vec(), 1));
+        assert!(!accept_key(2, val.clone(), proofs[..2].to_vec(), 2));
+        assert!(accept_key(2, val.clone(), proofs[..3].to_vec(), 2));
+        assert!(!accept_key(2, val, proofs.clone(), 3));
+    }
+}
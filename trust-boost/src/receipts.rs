@@ -0,0 +1,131 @@
+use cosmwasm_std::{Addr, Env, Order, StdError, StdResult, Storage, SubMsgResult};
+
+use crate::state::{Receipt, ReceiptStatus, TBInput, AWAITING_REPLY, RECEIPTS, USED_NONCES};
+
+// Every input of a decided slot gets a receipt on each chain, keyed by (slot, position in the
+// batch). An input that is sent to its target is Pending until the reply to its WasmMsg
+// records the outcome with what the target returned. Targets run as reply_always
+// submessages and the reply never fails, so a failing target only reverts its own changes,
+// the decision and the rest of the slot stay. Replies arrive in the order the submessages
+// were emitted, so the inputs awaiting one are kept in that order.
+
+fn new_receipt(env: &Env, slot: u64, index: u32, input: &TBInput, status: ReceiptStatus) -> Receipt {
+    Receipt {
+        slot,
+        index,
+        sender: input.sender().ok(),
+        nonce: input.nonce,
+        contract: None,
+        funds: vec![],
+        status,
+        events: vec![],
+        data: None,
+        block_height: env.block.height,
+    }
+}
+
+// The input is not executed on this chain
+pub fn skip(store: &mut dyn Storage, env: &Env, slot: u64, index: u32, input: &TBInput, reason: String) -> StdResult<()> {
+    RECEIPTS.save(store, (slot, index), &new_receipt(env, slot, index, input, ReceiptStatus::Skipped { reason }))
+}
+
+// The input is sent to `contract` with its funds, the next reply is its outcome
+pub fn expect_reply(store: &mut dyn Storage, env: &Env, slot: u64, index: u32, input: &TBInput, contract: &Addr) -> StdResult<()> {
+    let mut receipt = new_receipt(env, slot, index, input, ReceiptStatus::Pending {});
+    receipt.contract = Some(contract.clone());
+    receipt.funds = input.funds.clone();
+    RECEIPTS.save(store, (slot, index), &receipt)?;
+    let mut awaiting = AWAITING_REPLY.may_load(store)?.unwrap_or_default();
+    awaiting.push((slot, index));
+    AWAITING_REPLY.save(store, &awaiting)
+}
+
+pub fn record_reply(store: &mut dyn Storage, env: &Env, result: SubMsgResult) -> StdResult<Receipt> {
+    let mut awaiting = AWAITING_REPLY.may_load(store)?.unwrap_or_default();
+    if awaiting.is_empty() {
+        return Err(StdError::generic_err("no execution awaits a reply"));
+    }
+    let key = awaiting.remove(0);
+    AWAITING_REPLY.save(store, &awaiting)?;
+
+    let mut receipt = RECEIPTS.load(store, key)?;
+    receipt.block_height = env.block.height;
+    match result {
+        SubMsgResult::Ok(response) => {
+            receipt.status = ReceiptStatus::Succeeded {};
+            receipt.events = response.events;
+            receipt.data = response.data;
+        },
+        SubMsgResult::Err(error) => receipt.status = ReceiptStatus::Failed { error },
+    }
+    RECEIPTS.save(store, key, &receipt)?;
+    Ok(receipt)
+}
+
+pub fn slot_receipts(store: &dyn Storage, slot: u64) -> StdResult<Vec<Receipt>> {
+    RECEIPTS
+        .prefix(slot)
+        .range(store, None, None, Order::Ascending)
+        .map(|item| item.map(|(_, receipt)| receipt))
+        .collect()
+}
+
+// The receipt of the input a sender executed with `nonce`, none until it is executed here
+pub fn input_receipt(store: &dyn Storage, sender: String, nonce: u64) -> StdResult<Option<Receipt>> {
+    let slot = match USED_NONCES.may_load(store, (sender.clone(), nonce))? {
+        Some(slot) => slot,
+        None => return Ok(None),
+    };
+    // a replay of the input in the same slot has a Skipped receipt of its own
+    let executed = |receipt: &Receipt| {
+        receipt.nonce == nonce
+            && receipt.sender.as_ref().map(Addr::as_str) == Some(sender.as_str())
+            && !matches!(receipt.status, ReceiptStatus::Skipped { .. })
+    };
+    Ok(slot_receipts(store, slot)?.into_iter().find(executed))
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use cosmwasm_std::testing::{mock_dependencies, mock_env};
+    use cosmwasm_std::{Binary, Event, SubMsgResponse};
+
+    fn input(nonce: u64) -> TBInput {
+        TBInput { public_key: vec![2; 33], nonce, ..TBInput::default() }
+    }
+
+    #[test]
+    fn replies_complete_receipts_in_order() {
+        let mut deps = mock_dependencies();
+        let store = &mut deps.storage;
+        let mut env = mock_env();
+        let target = Addr::unchecked("target");
+
+        expect_reply(store, &env, 4, 0, &input(1), &target).unwrap();
+        skip(store, &env, 4, 1, &input(2), "invalid signature".to_string()).unwrap();
+        expect_reply(store, &env, 4, 2, &input(3), &target).unwrap();
+        assert_eq!(slot_receipts(store, 4).unwrap()[0].status, ReceiptStatus::Pending {});
+
+        env.block.height += 1;
+        let ok = SubMsgResult::Ok(SubMsgResponse { events: vec![Event::new("wasm")], data: Some(Binary::from(b"42")) });
+        let first = record_reply(store, &env, ok).unwrap();
+        assert_eq!((first.index, first.status, first.block_height), (0, ReceiptStatus::Succeeded {}, env.block.height));
+        assert_eq!(first.events, vec![Event::new("wasm")]);
+        let second = record_reply(store, &env, SubMsgResult::Err("out of funds".to_string())).unwrap();
+        assert_eq!((second.index, second.status), (2, ReceiptStatus::Failed { error: "out of funds".to_string() }));
+        assert!(record_reply(store, &env, SubMsgResult::Err(String::new())).is_err());
+
+        let statuses: Vec<_> = slot_receipts(store, 4).unwrap().into_iter().map(|receipt| receipt.status).collect();
+        assert_eq!(statuses, vec![
+            ReceiptStatus::Succeeded {},
+            ReceiptStatus::Skipped { reason: "invalid signature".to_string() },
+            ReceiptStatus::Failed { error: "out of funds".to_string() },
+        ]);
+
+        let sender = input(3).sender().unwrap().to_string();
+        assert_eq!(input_receipt(store, sender.clone(), 3).unwrap(), None);
+        USED_NONCES.save(store, (sender.clone(), 3), &4).unwrap();
+        assert_eq!(input_receipt(store, sender, 3).unwrap().unwrap().index, 2);
+    }
+}
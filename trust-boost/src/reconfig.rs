@@ -0,0 +1,226 @@
+use cosmwasm_std::{Order, StdError, StdResult, Storage};
+use cw_storage_plus::Bound;
+
+use crate::membership::{set_members, validate_quorum};
+use crate::state::{Batch, Config, Member, Reconfig, APPROVED_RECONFIG, CONFIG, LOG_LENGTH, PENDING_RECONFIG, SCHEDULED_RECONFIGS};
+
+// A reconfiguration is agreed on like any input: governance proposes it on a chain, the chain
+// puts it in the batch of the next slot it starts. Once slot s is in the log, every replica
+// schedules it for slot s + RECONFIG_WINDOW and instances from that slot on run with the new
+// n, f and members. No instance is started RECONFIG_WINDOW slots past the end of the log, so a
+// slot of the new replica set is never started before the reconfiguration is known.
+//
+// Chain ids index the msg queues, so a replica set is always the chain ids 0..n: growing adds
+// chains at the end and shrinking removes the highest chain ids. A new chain is instantiated
+// with the new n and f and first_slot set to the activation slot.
+//
+// A replica only echoes a proposal carrying a reconfiguration its own governance approved, so
+// one that gathers an echo quorum was approved on at least n - 2f correct chains. A primary
+// cannot put a replica set of its own choosing up for agreement.
+
+pub const RECONFIG_WINDOW: u64 = 8;
+// Largest replica set a reconfiguration may ask for, msg queues are allocated per replica
+pub const MAX_REPLICAS: u32 = 100;
+
+// A replica set every replica can switch to
+pub fn check(reconfig: &Reconfig) -> StdResult<()> {
+    if reconfig.n > MAX_REPLICAS {
+        return Err(StdError::generic_err(format!(
+            "n = {} is above the maximum of {} replicas", reconfig.n, MAX_REPLICAS
+        )));
+    }
+    validate_quorum(0, reconfig.n, reconfig.f, reconfig.three_chain_demo)?;
+    if let Some((chain_id, _)) = reconfig.members.iter().find(|(chain_id, _)| *chain_id >= reconfig.n) {
+        return Err(StdError::generic_err(format!(
+            "Member {} is outside of n = {}", chain_id, reconfig.n
+        )));
+    }
+    Ok(())
+}
+
+// Queue a reconfiguration approved by governance for the next slot this chain starts
+pub fn propose(store: &mut dyn Storage, reconfig: Reconfig) -> StdResult<()> {
+    check(&reconfig)?;
+    APPROVED_RECONFIG.save(store, &reconfig)?;
+    PENDING_RECONFIG.save(store, &reconfig)
+}
+
+// Whether a replica may echo a proposal of this batch, as far as its reconfiguration goes
+pub fn may_echo(store: &dyn Storage, batch: &Batch) -> StdResult<bool> {
+    match &batch.reconfig {
+        Some(reconfig) => Ok(check(reconfig).is_ok() && APPROVED_RECONFIG.may_load(store)?.as_ref() == Some(reconfig)),
+        None => Ok(true),
+    }
+}
+
+// Schedule the reconfiguration of a slot that was just appended to the log. Every replica
+// skips one it could not switch to, whether or not it voted for it.
+pub fn schedule(store: &mut dyn Storage, slot: u64, batch: &Batch) -> StdResult<()> {
+    if let Some(reconfig) = &batch.reconfig {
+        if check(reconfig).is_err() {
+            return Ok(());
+        }
+        SCHEDULED_RECONFIGS.save(store, slot + RECONFIG_WINDOW, reconfig)?;
+        // another replica got the same proposal decided
+        if PENDING_RECONFIG.may_load(store)?.as_ref() == Some(reconfig) {
+            PENDING_RECONFIG.remove(store);
+        }
+        if APPROVED_RECONFIG.may_load(store)?.as_ref() == Some(reconfig) {
+            APPROVED_RECONFIG.remove(store);
+        }
+    }
+    Ok(())
+}
+
+// Make the scheduled reconfigurations the log has reached the current configuration
+pub fn activate(store: &mut dyn Storage) -> StdResult<()> {
+    let log_length = LOG_LENGTH.load(store)?;
+    let due = SCHEDULED_RECONFIGS
+        .range(store, None, Some(Bound::inclusive(log_length)), Order::Ascending)
+        .collect::<StdResult<Vec<_>>>()?;
+    for (activate_at, reconfig) in due {
+        let mut config = CONFIG.load(store)?;
+        apply(&mut config, &reconfig);
+        CONFIG.save(store, &config)?;
+        set_members(store, reconfig.members)?;
+        SCHEDULED_RECONFIGS.remove(store, activate_at);
+    }
+    Ok(())
+}
+
+fn apply(config: &mut Config, reconfig: &Reconfig) {
+    config.n = reconfig.n;
+    config.f = reconfig.f;
+    config.three_chain_demo = reconfig.three_chain_demo;
+}
+
+// The configuration instance `slot` runs with
+pub fn config_at(store: &dyn Storage, slot: u64) -> StdResult<Config> {
+    let mut config = CONFIG.load(store)?;
+    let scheduled = SCHEDULED_RECONFIGS
+        .range(store, None, Some(Bound::inclusive(slot)), Order::Descending)
+        .next()
+        .transpose()?;
+    if let Some((_, reconfig)) = scheduled {
+        apply(&mut config, &reconfig);
+    }
+    Ok(config)
+}
+
+// A slot can be started once its configuration is known, and only by a chain that is part of it
+pub fn can_start(store: &dyn Storage, slot: u64) -> StdResult<bool> {
+    let log_length = LOG_LENGTH.load(store)?;
+    let config = config_at(store, slot)?;
+    Ok(slot < log_length + RECONFIG_WINDOW && config.chain_id < config.n)
+}
+
+pub fn scheduled(store: &dyn Storage) -> StdResult<Vec<(u64, Reconfig)>> {
+    SCHEDULED_RECONFIGS
+        .range(store, None, None, Order::Ascending)
+        .collect()
+}
+
+// Largest n of the current and the scheduled configurations
+pub fn max_n(store: &dyn Storage) -> StdResult<u32> {
+    let n = CONFIG.load(store)?.n;
+    Ok(scheduled(store)?.into_iter().map(|(_, reconfig)| reconfig.n).fold(n, u32::max))
+}
+
+// The member a scheduled configuration expects behind chain_id
+pub fn scheduled_member(store: &dyn Storage, chain_id: u32) -> StdResult<Option<Member>> {
+    Ok(scheduled(store)?
+        .into_iter()
+        .flat_map(|(_, reconfig)| reconfig.members)
+        .find(|(id, _)| *id == chain_id)
+        .map(|(_, member)| member))
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use cosmwasm_std::testing::mock_dependencies;
+    use cosmwasm_std::Addr;
+    use crate::log::append_decided;
+    use crate::membership::bind_peer;
+    use crate::state::{State, STATE};
+    use cosmwasm_std::testing::mock_env;
+
+    fn reconfig(n: u32, f: u32) -> Reconfig {
+        Reconfig { n, f, three_chain_demo: false, members: vec![] }
+    }
+
+    #[test]
+    fn decided_reconfig_activates_at_window_boundary() {
+        let mut deps = mock_dependencies();
+        let store = deps.as_mut().storage;
+        let mut config = Config::new(3, Addr::unchecked("target"));
+        config.n = 4;
+        config.f = 1;
+        CONFIG.save(store, &config).unwrap();
+        LOG_LENGTH.save(store, &0).unwrap();
+
+        assert!(propose(store, reconfig(5, 2)).is_err());
+        propose(store, reconfig(3, 0)).unwrap();
+        let batch = Batch { inputs: vec![], reconfig: PENDING_RECONFIG.may_load(store).unwrap() };
+        let mut state = State::new(0, &config, batch.clone(), mock_env().block.time);
+        state.done = Some(batch);
+        STATE.save(store, 0, &state).unwrap();
+        append_decided(store).unwrap();
+
+        assert!(PENDING_RECONFIG.may_load(store).unwrap().is_none());
+        assert_eq!(config_at(store, RECONFIG_WINDOW - 1).unwrap().n, 4);
+        assert_eq!(config_at(store, RECONFIG_WINDOW).unwrap().n, 3);
+        // chain 3 is not part of the replica set from the boundary on
+        assert!(can_start(store, RECONFIG_WINDOW - 1).unwrap());
+        assert!(!can_start(store, RECONFIG_WINDOW).unwrap());
+        assert_eq!(CONFIG.load(store).unwrap().n, 4);
+
+        LOG_LENGTH.save(store, &RECONFIG_WINDOW).unwrap();
+        activate(store).unwrap();
+        assert_eq!(CONFIG.load(store).unwrap().n, 3);
+        assert!(scheduled(store).unwrap().is_empty());
+    }
+
+    #[test]
+    fn members_of_scheduled_reconfig_can_join() {
+        let mut deps = mock_dependencies();
+        let store = deps.as_mut().storage;
+        let mut config = Config::new(0, Addr::unchecked("target"));
+        config.n = 4;
+        config.f = 1;
+        CONFIG.save(store, &config).unwrap();
+
+        assert!(bind_peer(store, 4, "channel-4").is_err());
+        let batch = Batch { inputs: vec![], reconfig: Some(reconfig(5, 1)) };
+        schedule(store, 0, &batch).unwrap();
+        assert_eq!(max_n(store).unwrap(), 5);
+        bind_peer(store, 4, "channel-4").unwrap();
+        assert!(bind_peer(store, 5, "channel-5").is_err());
+    }
+
+    #[test]
+    fn only_approved_reconfigs_are_echoed_and_valid_ones_scheduled() {
+        let mut deps = mock_dependencies();
+        let store = deps.as_mut().storage;
+        let mut config = Config::new(0, Addr::unchecked("target"));
+        config.n = 4;
+        config.f = 1;
+        CONFIG.save(store, &config).unwrap();
+
+        let batch = |reconfig| Batch { inputs: vec![], reconfig: Some(reconfig) };
+        assert!(may_echo(store, &Batch { inputs: vec![], reconfig: None }).unwrap());
+        assert!(!may_echo(store, &batch(reconfig(5, 1))).unwrap());
+        assert!(propose(store, reconfig(MAX_REPLICAS + 1, 1)).is_err());
+        propose(store, reconfig(5, 1)).unwrap();
+        assert!(may_echo(store, &batch(reconfig(5, 1))).unwrap());
+        assert!(!may_echo(store, &batch(reconfig(6, 1))).unwrap());
+
+        // a decided reconfiguration nobody can switch to is skipped
+        schedule(store, 0, &batch(reconfig(4, 2))).unwrap();
+        schedule(store, 1, &batch(reconfig(u32::MAX, 0))).unwrap();
+        assert!(scheduled(store).unwrap().is_empty());
+        schedule(store, 2, &batch(reconfig(5, 1))).unwrap();
+        assert_eq!(scheduled(store).unwrap().len(), 1);
+        assert!(APPROVED_RECONFIG.may_load(store).unwrap().is_none());
+    }
+}
@@ -0,0 +1,102 @@
+use cosmwasm_std::{Env, StdResult, Storage};
+
+use crate::error::ContractError;
+use crate::state::{Expiry, TBInput, CONFIG, USED_NONCES};
+
+// A signed input covers its domain, nonce and expiry next to the payload (see
+// TBInput::sign_bytes), so none of them can be changed without the user's key.
+// A chain refuses an input before it enters consensus when it is signed for another
+// deployment, has expired, or its nonce was already used by the same sender. Every replica
+// checks domain and expiry again before it echoes a proposal, so a Byzantine primary cannot
+// get such an input decided. Inputs that get into a slot anyway are checked again when the
+// slot is executed: only the first input with a given (sender, nonce) is executed, in log
+// order, so every chain skips the same ones. An input that expired by then is skipped too;
+// chains execute a slot at different heights, so close to the deadline they may disagree,
+// which the slot reports show.
+
+// Signed for this deployment and not expired
+pub fn check_current(store: &dyn Storage, env: &Env, input: &TBInput) -> Result<(), ContractError> {
+    let domain = CONFIG.load(store)?.domain;
+    if input.domain != domain {
+        return Err(ContractError::WrongDomain { expected: domain, got: input.domain.clone() });
+    }
+    if input.expires.is_expired(env) {
+        return Err(ContractError::Expired {});
+    }
+    Ok(())
+}
+
+pub fn check_fresh(store: &dyn Storage, env: &Env, input: &TBInput) -> Result<(), ContractError> {
+    check_current(store, env, input)?;
+    let sender = input.sender()?.to_string();
+    if USED_NONCES.has(store, (sender.clone(), input.nonce)) {
+        return Err(ContractError::NonceUsed { sender, nonce: input.nonce });
+    }
+    Ok(())
+}
+
+impl Expiry {
+    pub fn is_expired(&self, env: &Env) -> bool {
+        match self {
+            Expiry::AtHeight(height) => env.block.height >= *height,
+            Expiry::AtTime(time) => env.block.time >= *time,
+        }
+    }
+}
+
+// Mark the nonce of an input executed in `slot` as used, false if it was used before
+// or the input is for another deployment
+pub fn consume_nonce(store: &mut dyn Storage, input: &TBInput, slot: u64) -> StdResult<bool> {
+    if input.domain != CONFIG.load(store)?.domain {
+        return Ok(false);
+    }
+    let key = (input.sender()?.to_string(), input.nonce);
+    if USED_NONCES.has(store, key.clone()) {
+        return Ok(false);
+    }
+    USED_NONCES.save(store, key, &slot)?;
+    Ok(true)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use cosmwasm_std::testing::{mock_dependencies, mock_env};
+    use cosmwasm_std::Addr;
+    use crate::state::Config;
+
+    fn input(domain: &str, nonce: u64, expires: Expiry) -> TBInput {
+        TBInput { public_key: vec![2; 33], domain: domain.to_string(), nonce, expires, ..TBInput::default() }
+    }
+
+    #[test]
+    fn stale_replayed_and_foreign_inputs_are_refused() {
+        let mut deps = mock_dependencies();
+        let env = mock_env();
+        let mut config = Config::new(0, Addr::unchecked("target"));
+        config.domain = "tb-test".to_string();
+        CONFIG.save(deps.as_mut().storage, &config).unwrap();
+        let store = deps.as_mut().storage;
+
+        let fresh = input("tb-test", 1, Expiry::AtHeight(env.block.height + 1));
+        check_fresh(store, &env, &fresh).unwrap();
+
+        let foreign = input("tb-other", 1, Expiry::AtHeight(env.block.height + 1));
+        assert!(matches!(check_fresh(store, &env, &foreign), Err(ContractError::WrongDomain { .. })));
+        assert!(!consume_nonce(store, &foreign, 0).unwrap());
+
+        let at_height = input("tb-test", 2, Expiry::AtHeight(env.block.height));
+        assert!(matches!(check_fresh(store, &env, &at_height), Err(ContractError::Expired {})));
+        let at_time = input("tb-test", 2, Expiry::AtTime(env.block.time.minus_seconds(1)));
+        assert!(matches!(check_fresh(store, &env, &at_time), Err(ContractError::Expired {})));
+
+        // the first execution uses the nonce, the same nonce with another payload is a replay too
+        assert!(consume_nonce(store, &fresh, 3).unwrap());
+        assert!(!consume_nonce(store, &fresh, 4).unwrap());
+        let other_payload = TBInput { binary: "e30K".to_string(), ..fresh.clone() };
+        assert!(matches!(check_fresh(store, &env, &other_payload), Err(ContractError::NonceUsed { nonce: 1, .. })));
+        // nonces are per sender
+        let other_sender = TBInput { public_key: vec![3; 33], ..fresh };
+        check_fresh(store, &env, &other_sender).unwrap();
+    }
+}
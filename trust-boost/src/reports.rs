@@ -0,0 +1,98 @@
+use std::mem::discriminant;
+
+use cosmwasm_std::{Order, StdResult, Storage};
+
+use crate::ibc_msg::Msg;
+use crate::msg::{ChainReport, SlotReportsResponse};
+use crate::receipts::slot_receipts;
+use crate::state::{ReceiptStatus, AWAITING_REPLY, CHAIN_REPORTS, CONFIG, EXEC_CURSOR, REPORT_CURSOR, STATE};
+
+// Every chain executes a decided slot on its own, so the same input may succeed on one chain
+// and be rejected by the target on another. Once no receipt of an executed slot is Pending
+// any more, the chain sends the statuses as a Report to the other replicas of the slot and
+// keeps them under its own chain_id next to the reports it received. Slots are reported in
+// order, a slot waits for the replies of every earlier one. The first report of a chain for
+// a slot is kept, it cannot be taken back. Inputs whose outcome (succeeded, failed or
+// skipped) is not the same in every report are divergent.
+
+// Queue the Report of every slot whose receipts are complete since the last call
+pub fn queue_reports(store: &mut dyn Storage, queue: &mut [Vec<Msg>]) -> StdResult<()> {
+    let chain_id = CONFIG.load(store)?.chain_id;
+    let executed = EXEC_CURSOR.load(store)?;
+    let awaiting = AWAITING_REPLY.may_load(store)?.unwrap_or_default();
+    let mut slot = REPORT_CURSOR.load(store)?;
+    while slot < executed && !awaiting.iter().any(|(pending, _)| *pending == slot) {
+        let statuses: Vec<ReceiptStatus> = slot_receipts(store, slot)?.into_iter().map(|receipt| receipt.status).collect();
+        CHAIN_REPORTS.save(store, (slot, chain_id), &statuses)?;
+        for peer in 0..STATE.load(store, slot)?.n {
+            if peer != chain_id {
+                queue[peer as usize].push(Msg::Report { instance_id: slot, statuses: statuses.clone() });
+            }
+        }
+        slot += 1;
+    }
+    REPORT_CURSOR.save(store, &slot)
+}
+
+pub fn record_report(store: &mut dyn Storage, slot: u64, chain_id: u32, statuses: &[ReceiptStatus]) -> StdResult<()> {
+    if !CHAIN_REPORTS.has(store, (slot, chain_id)) {
+        CHAIN_REPORTS.save(store, (slot, chain_id), &statuses.to_vec())?;
+    }
+    Ok(())
+}
+
+pub fn slot_reports(store: &dyn Storage, slot: u64) -> StdResult<SlotReportsResponse> {
+    let reports: Vec<ChainReport> = CHAIN_REPORTS
+        .prefix(slot)
+        .range(store, None, None, Order::Ascending)
+        .map(|item| item.map(|(chain_id, statuses)| ChainReport { chain_id, statuses }))
+        .collect::<StdResult<_>>()?;
+    let n = match STATE.may_load(store, slot)? {
+        Some(state) => state.n,
+        None => CONFIG.load(store)?.n,
+    };
+    let missing = (0..n).filter(|chain_id| reports.iter().all(|report| report.chain_id != *chain_id)).collect();
+    // a report that is shorter than another one disagrees on the inputs it lacks
+    let len = reports.iter().map(|report| report.statuses.len()).max().unwrap_or_default();
+    let divergent = (0..len)
+        .filter(|index| {
+            let mut kinds = reports.iter().map(|report| report.statuses.get(*index).map(discriminant));
+            let first = kinds.next().flatten();
+            kinds.any(|kind| kind != first)
+        })
+        .map(|index| index as u32)
+        .collect();
+    Ok(SlotReportsResponse { slot, reports, missing, divergent })
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use cosmwasm_std::testing::mock_dependencies;
+    use cosmwasm_std::Addr;
+    use crate::state::Config;
+
+    #[test]
+    fn outcomes_are_compared_across_chains() {
+        let mut deps = mock_dependencies();
+        let store = &mut deps.storage;
+        let mut config = Config::new(0, Addr::unchecked("nameservice"));
+        config.n = 4;
+        CONFIG.save(store, &config).unwrap();
+
+        let failed = ReceiptStatus::Failed { error: "NameTaken".to_string() };
+        let skipped = ReceiptStatus::Skipped { reason: "invalid signature".to_string() };
+        record_report(store, 3, 0, &[ReceiptStatus::Succeeded {}, skipped.clone(), ReceiptStatus::Succeeded {}]).unwrap();
+        record_report(store, 3, 2, &[failed.clone(), skipped.clone(), ReceiptStatus::Succeeded {}]).unwrap();
+        // a chain cannot change its report
+        record_report(store, 3, 2, &[ReceiptStatus::Succeeded {}, skipped.clone(), ReceiptStatus::Succeeded {}]).unwrap();
+        let res = slot_reports(store, 3).unwrap();
+        assert_eq!(res.reports[1].statuses[0], failed);
+        assert_eq!((res.missing, res.divergent), (vec![1, 3], vec![0]));
+
+        // errors may differ in their text as long as the outcome is the same
+        record_report(store, 4, 0, &[ReceiptStatus::Failed { error: "a".to_string() }]).unwrap();
+        record_report(store, 4, 1, &[ReceiptStatus::Failed { error: "b".to_string() }, skipped]).unwrap();
+        assert_eq!(slot_reports(store, 4).unwrap().divergent, vec![1]);
+    }
+}
@@ -0,0 +1,210 @@
+use cosmwasm_std::{Binary, IbcMsg, IbcTimeout, Order, StdResult, Storage};
+use sha2::{Digest, Sha256};
+
+use crate::codec::{channel_encoding, decode_packet, encode_for_channel};
+use crate::ibc_msg::{Msg, PacketMsg};
+use crate::membership::{disconnect_channel, is_connected};
+use crate::state::{OutboundPacket, CHANNELS, OUTBOX};
+use crate::utils::find_chain_id;
+
+// Every MsgQueue packet stays in the outbox of its peer chain until the peer acks it.
+// Channels are ordered, so a timeout closes the channel: the packet is kept, along with
+// queues for the peer while it is disconnected, and all of them are sent again on the
+// new channel once the peer is bound to it. An error ack is final unless the peer may
+// accept the packet later, then it is sent again right away. A packet is sent again at
+// most MAX_RETRIES times. Payloads are keyed by their digest, so resending an identical
+// queue before it is acked does not create a second entry.
+
+pub const MAX_RETRIES: u32 = 3;
+
+// Part of the error ack for a queue on a channel whose WhoAmI the peer has not accepted.
+// That can change, e.g. once the peer scheduled the reconfiguration this chain joins with.
+pub const UNBOUND_CHANNEL: &str = "is not bound to a chain id";
+
+pub fn is_transient(error: &str) -> bool {
+    error.contains(UNBOUND_CHANNEL)
+}
+
+fn outbox_key(chain_id: u32, data: &Binary) -> (u32, Vec<u8>) {
+    (chain_id, Sha256::digest(data.as_slice()).to_vec())
+}
+
+// The MsgQueue packet for a peer, None if it has never been bound. The queue of a
+// disconnected peer is kept in the outbox until the peer is back.
+pub fn send_queue(store: &mut dyn Storage, chain_id: u32, msgs: Vec<Msg>, timeout: IbcTimeout) -> StdResult<Option<IbcMsg>> {
+    let channel_id = match CHANNELS.may_load(store, chain_id)? {
+        Some(channel_id) => channel_id,
+        None => return Ok(None),
+    };
+    let data = encode_for_channel(store, &channel_id, &PacketMsg::MsgQueue(msgs))?;
+    let key = outbox_key(chain_id, &data);
+    if !OUTBOX.has(store, key.clone()) {
+        let packet = OutboundPacket { chain_id, channel_id: channel_id.clone(), data: data.clone(), retries: 0 };
+        OUTBOX.save(store, key, &packet)?;
+    }
+    if !is_connected(store, chain_id) {
+        return Ok(None);
+    }
+    Ok(Some(IbcMsg::SendPacket { channel_id, data, timeout }))
+}
+
+// The tracked packet sent on channel_id with this data
+fn sent_on(store: &dyn Storage, channel_id: &str, data: &Binary) -> Option<((u32, Vec<u8>), OutboundPacket)> {
+    OUTBOX
+        .range(store, None, None, Order::Ascending)
+        .filter_map(|item| item.ok())
+        .find(|(_, packet)| packet.channel_id == channel_id && packet.data == *data)
+}
+
+// The peer processed the packet, or refused it for good
+pub fn acked(store: &mut dyn Storage, channel_id: &str, data: &Binary) -> StdResult<()> {
+    if let Some((key, _)) = sent_on(store, channel_id, data) {
+        OUTBOX.remove(store, key);
+    }
+    Ok(())
+}
+
+// The peer acked the packet with an error it may not return later. Returns the packet
+// to send again, or None once it ran out of retries or was never tracked.
+pub fn retry(store: &mut dyn Storage, channel_id: &str, data: &Binary, timeout: IbcTimeout) -> StdResult<Option<IbcMsg>> {
+    let (key, mut packet) = match sent_on(store, channel_id, data) {
+        Some(entry) => entry,
+        None => return Ok(None),
+    };
+    if packet.retries >= MAX_RETRIES {
+        OUTBOX.remove(store, key);
+        return Ok(None);
+    }
+    packet.retries += 1;
+    OUTBOX.save(store, key, &packet)?;
+    Ok(Some(IbcMsg::SendPacket { channel_id: packet.channel_id, data: packet.data, timeout }))
+}
+
+// The packet timed out, which closes its ordered channel. The packet waits for the peer to
+// be bound to a new channel, or is sent on it right away if that already happened.
+pub fn timed_out(store: &mut dyn Storage, channel_id: &str, data: &Binary, timeout: IbcTimeout) -> StdResult<Vec<IbcMsg>> {
+    if find_chain_id(store, channel_id).is_some() {
+        disconnect_channel(store, channel_id)?;
+    }
+    match sent_on(store, channel_id, data) {
+        Some((_, packet)) if is_connected(store, packet.chain_id) => resend_to(store, packet.chain_id, timeout),
+        _ => Ok(Vec::new()),
+    }
+}
+
+// Send the outstanding packets of a peer on the channel it is bound to now, called once
+// it completed WhoAmI on a new channel. Packets are encoded for the new channel's version.
+pub fn resend_to(store: &mut dyn Storage, chain_id: u32, timeout: IbcTimeout) -> StdResult<Vec<IbcMsg>> {
+    let channel_id = match CHANNELS.may_load(store, chain_id)? {
+        Some(channel_id) => channel_id,
+        None => return Ok(Vec::new()),
+    };
+    let stale: Vec<((u32, Vec<u8>), OutboundPacket)> = OUTBOX
+        .prefix(chain_id)
+        .range(store, None, None, Order::Ascending)
+        .filter_map(|item| item.ok())
+        .filter(|(_, packet)| packet.channel_id != channel_id)
+        .map(|(digest, packet)| ((chain_id, digest), packet))
+        .collect();
+    let mut msgs = Vec::new();
+    for (key, packet) in stale {
+        OUTBOX.remove(store, key);
+        if packet.retries >= MAX_RETRIES {
+            continue;
+        }
+        let queue = decode_packet(&packet.data, channel_encoding(store, &packet.channel_id)?)?;
+        let data = encode_for_channel(store, &channel_id, &queue)?;
+        let resent = OutboundPacket { chain_id, channel_id: channel_id.clone(), data: data.clone(), retries: packet.retries + 1 };
+        OUTBOX.save(store, outbox_key(chain_id, &data), &resent)?;
+        msgs.push(IbcMsg::SendPacket { channel_id: channel_id.clone(), data, timeout: timeout.clone() });
+    }
+    Ok(msgs)
+}
+
+// The peer was dropped from the replica set, nothing is sent to it any more
+pub fn forget(store: &mut dyn Storage, chain_id: u32) -> StdResult<()> {
+    let keys: Vec<Vec<u8>> = OUTBOX
+        .prefix(chain_id)
+        .keys(store, None, None, Order::Ascending)
+        .collect::<StdResult<_>>()?;
+    for digest in keys {
+        OUTBOX.remove(store, (chain_id, digest));
+    }
+    Ok(())
+}
+
+pub fn outstanding(store: &dyn Storage) -> StdResult<Vec<OutboundPacket>> {
+    OUTBOX
+        .range(store, None, None, Order::Ascending)
+        .map(|item| item.map(|(_, packet)| packet))
+        .collect()
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use cosmwasm_std::testing::{mock_dependencies, mock_env};
+    use crate::membership::bind_peer;
+    use crate::state::{Config, CONFIG};
+    use crate::utils::get_timeout;
+
+    // this chain is 0 of 4, chain 1 is bound to channel-1
+    fn connect(store: &mut dyn Storage) {
+        let mut config = Config::new(0, cosmwasm_std::Addr::unchecked("target"));
+        config.n = 4;
+        CONFIG.save(store, &config).unwrap();
+        bind_peer(store, 1, "channel-1").unwrap();
+    }
+
+    #[test]
+    fn retries_are_bounded_and_acks_clear_the_outbox() {
+        let mut deps = mock_dependencies();
+        let store = deps.as_mut().storage;
+        connect(store);
+        let timeout = get_timeout(&mock_env());
+        let msg = send_queue(store, 1, vec![], timeout.clone()).unwrap().unwrap();
+        let data = match &msg {
+            IbcMsg::SendPacket { data, .. } => data.clone(),
+            _ => unreachable!(),
+        };
+        send_queue(store, 1, vec![], timeout.clone()).unwrap();
+        assert_eq!(outstanding(store).unwrap().len(), 1);
+
+        for _ in 0..MAX_RETRIES {
+            assert!(retry(store, "channel-1", &data, timeout.clone()).unwrap().is_some());
+        }
+        assert!(retry(store, "channel-1", &data, timeout.clone()).unwrap().is_none());
+        assert!(outstanding(store).unwrap().is_empty());
+
+        send_queue(store, 1, vec![], timeout.clone()).unwrap();
+        acked(store, "channel-1", &data).unwrap();
+        assert!(outstanding(store).unwrap().is_empty());
+        assert!(retry(store, "channel-1", &data, timeout).unwrap().is_none());
+    }
+
+    #[test]
+    fn timed_out_packets_wait_for_the_peer_to_reconnect() {
+        let mut deps = mock_dependencies();
+        let store = deps.as_mut().storage;
+        connect(store);
+        let timeout = get_timeout(&mock_env());
+        let first = vec![Msg::Request { instance_id: 0, view: 1, chain_id: 0 }];
+        let data = match send_queue(store, 1, first, timeout.clone()).unwrap() {
+            Some(IbcMsg::SendPacket { data, .. }) => data,
+            _ => unreachable!(),
+        };
+
+        // the channel closes with the timeout, nothing goes out until the peer is back
+        assert!(timed_out(store, "channel-1", &data, timeout.clone()).unwrap().is_empty());
+        assert!(!is_connected(store, 1));
+        let second = vec![Msg::Request { instance_id: 1, view: 1, chain_id: 0 }];
+        assert_eq!(send_queue(store, 1, second, timeout.clone()).unwrap(), None);
+        assert_eq!(outstanding(store).unwrap().len(), 2);
+
+        bind_peer(store, 1, "channel-7").unwrap();
+        let resent = resend_to(store, 1, timeout).unwrap();
+        assert_eq!(resent.len(), 2);
+        assert!(resent.iter().all(|msg| matches!(msg, IbcMsg::SendPacket { channel_id, .. } if channel_id == "channel-7")));
+        assert!(outstanding(store).unwrap().iter().all(|packet| packet.channel_id == "channel-7" && packet.retries == 1));
+    }
+}
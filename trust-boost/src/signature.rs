@@ -0,0 +1,278 @@
+use bech32::{FromBase32, ToBase32, Variant};
+use cosmwasm_std::{Addr, Api, Binary, StdError, StdResult};
+use ripemd::Ripemd160;
+use sha2::{Digest, Sha256};
+
+use crate::error::ContractError;
+use crate::escrow::{check_funds, coins_text};
+use crate::state::{Batch, Expiry, SignatureScheme, TBInput};
+
+// Every input is signed by its user. What is signed is the text
+//
+//   trustboost input
+//   domain: <domain>
+//   nonce: <nonce>
+//   expires: height <height> | time <nanoseconds>
+//   contract: <target contract, empty for the default one>
+//   funds: <coins sent along as amount and denom, comma separated, e.g. 100stake,5uatom>
+//   binary: <binary>
+//
+// lines separated by "\n", and TBInput::scheme says how:
+// - secp256k1: a 64 byte signature over Sha256(text), as js_client makes it
+// - ed25519: a signature over the text itself
+// - adr036: what Cosmos wallets produce for "sign arbitrary data" (Keplr signArbitrary),
+//   a secp256k1 signature over the amino JSON sign doc of a MsgSignData whose data is
+//   the base64 text and whose signer is the address of the key, on any chain prefix
+//
+// An input is executed on behalf of the address of its key, derived as the Cosmos SDK does
+// for the key type and encoded with the prefix of this chain: RIPEMD160(SHA256(key)) for
+// secp256k1 keys, which adr036 also uses, and the first 20 bytes of SHA256(key) for ed25519.
+// secp256k1 keys are only accepted in their 33 byte compressed form, one key is one sender.
+//
+// Inputs are validated when they are handed to a chain and again by every replica before it
+// echoes a proposal, so a malformed or forged input never takes up a consensus round.
+// Validation only looks at the input itself and gives the same answer on every chain.
+
+pub const ADDR_PREFIX: &str = "wasm";
+
+impl TBInput {
+    // The text the user signs. binary is base64, the domain and the contract of an accepted
+    // input are the ones configured on the chain and denoms are checked by validate, none has
+    // a line break, so the lines cannot run into each other.
+    pub fn sign_bytes(&self) -> Vec<u8> {
+        let expires = match &self.expires {
+            Expiry::AtHeight(height) => format!("height {}", height),
+            Expiry::AtTime(time) => format!("time {}", time.nanos()),
+        };
+        format!(
+            "trustboost input\ndomain: {}\nnonce: {}\nexpires: {}\ncontract: {}\nfunds: {}\nbinary: {}",
+            self.domain,
+            self.nonce,
+            expires,
+            self.contract.as_deref().unwrap_or_default(),
+            coins_text(&self.funds),
+            self.binary
+        )
+        .into_bytes()
+    }
+
+    pub fn verify(&self, api: &dyn Api) -> StdResult<bool> {
+        let message = self.sign_bytes();
+        match &self.scheme {
+            SignatureScheme::Secp256k1 => {
+                let hash = Sha256::digest(&message);
+                Ok(api.secp256k1_verify(&hash, &self.signature, &self.public_key)?)
+            },
+            SignatureScheme::Ed25519 => Ok(api.ed25519_verify(&message, &self.signature, &self.public_key)?),
+            SignatureScheme::Adr036 { signer } => {
+                if decode_address(signer)? != secp256k1_address(&self.public_key) {
+                    return Ok(false);
+                }
+                let hash = Sha256::digest(adr036_sign_doc(signer, &Binary::from(message).to_base64())?);
+                Ok(api.secp256k1_verify(&hash, &self.signature, &self.public_key)?)
+            },
+        }
+    }
+
+    // A well formed input with a valid signature, checked before any key is handed to the api
+    pub fn validate(&self, api: &dyn Api) -> Result<(), ContractError> {
+        Binary::from_base64(&self.binary).map_err(|err| ContractError::InvalidBinary { reason: err.to_string() })?;
+        check_funds(&self.funds)?;
+        let (key_ok, key_type) = match self.scheme {
+            SignatureScheme::Secp256k1 | SignatureScheme::Adr036 { .. } => match self.public_key.first() {
+                // the address is derived from the key bytes, so an uncompressed key would be a
+                // second sender for the same private key
+                Some(0x02) | Some(0x03) => (self.public_key.len() == 33, "compressed secp256k1"),
+                _ => (false, "compressed secp256k1"),
+            },
+            SignatureScheme::Ed25519 => (self.public_key.len() == 32, "ed25519"),
+        };
+        if !key_ok {
+            return Err(ContractError::InvalidPublicKey {
+                reason: format!("{} bytes are not a {} key", self.public_key.len(), key_type),
+            });
+        }
+        if self.signature.len() != 64 {
+            return Err(ContractError::InvalidSignature { reason: format!("expected 64 bytes, got {}", self.signature.len()) });
+        }
+        match self.verify(api) {
+            Ok(true) => Ok(()),
+            Ok(false) => Err(ContractError::InvalidSignature { reason: "does not match the input".to_string() }),
+            Err(err) => Err(ContractError::InvalidSignature { reason: err.to_string() }),
+        }
+    }
+
+    // The address the input is executed for
+    pub fn sender(&self) -> StdResult<Addr> {
+        let address = match self.scheme {
+            SignatureScheme::Secp256k1 | SignatureScheme::Adr036 { .. } => secp256k1_address(&self.public_key),
+            SignatureScheme::Ed25519 => Sha256::digest(&self.public_key)[..20].to_vec(),
+        };
+        let address = bech32::encode(ADDR_PREFIX, address.to_base32(), Variant::Bech32)
+            .map_err(|err| StdError::generic_err(err.to_string()))?;
+        Ok(Addr::unchecked(address))
+    }
+}
+
+impl Batch {
+    // Whether a replica may echo a proposal of this batch
+    pub fn is_valid(&self, api: &dyn Api) -> bool {
+        self.inputs.iter().all(|input| input.validate(api).is_ok())
+    }
+}
+
+fn secp256k1_address(public_key: &[u8]) -> Vec<u8> {
+    Ripemd160::digest(Sha256::digest(public_key)).to_vec()
+}
+
+// The bytes behind a bech32 address, whatever its prefix
+fn decode_address(address: &str) -> StdResult<Vec<u8>> {
+    let (_, data, _) = bech32::decode(address)
+        .map_err(|err| StdError::generic_err(format!("invalid signer {}: {}", address, err)))?;
+    Vec::<u8>::from_base32(&data).map_err(|err| StdError::generic_err(format!("invalid signer {}: {}", address, err)))
+}
+
+// ADR-036 sign doc in canonical amino JSON: keys sorted, no whitespace, account_number,
+// sequence and chain_id zeroed. Both fields are checked first, so neither needs escaping.
+pub fn adr036_sign_doc(signer: &str, data: &str) -> StdResult<Vec<u8>> {
+    decode_address(signer)?;
+    Binary::from_base64(data)?;
+    Ok(format!(
+        r#"{{"account_number":"0","chain_id":"","fee":{{"amount":[],"gas":"0"}},"memo":"","msgs":[{{"type":"sign/MsgSignData","value":{{"data":"{}","signer":"{}"}}}}],"sequence":"0"}}"#,
+        data, signer
+    )
+    .into_bytes())
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use cosmwasm_std::testing::mock_dependencies;
+    use cosmwasm_std::Coin;
+    use ed25519_zebra::{SigningKey as Ed25519Key, VerificationKey};
+    use k256::ecdsa::{signature::Signer, Signature, SigningKey};
+    use k256::elliptic_curve::sec1::ToEncodedPoint;
+
+    fn binary() -> String {
+        Binary::from(br#"{"register":{"name":"alice"}}"#).to_base64()
+    }
+
+    fn secp256k1_key() -> SigningKey {
+        SigningKey::from_bytes(&[7u8; 32]).unwrap()
+    }
+
+    fn unsigned(scheme: SignatureScheme) -> TBInput {
+        TBInput {
+            binary: binary(),
+            public_key: secp256k1_key().verifying_key().to_bytes().to_vec(),
+            scheme,
+            domain: "tb-test".to_string(),
+            nonce: 1,
+            expires: Expiry::AtHeight(100),
+            ..TBInput::default()
+        }
+    }
+
+    // signs `message`, the sign bytes of the input unless given
+    fn secp256k1_input(scheme: SignatureScheme, message: Option<&[u8]>) -> TBInput {
+        let input = unsigned(scheme);
+        let signature: Signature = secp256k1_key().sign(message.unwrap_or(&input.sign_bytes()));
+        TBInput { signature: signature.as_ref().to_vec(), ..input }
+    }
+
+    #[test]
+    fn every_scheme_verifies_and_rejects_tampering() {
+        let deps = mock_dependencies();
+        let api = &deps.api;
+
+        let raw = secp256k1_input(SignatureScheme::Secp256k1, None);
+        assert!(raw.verify(api).unwrap());
+
+        let key = Ed25519Key::from([9u8; 32]);
+        let ed25519 = TBInput {
+            public_key: VerificationKey::from(&key).as_ref().to_vec(),
+            ..unsigned(SignatureScheme::Ed25519)
+        };
+        let ed25519 = TBInput { signature: <[u8; 64]>::from(key.sign(&ed25519.sign_bytes())).to_vec(), ..ed25519 };
+        assert!(ed25519.verify(api).unwrap());
+
+        // the signer is the key's address on the wallet's chain
+        let signer = bech32::encode("cosmos", secp256k1_address(&raw.public_key).to_base32(), Variant::Bech32).unwrap();
+        let doc = adr036_sign_doc(&signer, &Binary::from(raw.sign_bytes()).to_base64()).unwrap();
+        let adr036 = secp256k1_input(SignatureScheme::Adr036 { signer: signer.clone() }, Some(&doc));
+        assert!(adr036.verify(api).unwrap());
+
+        // every signed field is covered
+        for input in [raw.clone(), ed25519.clone(), adr036.clone()] {
+            let tampered = [
+                TBInput { binary: Binary::from(br#"{"register":{"name":"bob"}}"#).to_base64(), ..input.clone() },
+                TBInput { domain: "tb-other".to_string(), ..input.clone() },
+                TBInput { nonce: 2, ..input.clone() },
+                TBInput { expires: Expiry::AtHeight(101), ..input.clone() },
+                TBInput { contract: Some("wasm1other".to_string()), ..input.clone() },
+                TBInput { funds: vec![Coin::new(1, "stake")], ..input.clone() },
+            ];
+            for tampered in tampered {
+                assert!(!tampered.verify(api).unwrap());
+            }
+        }
+        // a raw signature is not valid as a sign doc signature and the other way round
+        assert!(!TBInput { scheme: SignatureScheme::Adr036 { signer }, ..raw.clone() }.verify(api).unwrap());
+        assert!(!TBInput { scheme: SignatureScheme::Secp256k1, ..adr036.clone() }.verify(api).unwrap());
+        // a doc naming someone else's address
+        let other = bech32::encode("cosmos", vec![1u8; 20].to_base32(), Variant::Bech32).unwrap();
+        assert!(!TBInput { scheme: SignatureScheme::Adr036 { signer: other }, ..adr036 }.verify(api).unwrap());
+    }
+
+    #[test]
+    fn uncompressed_key_cannot_replay_an_input() {
+        let deps = mock_dependencies();
+        let raw = secp256k1_input(SignatureScheme::Secp256k1, None);
+        raw.validate(&deps.api).unwrap();
+        // the same signature under the uncompressed form of the key, which would be a fresh sender
+        let uncompressed = secp256k1_key().verifying_key().to_encoded_point(false).as_bytes().to_vec();
+        let replayed = TBInput { public_key: uncompressed, ..raw.clone() };
+        assert!(replayed.verify(&deps.api).unwrap());
+        assert_ne!(replayed.sender().unwrap(), raw.sender().unwrap());
+        assert!(matches!(replayed.validate(&deps.api), Err(ContractError::InvalidPublicKey { .. })));
+    }
+
+    #[test]
+    fn sender_follows_the_key_type() {
+        let raw = secp256k1_input(SignatureScheme::Secp256k1, None);
+        let adr036 = TBInput { scheme: SignatureScheme::Adr036 { signer: String::new() }, ..raw.clone() };
+        // the same secp256k1 key is the same account whichever way it signed
+        assert_eq!(raw.sender().unwrap(), adr036.sender().unwrap());
+        assert!(raw.sender().unwrap().as_str().starts_with("wasm1"));
+
+        let ed25519 = TBInput { scheme: SignatureScheme::Ed25519, ..raw.clone() };
+        let expected = bech32::encode(ADDR_PREFIX, Sha256::digest(&raw.public_key)[..20].to_vec().to_base32(), Variant::Bech32).unwrap();
+        assert_eq!(ed25519.sender().unwrap().as_str(), expected);
+        assert_ne!(ed25519.sender().unwrap(), raw.sender().unwrap());
+    }
+
+    #[test]
+    fn sign_bytes_are_stable() {
+        let input = TBInput { expires: Expiry::AtTime(cosmwasm_std::Timestamp::from_seconds(2)), ..unsigned(SignatureScheme::Ed25519) };
+        let expected = format!("trustboost input\ndomain: tb-test\nnonce: 1\nexpires: time 2000000000\ncontract: \nfunds: \nbinary: {}", binary());
+        assert_eq!(input.sign_bytes(), expected.as_bytes());
+        let input = TBInput { contract: Some("wasm1target".to_string()), ..input };
+        let expected = expected.replace("contract: \n", "contract: wasm1target\n");
+        assert_eq!(input.sign_bytes(), expected.as_bytes());
+        let input = TBInput { funds: vec![Coin::new(5, "stake"), Coin::new(100, "uatom")], ..input };
+        let expected = expected.replace("funds: \n", "funds: 5stake,100uatom\n");
+        assert_eq!(input.sign_bytes(), expected.into_bytes());
+    }
+
+    #[test]
+    fn sign_doc_is_canonical_amino_json() {
+        let signer = bech32::encode("cosmos", vec![1u8; 20].to_base32(), Variant::Bech32).unwrap();
+        let doc = adr036_sign_doc(&signer, "aGk=").unwrap();
+        let parsed: serde_json::Value = serde_json::from_slice(&doc).unwrap();
+        // serde_json sorts keys, so re-serializing has to give the same bytes
+        assert_eq!(serde_json::to_vec(&parsed).unwrap(), doc);
+        assert_eq!(parsed["msgs"][0]["value"]["data"], "aGk=");
+        assert!(adr036_sign_doc("cosmos1\"", "aGk=").is_err());
+        assert!(adr036_sign_doc(&signer, "a\"").is_err());
+    }
+}
@@ -0,0 +1,991 @@
+// Deterministic multi-chain simulator: n copies of the contract on mock storage, connected
+// pairwise by ordered channels. channel-j on chain i leads to chain j. Every SendPacket a chain
+// emits goes into the network, a Scheduler picks which channel delivers next and how long each
+// packet is delayed, and the ack goes straight back to the sender. A channel is ordered, so its
+// packets are always delivered in the order they were sent. Block height and time advance by one
+// per delivery. A scheduler may also drop a packet, which times it out at the sender and closes
+// its channel, or deliver it twice. A closed channel is reopened right away as channel-j-<k>.
+
+use std::collections::{HashMap, HashSet, VecDeque};
+use std::convert::TryInto;
+
+use cosmwasm_std::testing::{mock_dependencies, mock_env, mock_info, MockApi, MockQuerier, MockStorage};
+use cosmwasm_std::{
+    from_binary, BankMsg, Binary, Coin, CosmosMsg, Env, IbcAcknowledgement, IbcChannel, IbcChannelCloseMsg, IbcChannelConnectMsg, IbcEndpoint,
+    IbcMsg, IbcOrder, IbcPacket, IbcPacketAckMsg, IbcPacketReceiveMsg, IbcPacketTimeoutMsg, IbcTimeout, OwnedDeps,
+    Reply, Response, SubMsg, SubMsgResponse, SubMsgResult, WasmMsg,
+};
+use k256::ecdsa::{signature::Signer, Signature, SigningKey};
+use serde::de::DeserializeOwned;
+use sha2::{Digest, Sha256};
+
+use crate::codec::encode_for_channel;
+use crate::contract::{execute, instantiate, query, reply, sudo};
+use crate::error::ContractError;
+use crate::ibc::{ibc_channel_close, ibc_channel_connect, ibc_packet_ack, ibc_packet_receive, ibc_packet_timeout};
+use crate::ibc_msg::{Msg, PacketMsg};
+use crate::msg::{ExecuteMsg, InstantiateMsg, QueryMsg, SudoMsg};
+use crate::retransmit::MAX_RETRIES;
+use crate::state::{Batch, Member, TBInput, STATE};
+use crate::utils::{get_timeout, IBC_APP_VERSION, IBC_APP_VERSION_COMPACT};
+
+pub type Deps = OwnedDeps<MockStorage, MockApi, MockQuerier>;
+
+pub const DOMAIN: &str = "tb-sim";
+
+pub struct Packet {
+    pub src: u32,
+    pub dst: u32,
+    pub data: Binary,
+    pub timeout: IbcTimeout,
+    /// tick from which the packet can be delivered
+    pub ready_at: u64,
+    /// times the channel between src and dst had been reopened when the packet was sent
+    pub generation: u32,
+}
+
+#[derive(Clone, Copy, Debug, PartialEq)]
+pub enum Delivery {
+    Deliver,
+    /// the packet times out, which closes the channel. Both chains reopen it and send
+    /// their outstanding packets again, while they have retries left
+    Drop,
+    Duplicate,
+}
+
+// Controls the network: the delay of every packet sent, which of the channels whose
+// next packet is ready delivers first, and what happens to that packet
+pub trait Scheduler {
+    fn delay(&mut self, _src: u32, _dst: u32) -> u64 {
+        0
+    }
+    // `ready` holds the next packet of every channel that can deliver now, oldest first
+    fn pick(&mut self, ready: &[&Packet]) -> usize;
+    fn delivery(&mut self, _packet: &Packet) -> Delivery {
+        Delivery::Deliver
+    }
+}
+
+// Packets are delivered in the order they were sent
+pub struct Fifo;
+
+impl Scheduler for Fifo {
+    fn pick(&mut self, _ready: &[&Packet]) -> usize {
+        0
+    }
+}
+
+// Random channel order and packet delays of up to max_delay ticks, reproducible from the seed
+pub struct Random {
+    state: u64,
+    max_delay: u64,
+}
+
+impl Random {
+    pub fn new(seed: u64, max_delay: u64) -> Self {
+        Random { state: seed.wrapping_mul(0x9E37_79B9_7F4A_7C15) | 1, max_delay }
+    }
+
+    // xorshift64*
+    fn next(&mut self) -> u64 {
+        self.state ^= self.state >> 12;
+        self.state ^= self.state << 25;
+        self.state ^= self.state >> 27;
+        self.state.wrapping_mul(0x2545_F491_4F6C_DD1D)
+    }
+}
+
+impl Scheduler for Random {
+    fn delay(&mut self, _src: u32, _dst: u32) -> u64 {
+        self.next() % (self.max_delay + 1)
+    }
+
+    fn pick(&mut self, ready: &[&Packet]) -> usize {
+        (self.next() % ready.len() as u64) as usize
+    }
+}
+
+// Every packet sent by or to the given chains is delayed by `delay` ticks
+pub struct SlowChains {
+    pub chains: Vec<u32>,
+    pub delay: u64,
+}
+
+impl Scheduler for SlowChains {
+    fn delay(&mut self, src: u32, dst: u32) -> u64 {
+        if self.chains.contains(&src) || self.chains.contains(&dst) {
+            self.delay
+        } else {
+            0
+        }
+    }
+
+    fn pick(&mut self, _ready: &[&Packet]) -> usize {
+        0
+    }
+}
+
+pub struct Sim {
+    pub chains: Vec<Deps>,
+    pub env: Env,
+    /// packets in flight, in the order they were sent
+    pub net: VecDeque<Packet>,
+    pub scheduler: Box<dyn Scheduler>,
+    /// wasm msgs executed by every chain, in order, with the contract they were sent to
+    pub executed: Vec<Vec<(String, Binary)>>,
+    /// target contracts whose execution fails, every other one succeeds
+    pub failing: HashSet<String>,
+    /// coins every chain sent out, along with executions that succeeded and as refunds
+    pub transfers: Vec<Vec<(String, Vec<Coin>)>>,
+    now: u64,
+    seq: u64,
+    // times the channel between two chains was reopened, it is never closed more often than
+    // a packet is resent, so no packet runs out of retries
+    generations: HashMap<(u32, u32), u32>,
+}
+
+// The local id of the channel to chain_id, "channel-<chain_id>" until it is reopened
+fn channel_id(chain_id: u32, generation: u32) -> String {
+    match generation {
+        0 => format!("channel-{}", chain_id),
+        _ => format!("channel-{}-{}", chain_id, generation),
+    }
+}
+
+fn link(a: u32, b: u32) -> (u32, u32) {
+    (a.min(b), a.max(b))
+}
+
+fn port_id(chain_id: u32) -> String {
+    format!("wasm.{}", chain_id)
+}
+
+// The members of a replica set of n chains, as configured on every chain
+pub fn members(n: u32) -> Vec<(u32, Member)> {
+    (0..n)
+        .map(|chain_id| (chain_id, Member { port_id: Some(port_id(chain_id)), connection_id: None }))
+        .collect()
+}
+
+pub fn instantiate_msg(chain_id: u32, n: u32, f: u32) -> InstantiateMsg {
+    InstantiateMsg {
+        chain_id,
+        n,
+        f,
+        three_chain_demo: None,
+        contract_addr: "target_contract".to_string(),
+        pipeline_depth: Some(2),
+        max_batch_size: None,
+        max_mempool_size: None,
+        allowed_ports: None,
+        allowed_connections: None,
+        members: Some(members(n)),
+        first_slot: None,
+        domain: DOMAIN.to_string(),
+        sender_field: None,
+        owner: None,
+        targets: None,
+    }
+}
+
+// all inputs come from the same key, each text gets its own nonce
+pub fn signed(text: &str) -> TBInput {
+    signed_for(text, None)
+}
+
+pub fn signed_for(text: &str, contract: Option<&str>) -> TBInput {
+    let nonce = Sha256::digest(text.as_bytes())[..8].try_into().unwrap();
+    sign(TBInput {
+        binary: Binary::from(text.as_bytes()).to_base64(),
+        public_key: key().verifying_key().to_bytes().to_vec(),
+        domain: DOMAIN.to_string(),
+        nonce: u64::from_be_bytes(nonce),
+        contract: contract.map(str::to_string),
+        ..TBInput::default()
+    })
+}
+
+// An input to `contract` that sends `funds` along
+pub fn signed_paying(text: &str, contract: Option<&str>, funds: Vec<Coin>) -> TBInput {
+    sign(TBInput { funds, ..signed_for(text, contract) })
+}
+
+fn key() -> SigningKey {
+    SigningKey::from_bytes(&[7u8; 32]).unwrap()
+}
+
+pub fn sign(input: TBInput) -> TBInput {
+    let signature: Signature = key().sign(&input.sign_bytes());
+    TBInput { signature: signature.as_ref().to_vec(), ..input }
+}
+
+impl Sim {
+    // n chains tolerating f faults, all connected to each other
+    pub fn new(n: u32, f: u32) -> Self {
+        Self::with_scheduler(n, f, Box::new(Fifo))
+    }
+
+    pub fn with_scheduler(n: u32, f: u32, scheduler: Box<dyn Scheduler>) -> Self {
+        let mut sim = Sim {
+            chains: Vec::new(),
+            env: mock_env(),
+            net: VecDeque::new(),
+            scheduler,
+            executed: Vec::new(),
+            now: 0,
+            seq: 0,
+            generations: HashMap::new(),
+            failing: HashSet::new(),
+            transfers: Vec::new(),
+        };
+        for chain_id in 0..n {
+            sim.add_chain(instantiate_msg(chain_id, n, f));
+        }
+        sim.run();
+        sim
+    }
+
+    // Instantiate one more chain and connect it to all existing ones
+    pub fn add_chain(&mut self, msg: InstantiateMsg) {
+        let chain_id = msg.chain_id;
+        assert_eq!(chain_id as usize, self.chains.len());
+        let mut deps = mock_dependencies();
+        instantiate(deps.as_mut(), self.env.clone(), mock_info("creator", &[]), msg).unwrap();
+        self.chains.push(deps);
+        self.executed.push(Vec::new());
+        self.transfers.push(Vec::new());
+        for other in 0..chain_id {
+            self.connect(chain_id, other);
+            self.connect(other, chain_id);
+        }
+    }
+
+    // half of the channels use the compact encoding, so both are exercised in every run
+    fn connect(&mut self, chain_id: u32, other: u32) {
+        let msg = IbcChannelConnectMsg::new_ack(self.channel(chain_id, other), self.channel(chain_id, other).version);
+        let res = ibc_channel_connect(self.chains[chain_id as usize].as_mut(), self.env.clone(), msg).unwrap();
+        self.route(chain_id, res.messages);
+    }
+
+    // The current channel between the two chains, as seen from chain_id
+    fn channel(&self, chain_id: u32, other: u32) -> IbcChannel {
+        let generation = self.generations.get(&link(chain_id, other)).copied().unwrap_or_default();
+        let version = if (chain_id + other).is_multiple_of(2) { IBC_APP_VERSION } else { IBC_APP_VERSION_COMPACT };
+        IbcChannel::new(
+            IbcEndpoint { port_id: port_id(chain_id), channel_id: channel_id(other, generation) },
+            IbcEndpoint { port_id: port_id(other), channel_id: channel_id(chain_id, generation) },
+            IbcOrder::Ordered,
+            version,
+            format!("connection-{}", other),
+        )
+    }
+
+    // A timeout closed the channel between a and b: packets still in flight on it time out
+    // as well, then the relayer opens a new channel and both chains send WhoAmI on it
+    fn reopen(&mut self, a: u32, b: u32) {
+        for (chain_id, other) in [(a, b), (b, a)] {
+            let msg = IbcChannelCloseMsg::new_confirm(self.channel(chain_id, other));
+            ibc_channel_close(self.chains[chain_id as usize].as_mut(), self.env.clone(), msg).unwrap();
+        }
+        let (lost, net): (VecDeque<Packet>, VecDeque<Packet>) = std::mem::take(&mut self.net)
+            .into_iter()
+            .partition(|packet| link(packet.src, packet.dst) == link(a, b));
+        self.net = net;
+        for packet in lost {
+            let src = packet.src;
+            let packet = self.ibc_packet(packet);
+            let res = ibc_packet_timeout(self.chains[src as usize].as_mut(), self.env.clone(), IbcPacketTimeoutMsg::new(packet)).unwrap();
+            self.route(src, res.messages);
+        }
+        *self.generations.entry(link(a, b)).or_default() += 1;
+        self.connect(a, b);
+        self.connect(b, a);
+    }
+
+    fn ibc_packet(&mut self, packet: Packet) -> IbcPacket {
+        self.seq += 1;
+        let (src, dst) = (packet.src, packet.dst);
+        IbcPacket::new(
+            packet.data,
+            IbcEndpoint { port_id: port_id(src), channel_id: channel_id(dst, packet.generation) },
+            IbcEndpoint { port_id: port_id(dst), channel_id: channel_id(src, packet.generation) },
+            self.seq,
+            packet.timeout,
+        )
+    }
+
+    fn route(&mut self, src: u32, msgs: Vec<SubMsg>) {
+        for msg in msgs {
+            match msg.msg {
+                CosmosMsg::Ibc(IbcMsg::SendPacket { channel_id, data, timeout }) => {
+                    let dst = channel_id.trim_start_matches("channel-").split('-').next().unwrap().parse().unwrap();
+                    assert_eq!(self.channel(src, dst).endpoint.channel_id, channel_id, "chain {} sent on a closed channel", src);
+                    let generation = self.generations.get(&link(src, dst)).copied().unwrap_or_default();
+                    // a packet never overtakes an earlier one on its channel
+                    let after = self.net
+                        .iter()
+                        .filter(|packet| packet.src == src && packet.dst == dst)
+                        .map(|packet| packet.ready_at)
+                        .max()
+                        .unwrap_or_default();
+                    let ready_at = (self.now + self.scheduler.delay(src, dst)).max(after);
+                    self.net.push_back(Packet { src, dst, data, timeout, ready_at, generation });
+                },
+                // the target answers right away, the reply records the outcome
+                CosmosMsg::Wasm(WasmMsg::Execute { contract_addr, msg: payload, funds }) => {
+                    let result = if self.failing.contains(&contract_addr) {
+                        SubMsgResult::Err(format!("{} failed", contract_addr))
+                    } else {
+                        if !funds.is_empty() {
+                            self.transfers[src as usize].push((contract_addr.clone(), funds));
+                        }
+                        SubMsgResult::Ok(SubMsgResponse { events: vec![], data: None })
+                    };
+                    self.executed[src as usize].push((contract_addr, payload));
+                    let res = reply(self.chains[src as usize].as_mut(), self.env.clone(), Reply { id: msg.id, result }).unwrap();
+                    // the last reply of a slot sends its report
+                    self.route(src, res.messages);
+                },
+                CosmosMsg::Bank(BankMsg::Send { to_address, amount }) => self.transfers[src as usize].push((to_address, amount)),
+                other => panic!("unexpected msg {:?}", other),
+            }
+        }
+    }
+
+    fn deliver(&mut self, packet: Packet, delivery: Delivery) {
+        let (src, dst) = (packet.src, packet.dst);
+        let packet = self.ibc_packet(packet);
+        let reopened = self.generations.get(&link(src, dst)).copied().unwrap_or_default();
+        if delivery == Delivery::Drop && reopened < MAX_RETRIES {
+            let res = ibc_packet_timeout(self.chains[src as usize].as_mut(), self.env.clone(), IbcPacketTimeoutMsg::new(packet)).unwrap();
+            self.route(src, res.messages);
+            self.reopen(src, dst);
+            return;
+        }
+        if delivery == Delivery::Duplicate {
+            let res = ibc_packet_receive(self.chains[dst as usize].as_mut(), self.env.clone(), IbcPacketReceiveMsg::new(packet.clone())).unwrap();
+            self.route(dst, res.messages);
+        }
+        let res = ibc_packet_receive(self.chains[dst as usize].as_mut(), self.env.clone(), IbcPacketReceiveMsg::new(packet.clone())).unwrap();
+        let ack = IbcAcknowledgement::new(res.acknowledgement.clone());
+        self.route(dst, res.messages);
+        let res = ibc_packet_ack(self.chains[src as usize].as_mut(), self.env.clone(), IbcPacketAckMsg::new(ack, packet)).unwrap();
+        self.route(src, res.messages);
+    }
+
+    // Send msgs from src to dst as if src's contract had, whatever they say
+    pub fn inject(&mut self, src: u32, dst: u32, msgs: Vec<Msg>) {
+        let channel_id = self.channel(src, dst).endpoint.channel_id;
+        let data = encode_for_channel(&self.chains[src as usize].storage, &channel_id, &PacketMsg::MsgQueue(msgs)).unwrap();
+        let timeout = get_timeout(&self.env);
+        self.route(src, vec![SubMsg::new(IbcMsg::SendPacket { channel_id, data, timeout })]);
+    }
+
+    // Deliver one packet, returns false once the network is quiet
+    pub fn step(&mut self) -> bool {
+        let first_ready = match self.net.iter().map(|packet| packet.ready_at).min() {
+            Some(ready_at) => ready_at,
+            None => return false,
+        };
+        self.tick(first_ready.max(self.now + 1) - self.now);
+
+        // the head of every channel that can deliver now
+        let mut heads: Vec<usize> = Vec::new();
+        for (i, packet) in self.net.iter().enumerate() {
+            let first_on_channel = !self.net
+                .iter()
+                .take(i)
+                .any(|earlier| earlier.src == packet.src && earlier.dst == packet.dst);
+            if first_on_channel && packet.ready_at <= self.now {
+                heads.push(i);
+            }
+        }
+        let net = &self.net;
+        let ready: Vec<&Packet> = heads.iter().map(|i| &net[*i]).collect();
+        let picked = heads[self.scheduler.pick(&ready)];
+        let packet = self.net.remove(picked).unwrap();
+        let delivery = self.scheduler.delivery(&packet);
+        self.deliver(packet, delivery);
+        true
+    }
+
+    // Deliver packets until the network is quiet
+    pub fn run(&mut self) {
+        while self.step() {}
+    }
+
+    // Let time pass without delivering anything
+    pub fn tick(&mut self, ticks: u64) {
+        self.now += ticks;
+        self.env.block.height += ticks;
+        self.env.block.time = self.env.block.time.plus_seconds(ticks);
+    }
+
+    pub fn execute(&mut self, chain_id: u32, msg: ExecuteMsg) {
+        self.try_execute(chain_id, msg).unwrap();
+    }
+
+    pub fn try_execute(&mut self, chain_id: u32, msg: ExecuteMsg) -> Result<Response, ContractError> {
+        self.try_execute_as(chain_id, "user", msg)
+    }
+
+    // chains are instantiated by "creator", their owner
+    pub fn try_execute_as(&mut self, chain_id: u32, sender: &str, msg: ExecuteMsg) -> Result<Response, ContractError> {
+        self.try_execute_paying(chain_id, sender, &[], msg)
+    }
+
+    pub fn try_execute_paying(&mut self, chain_id: u32, sender: &str, funds: &[Coin], msg: ExecuteMsg) -> Result<Response, ContractError> {
+        let res = execute(self.chains[chain_id as usize].as_mut(), self.env.clone(), mock_info(sender, funds), msg)?;
+        self.route(chain_id, res.messages.clone());
+        Ok(res)
+    }
+
+    pub fn sudo(&mut self, chain_id: u32, msg: SudoMsg) {
+        sudo(self.chains[chain_id as usize].as_mut(), self.env.clone(), msg).unwrap();
+    }
+
+    pub fn query<T: DeserializeOwned>(&self, chain_id: u32, msg: QueryMsg) -> T {
+        from_binary(&query(self.chains[chain_id as usize].as_ref(), self.env.clone(), msg).unwrap()).unwrap()
+    }
+
+    // The value a chain decided for an instance, None while it is undecided or not started
+    pub fn done(&self, chain_id: u32, instance_id: u64) -> Option<Batch> {
+        STATE
+            .may_load(self.chains[chain_id as usize].as_ref().storage, instance_id)
+            .unwrap()
+            .and_then(|state| state.done)
+    }
+
+    // No two chains in `chains` decided different values for one of the instances
+    pub fn assert_safety(&self, chains: &[u32], instances: std::ops::Range<u64>) {
+        for instance_id in instances {
+            let decided: Vec<(u32, Batch)> = chains
+                .iter()
+                .filter_map(|chain_id| self.done(*chain_id, instance_id).map(|val| (*chain_id, val)))
+                .collect();
+            for (chain_id, val) in &decided {
+                assert_eq!(*val, decided[0].1, "chains {} and {} disagree on instance {}", chain_id, decided[0].0, instance_id);
+            }
+        }
+    }
+
+    // Every chain in `chains` decided the same value for each of the instances
+    pub fn assert_agreement(&self, chains: &[u32], instances: std::ops::Range<u64>) {
+        for instance_id in instances {
+            let decided = self.done(chains[0], instance_id);
+            assert!(decided.is_some(), "chain {} did not decide instance {}", chains[0], instance_id);
+            for chain_id in &chains[1..] {
+                assert_eq!(self.done(*chain_id, instance_id), decided, "chain {} instance {}", chain_id, instance_id);
+            }
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::state::Fault;
+    use crate::msg::{LogResponse, MemberStatus, OwnershipAction, ReceiptsResponse, SlotReportsResponse, ReconfigsResponse};
+    use crate::reconfig::RECONFIG_WINDOW;
+    use crate::state::{Config, Escrow, Expiry, LogEntry, OutboundPacket, Ownership, Receipt, ReceiptStatus, Reconfig};
+    use cosmwasm_std::{coins, Addr};
+    use std::cell::RefCell;
+    use std::rc::Rc;
+
+    // the decided values of a chain's log, block heights and times differ between chains
+    fn log(sim: &Sim, chain_id: u32) -> Vec<Batch> {
+        let mut entries: Vec<LogEntry> = Vec::new();
+        loop {
+            let start_after = entries.last().map(|entry| entry.slot);
+            let page: LogResponse = sim.query(chain_id, QueryMsg::GetLog { start_after, limit: None });
+            if page.entries.is_empty() {
+                return entries.into_iter().map(|entry| entry.value).collect();
+            }
+            entries.extend(page.entries);
+        }
+    }
+
+    fn input(name: &str) -> TBInput {
+        signed(&format!("{{\"register\":{{\"name\":\"{}\"}}}}", name))
+    }
+
+    // every chain starts the instances itself with its own input
+    fn run_inputs(sim: &mut Sim, n: u32, instances: u64) {
+        for instance_id in 0..instances {
+            for chain_id in 0..n {
+                let value = input(&format!("i{}_{}", instance_id, chain_id));
+                sim.execute(chain_id, ExecuteMsg::Input { instance_id, value });
+            }
+        }
+        sim.run();
+    }
+
+    #[test]
+    fn replicas_agree_under_fifo() {
+        let mut sim = Sim::new(4, 1);
+        run_inputs(&mut sim, 4, 2);
+        sim.assert_agreement(&[0, 1, 2, 3], 0..2);
+        assert_eq!(sim.executed[0], sim.executed[3]);
+    }
+
+    #[test]
+    fn replicas_agree_under_random_schedules() {
+        for seed in 0..8 {
+            let mut sim = Sim::with_scheduler(4, 1, Box::new(Random::new(seed, 5)));
+            run_inputs(&mut sim, 4, 2);
+            sim.assert_agreement(&[0, 1, 2, 3], 0..2);
+        }
+        let mut sim = Sim::with_scheduler(7, 2, Box::new(Random::new(42, 5)));
+        run_inputs(&mut sim, 7, 1);
+        sim.assert_agreement(&[0, 1, 2, 3, 4, 5, 6], 0..1);
+    }
+
+    #[test]
+    fn pipelined_slots_agree_with_a_slow_chain() {
+        let mut sim = Sim::with_scheduler(4, 1, Box::new(SlowChains { chains: vec![3], delay: 20 }));
+        for i in 0..6 {
+            sim.execute(i % 3, ExecuteMsg::Submit { value: input(&format!("s{}", i)) });
+        }
+        sim.run();
+        sim.assert_agreement(&[0, 1, 2, 3], 0..6);
+        let logs: Vec<Vec<Batch>> = (0..4).map(|chain_id| log(&sim, chain_id)).collect();
+        assert!(logs.iter().all(|log| *log == logs[0]));
+    }
+
+    // chain 1 is the primary of view 0
+    fn run_with_fault(fault: Fault, seed: u64) -> Sim {
+        let mut sim = Sim::with_scheduler(4, 1, Box::new(Random::new(seed, 3)));
+        sim.try_execute_as(1, "creator", ExecuteMsg::SetFaults { faults: vec![fault] }).unwrap();
+        run_inputs(&mut sim, 4, 2);
+        sim
+    }
+
+    // Drops the first MsgQueue from src to dst and records every packet delivered on that link
+    struct DropOnce {
+        src: u32,
+        dst: u32,
+        seen: Rc<RefCell<Vec<(u32, Binary, Delivery)>>>,
+    }
+
+    impl Scheduler for DropOnce {
+        fn pick(&mut self, _ready: &[&Packet]) -> usize {
+            0
+        }
+
+        fn delivery(&mut self, packet: &Packet) -> Delivery {
+            if packet.src != self.src || packet.dst != self.dst {
+                return Delivery::Deliver;
+            }
+            let mut seen = self.seen.borrow_mut();
+            let first_queue = packet.generation == 0 && !seen.iter().any(|(_, _, delivery)| *delivery == Delivery::Drop);
+            let delivery = if first_queue { Delivery::Drop } else { Delivery::Deliver };
+            seen.push((packet.generation, packet.data.clone(), delivery));
+            delivery
+        }
+    }
+
+    #[test]
+    fn timed_out_queue_is_resent_on_the_new_channel() {
+        let mut sim = Sim::new(4, 1);
+        let seen = Rc::new(RefCell::new(Vec::new()));
+        sim.scheduler = Box::new(DropOnce { src: 1, dst: 2, seen: seen.clone() });
+        run_inputs(&mut sim, 4, 2);
+        sim.assert_agreement(&[0, 1, 2, 3], 0..2);
+
+        // the queue that timed out went out again once chain 2 was bound to the reopened channel
+        let seen = seen.borrow();
+        let dropped = seen.iter().find(|(_, _, delivery)| *delivery == Delivery::Drop).unwrap();
+        assert!(seen.iter().any(|(generation, data, delivery)| *generation == 1 && *data == dropped.1 && *delivery == Delivery::Deliver));
+        let members: Vec<MemberStatus> = sim.query(1, QueryMsg::GetMembership {});
+        assert_eq!(members[2].channel_id, Some(channel_id(2, 1)));
+        assert!(members[2].connected);
+        let outstanding: Vec<OutboundPacket> = sim.query(1, QueryMsg::GetOutstandingPackets {});
+        assert!(outstanding.is_empty());
+    }
+
+    #[test]
+    fn correct_replicas_decide_despite_withheld_msgs() {
+        for seed in 0..4 {
+            let fault = Fault::Withhold { msg_types: vec!["Echo".to_string(), "Key1".to_string(), "Done".to_string()] };
+            let sim = run_with_fault(fault, seed);
+            sim.assert_agreement(&[0, 2, 3], 0..2);
+        }
+    }
+
+    #[test]
+    fn byzantine_primary_cannot_break_agreement() {
+        let faults = vec![
+            Fault::EquivocatingPrimary,
+            Fault::StaleView { by: 1 },
+            Fault::ForgeSuggest { key2: 5, prev_key2: 4, key3: 5 },
+            Fault::ReplayDone { from: 0 },
+            Fault::ForgeInputs,
+            Fault::PadBatch { to: 3 },
+            Fault::ForgeReconfig { n: 4, f: 5 },
+        ];
+        for fault in faults {
+            for seed in 0..3 {
+                let sim = run_with_fault(fault.clone(), seed);
+                sim.assert_safety(&[0, 2, 3], 0..2);
+            }
+        }
+    }
+
+    #[test]
+    fn forged_proposals_are_not_echoed() {
+        let mut sim = run_with_fault(Fault::ForgeInputs, 0);
+        // the forged proposal of view 0 gathers no echo quorum, the correct chains time out
+        // and the next primary proposes a valid value
+        for instance_id in 0..2 {
+            assert_eq!(sim.done(0, instance_id), None);
+        }
+        sim.tick(crate::contract::VIEW_TIMEOUT_SECONDS + 1);
+        for chain_id in [0, 2, 3] {
+            for instance_id in 0..2 {
+                sim.execute(chain_id, ExecuteMsg::Abort { instance_id });
+            }
+        }
+        sim.run();
+        sim.assert_agreement(&[0, 2, 3], 0..2);
+        let api = MockApi::default();
+        for instance_id in 0..2 {
+            assert!(sim.done(0, instance_id).unwrap().is_valid(&api));
+        }
+    }
+
+    #[test]
+    fn inputs_are_executed_on_their_registered_target() {
+        let mut sim = Sim::new(4, 1);
+        let add = ExecuteMsg::AddTarget { contract: "escrow".to_string() };
+        assert!(matches!(sim.try_execute(0, add.clone()), Err(ContractError::Unauthorized {})));
+        for chain_id in 0..4 {
+            sim.try_execute_as(chain_id, "creator", add.clone()).unwrap();
+        }
+        let unknown = signed_for(r#"{"deposit":{}}"#, Some("bank"));
+        let err = sim.try_execute(0, ExecuteMsg::Submit { value: unknown }).unwrap_err();
+        assert!(matches!(err, ContractError::TargetNotAllowed { .. }));
+
+        sim.execute(0, ExecuteMsg::Submit { value: signed_for(r#"{"deposit":{}}"#, Some("escrow")) });
+        sim.execute(1, ExecuteMsg::Submit { value: input("alice") });
+        sim.run();
+        for executed in &sim.executed {
+            let mut targets: Vec<&str> = executed.iter().map(|(contract, _)| contract.as_str()).collect();
+            targets.sort_unstable();
+            assert_eq!(targets, vec!["escrow", "target_contract"]);
+        }
+    }
+
+    #[test]
+    fn failed_targets_leave_a_receipt_and_keep_the_decision() {
+        let mut sim = Sim::new(4, 1);
+        for chain_id in 0..4 {
+            sim.try_execute_as(chain_id, "creator", ExecuteMsg::AddTarget { contract: "escrow".to_string() }).unwrap();
+        }
+        sim.failing.insert("escrow".to_string());
+        let deposit = signed_for(r#"{"deposit":{}}"#, Some("escrow"));
+        sim.execute(0, ExecuteMsg::Submit { value: deposit.clone() });
+        sim.execute(1, ExecuteMsg::Submit { value: input("alice") });
+        sim.run();
+        sim.assert_agreement(&[0, 1, 2, 3], 0..2);
+
+        for chain_id in 0..4 {
+            let mut statuses = Vec::new();
+            for slot in 0..2 {
+                let receipts: ReceiptsResponse = sim.query(chain_id, QueryMsg::GetReceipts { slot });
+                statuses.extend(receipts.receipts.into_iter().map(|receipt| (receipt.contract.unwrap().to_string(), receipt.status)));
+            }
+            statuses.sort_by(|a, b| a.0.cmp(&b.0));
+            assert_eq!(statuses, vec![
+                ("escrow".to_string(), ReceiptStatus::Failed { error: "escrow failed".to_string() }),
+                ("target_contract".to_string(), ReceiptStatus::Succeeded {}),
+            ]);
+        }
+        let sender = deposit.sender().unwrap().to_string();
+        let receipt: Option<Receipt> = sim.query(2, QueryMsg::GetInputReceipt { sender: sender.clone(), nonce: deposit.nonce });
+        assert!(matches!(receipt.unwrap().status, ReceiptStatus::Failed { .. }));
+        let unknown: Option<Receipt> = sim.query(2, QueryMsg::GetInputReceipt { sender, nonce: 7 });
+        assert_eq!(unknown, None);
+    }
+
+    #[test]
+    fn every_chain_reports_its_outcome_and_divergences_are_flagged() {
+        let mut sim = Sim::new(4, 1);
+        // chain 3 never registers the escrow, it skips the deposit the others execute
+        for chain_id in 0..3 {
+            sim.try_execute_as(chain_id, "creator", ExecuteMsg::AddTarget { contract: "escrow".to_string() }).unwrap();
+        }
+        sim.execute(0, ExecuteMsg::Submit { value: signed_for(r#"{"deposit":{}}"#, Some("escrow")) });
+        sim.run();
+        sim.execute(1, ExecuteMsg::Submit { value: input("alice") });
+        sim.run();
+        sim.assert_agreement(&[0, 1, 2, 3], 0..2);
+
+        for chain_id in 0..4 {
+            let deposit: SlotReportsResponse = sim.query(chain_id, QueryMsg::GetSlotReports { slot: 0 });
+            assert_eq!(deposit.reports.iter().map(|report| report.chain_id).collect::<Vec<_>>(), vec![0, 1, 2, 3]);
+            assert!(deposit.missing.is_empty());
+            assert_eq!(deposit.divergent, vec![0]);
+            assert_eq!(deposit.reports[0].statuses, vec![ReceiptStatus::Succeeded {}]);
+            assert!(matches!(deposit.reports[3].statuses[..], [ReceiptStatus::Skipped { .. }]));
+
+            let register: SlotReportsResponse = sim.query(chain_id, QueryMsg::GetSlotReports { slot: 1 });
+            assert!(register.missing.is_empty() && register.divergent.is_empty());
+            assert!(register.reports.iter().all(|report| report.statuses == vec![ReceiptStatus::Succeeded {}]));
+        }
+        // nothing is reported before a slot is executed
+        let pending: SlotReportsResponse = sim.query(0, QueryMsg::GetSlotReports { slot: 2 });
+        assert_eq!((pending.reports.len(), pending.missing), (0, vec![0, 1, 2, 3]));
+    }
+
+    #[test]
+    fn escrowed_funds_are_forwarded_or_refunded() {
+        let mut sim = Sim::new(4, 1);
+        for chain_id in 0..4 {
+            sim.try_execute_as(chain_id, "creator", ExecuteMsg::AddTarget { contract: "escrow".to_string() }).unwrap();
+        }
+        sim.failing.insert("escrow".to_string());
+        let fee = coins(100, "stake");
+        let register = signed_paying(r#"{"register":{"name":"alice"}}"#, None, fee.clone());
+        let deposit = signed_paying(r#"{"deposit":{}}"#, Some("escrow"), fee.clone());
+
+        // the coins sent have to be the ones signed
+        let err = sim.try_execute_paying(0, "payer", &coins(99, "stake"), ExecuteMsg::Submit { value: register.clone() }).unwrap_err();
+        assert!(matches!(err, ContractError::FundsMismatch { .. }));
+        sim.try_execute_paying(0, "payer", &fee, ExecuteMsg::Submit { value: register.clone() }).unwrap();
+        let escrow: Option<Escrow> = sim.query(0, QueryMsg::GetEscrow { sender: register.sender().unwrap().to_string(), nonce: register.nonce });
+        assert_eq!(escrow.unwrap().depositor.as_str(), "payer");
+        sim.run();
+        sim.try_execute_paying(1, "payer", &fee, ExecuteMsg::Submit { value: deposit.clone() }).unwrap();
+        sim.run();
+        sim.assert_agreement(&[0, 1, 2, 3], 0..2);
+
+        // the fee reaches the target on the chain that holds it, the failed deposit is refunded
+        assert_eq!(sim.transfers[0], vec![("target_contract".to_string(), fee.clone())]);
+        assert_eq!(sim.transfers[1], vec![("payer".to_string(), fee.clone())]);
+        assert!(sim.transfers[2].is_empty() && sim.transfers[3].is_empty());
+        let paid: ReceiptsResponse = sim.query(0, QueryMsg::GetReceipts { slot: 0 });
+        assert_eq!((paid.receipts[0].status.clone(), paid.receipts[0].funds.clone()), (ReceiptStatus::Succeeded {}, fee.clone()));
+        // chains without the escrow do not execute the paid action
+        let unpaid: ReceiptsResponse = sim.query(2, QueryMsg::GetReceipts { slot: 0 });
+        assert_eq!(unpaid.receipts[0].status, ReceiptStatus::Skipped { reason: "funds not escrowed on this chain".to_string() });
+        for chain_id in 0..2 {
+            for input in [&register, &deposit] {
+                let escrow: Option<Escrow> = sim.query(chain_id, QueryMsg::GetEscrow { sender: input.sender().unwrap().to_string(), nonce: input.nonce });
+                assert_eq!(escrow, None);
+            }
+        }
+    }
+
+    #[test]
+    fn privileged_msgs_are_owner_only() {
+        let mut sim = Sim::new(4, 1);
+        let (instance_id, val, view, local_channel_id) = (5, Batch::from(input("bob")), 0, "None".to_string());
+        let privileged = vec![
+            ExecuteMsg::SetContractAddr { addr: "elsewhere".to_string() },
+            ExecuteMsg::AddTarget { contract: "escrow".to_string() },
+            ExecuteMsg::RemoveTarget { contract: "target_contract".to_string() },
+            ExecuteMsg::SetFaults { faults: vec![Fault::ForgeInputs] },
+            ExecuteMsg::PreInput { instance_id, value: input("alice") },
+            ExecuteMsg::Key3 { instance_id, val: val.clone(), view, local_channel_id: local_channel_id.clone() },
+            ExecuteMsg::Lock { instance_id, val: val.clone(), view, local_channel_id: local_channel_id.clone() },
+            ExecuteMsg::Done { instance_id, val, view, local_channel_id },
+        ];
+        for msg in privileged {
+            assert!(matches!(sim.try_execute(0, msg.clone()), Err(ContractError::Unauthorized {})), "{:?}", msg);
+        }
+        assert!(query(sim.chains[0].as_ref(), sim.env.clone(), QueryMsg::GetState { instance_id }).is_err());
+
+        // only the proposed owner can take over, and the old one is locked out afterwards
+        let transfer = ExecuteMsg::UpdateOwnership(OwnershipAction::TransferOwnership { new_owner: "alice".to_string(), expiry: None });
+        assert!(matches!(sim.try_execute(0, transfer.clone()), Err(ContractError::Unauthorized {})));
+        sim.try_execute_as(0, "creator", transfer).unwrap();
+        let accept = ExecuteMsg::UpdateOwnership(OwnershipAction::AcceptOwnership {});
+        assert!(matches!(sim.try_execute(0, accept.clone()), Err(ContractError::Unauthorized {})));
+        sim.try_execute_as(0, "alice", accept).unwrap();
+        let ownership: Ownership = sim.query(0, QueryMsg::GetOwnership {});
+        assert_eq!(ownership.owner, Some(Addr::unchecked("alice")));
+        let add = ExecuteMsg::AddTarget { contract: "escrow".to_string() };
+        assert!(matches!(sim.try_execute_as(0, "creator", add.clone()), Err(ContractError::Unauthorized {})));
+        sim.try_execute_as(0, "alice", add).unwrap();
+    }
+
+    #[test]
+    fn oversized_proposals_are_not_echoed() {
+        let mut sim = run_with_fault(Fault::PadBatch { to: 3 }, 0);
+        // max_batch_size is 1, so the padded proposal of view 0 gathers no echo quorum
+        for instance_id in 0..2 {
+            assert_eq!(sim.done(0, instance_id), None);
+        }
+        sim.tick(crate::contract::VIEW_TIMEOUT_SECONDS + 1);
+        for chain_id in [0, 2, 3] {
+            for instance_id in 0..2 {
+                sim.execute(chain_id, ExecuteMsg::Abort { instance_id });
+            }
+        }
+        sim.run();
+        sim.assert_agreement(&[0, 2, 3], 0..2);
+        for instance_id in 0..2 {
+            assert_eq!(sim.done(0, instance_id).unwrap().inputs.len(), 1);
+        }
+    }
+
+    #[test]
+    fn expired_and_foreign_inputs_are_not_echoed() {
+        // signed by their user, but already expired or meant for another deployment
+        let expired = sign(TBInput { expires: Expiry::AtHeight(1), ..input("expired") });
+        let foreign = sign(TBInput { domain: "tb-other".to_string(), ..input("foreign") });
+        for forged in [expired, foreign] {
+            let mut sim = run_with_fault(Fault::ProposeInputs { inputs: vec![forged.clone()] }, 0);
+            for instance_id in 0..2 {
+                assert_eq!(sim.done(0, instance_id), None);
+            }
+            sim.tick(crate::contract::VIEW_TIMEOUT_SECONDS + 1);
+            for chain_id in [0, 2, 3] {
+                for instance_id in 0..2 {
+                    sim.execute(chain_id, ExecuteMsg::Abort { instance_id });
+                }
+            }
+            sim.run();
+            sim.assert_agreement(&[0, 2, 3], 0..2);
+            assert!((0..2).all(|instance_id| !sim.done(0, instance_id).unwrap().inputs.contains(&forged)));
+        }
+    }
+
+    #[test]
+    fn only_the_primary_can_propose() {
+        let mut sim = Sim::new(4, 1);
+        // the primary of view 0 stays silent
+        let faults = vec![Fault::Withhold { msg_types: vec!["Propose".to_string()] }];
+        sim.try_execute_as(1, "creator", ExecuteMsg::SetFaults { faults }).unwrap();
+        run_inputs(&mut sim, 4, 1);
+
+        // chain 2 proposes in the name of chain 1
+        let forged = Batch::from(input("forged"));
+        for dst in [0, 3] {
+            sim.inject(2, dst, vec![Msg::Propose { instance_id: 0, chain_id: 1, k: 0, v: forged.clone(), view: 0 }]);
+        }
+        sim.run();
+        for chain_id in [0, 3] {
+            assert!(!STATE.load(&sim.chains[chain_id].storage, 0).unwrap().received_propose);
+        }
+
+        sim.tick(crate::contract::VIEW_TIMEOUT_SECONDS + 1);
+        for chain_id in 0..4 {
+            sim.execute(chain_id, ExecuteMsg::Abort { instance_id: 0 });
+        }
+        sim.run();
+        sim.assert_agreement(&[0, 1, 2, 3], 0..1);
+        assert_ne!(sim.done(0, 0), Some(forged));
+    }
+
+    #[test]
+    fn forged_reconfigs_are_not_accepted() {
+        // one no governance approved, one with n - f < 0 and one too large to allocate
+        for (n, f) in [(5, 1), (4, 5), (u32::MAX, 0)] {
+            let mut sim = run_with_fault(Fault::ForgeReconfig { n, f }, 0);
+            for instance_id in 0..2 {
+                assert_eq!(sim.done(0, instance_id), None);
+            }
+            sim.tick(crate::contract::VIEW_TIMEOUT_SECONDS + 1);
+            for chain_id in [0, 2, 3] {
+                for instance_id in 0..2 {
+                    sim.execute(chain_id, ExecuteMsg::Abort { instance_id });
+                }
+            }
+            sim.run();
+            sim.assert_agreement(&[0, 2, 3], 0..2);
+            for chain_id in [0, 2, 3] {
+                assert!((0..2).all(|instance_id| sim.done(chain_id, instance_id).unwrap().reconfig.is_none()));
+                let reconfigs: ReconfigsResponse = sim.query(chain_id, QueryMsg::GetReconfigs {});
+                assert!(reconfigs.scheduled.is_empty());
+                let config: Config = sim.query(chain_id, QueryMsg::GetConfig {});
+                assert_eq!((config.n, config.f), (4, 1));
+            }
+        }
+    }
+
+    #[test]
+    fn inputs_only_start_the_next_free_slot() {
+        let mut sim = Sim::new(4, 1);
+        for instance_id in [1, 7] {
+            let res = sim.try_execute(0, ExecuteMsg::Input { instance_id, value: input("ahead") });
+            assert!(matches!(res, Err(ContractError::CustomError { .. })));
+        }
+        run_inputs(&mut sim, 4, 1);
+        sim.assert_agreement(&[0, 1, 2, 3], 0..1);
+        // slot 0 is in the log, inputs that lost it went on to the next slots
+        assert!(sim.try_execute(0, ExecuteMsg::Input { instance_id: 0, value: input("again") }).is_err());
+    }
+
+    #[test]
+    fn invalid_inputs_are_refused_up_front() {
+        let mut sim = Sim::new(4, 1);
+        let valid = input("alice");
+        let cases = vec![
+            TBInput { binary: "not base64!".to_string(), ..valid.clone() },
+            TBInput { public_key: vec![5; 33], ..valid.clone() },
+            TBInput { signature: vec![0; 10], ..valid.clone() },
+            TBInput { nonce: valid.nonce + 1, ..valid.clone() },
+            // signed, but not an execute msg the sender can be added to
+            signed(r#""register""#),
+        ];
+        let errors: Vec<ContractError> = cases
+            .into_iter()
+            .map(|value| sim.try_execute(0, ExecuteMsg::Input { instance_id: 0, value }).unwrap_err())
+            .collect();
+        assert!(matches!(errors[0], ContractError::InvalidBinary { .. }));
+        assert!(matches!(errors[1], ContractError::InvalidPublicKey { .. }));
+        assert!(matches!(errors[2], ContractError::InvalidSignature { .. }));
+        assert!(matches!(errors[3], ContractError::InvalidSignature { .. }));
+        assert!(matches!(errors[4], ContractError::InvalidPayload { .. }));
+        // nothing was started, the instance is still free for a valid input
+        sim.execute(0, ExecuteMsg::Input { instance_id: 0, value: valid });
+    }
+
+    #[test]
+    fn grows_replica_set_at_activation_slot() {
+        let mut sim = Sim::new(4, 1);
+        let reconfig = Reconfig { n: 5, f: 1, three_chain_demo: false, members: members(5) };
+        // governance approves it on every chain, chain 0 puts it into its next slot
+        for chain_id in 0..4 {
+            sim.sudo(chain_id, SudoMsg::ProposeReconfig { reconfig: reconfig.clone() });
+        }
+        sim.execute(0, ExecuteMsg::Submit { value: input("a") });
+        sim.run();
+
+        // slot 0 carried the reconfiguration, it is scheduled on every chain
+        for chain_id in 0..4 {
+            let reconfigs: ReconfigsResponse = sim.query(chain_id, QueryMsg::GetReconfigs {});
+            assert_eq!(reconfigs.pending, None);
+            assert_eq!(reconfigs.approved, None);
+            assert_eq!(reconfigs.scheduled, vec![(RECONFIG_WINDOW, reconfig.clone())]);
+        }
+
+        // chain 4 joins at the activation slot, the current replicas accept its WhoAmI
+        let mut msg = instantiate_msg(4, 5, 1);
+        msg.first_slot = Some(RECONFIG_WINDOW);
+        sim.add_chain(msg);
+        sim.run();
+        let channels: crate::msg::ChannelsResponse = sim.query(0, QueryMsg::GetChannels {});
+        assert!(channels.port_chan_pair.contains(&(4, channel_id(4, 0))));
+
+        for i in 0..2 * RECONFIG_WINDOW {
+            let chain_id = (i % 5) as u32;
+            sim.execute(chain_id, ExecuteMsg::Submit { value: input(&format!("s{}", i)) });
+            sim.run();
+        }
+
+        let logs: Vec<Vec<Batch>> = (0..5).map(|chain_id| log(&sim, chain_id)).collect();
+        assert!(logs[0].len() as u64 > RECONFIG_WINDOW + 1);
+        for chain_id in 1..4 {
+            assert_eq!(logs[chain_id], logs[0]);
+        }
+        // chain 4 decides the same values from the activation slot on
+        assert_eq!(logs[4][..], logs[0][RECONFIG_WINDOW as usize..]);
+        for chain_id in 0..5 {
+            let config: Config = sim.query(chain_id, QueryMsg::GetConfig {});
+            assert_eq!((config.n, config.f), (5, 1));
+        }
+        assert_eq!(sim.executed[4][..], sim.executed[0][sim.executed[0].len() - sim.executed[4].len()..]);
+    }
+}
@@ -1,49 +1,178 @@
-use std::collections::{HashSet, hash_map::DefaultHasher};
-use std::hash::{Hash, Hasher};
+use std::collections::HashSet;
 
 
-use cosmwasm_std::{IbcMsg, Timestamp, SubMsg, Addr};
+use cosmwasm_std::{IbcMsg, Timestamp, SubMsg, Addr, Binary, Coin, Empty, Event};
 use schemars::JsonSchema;
 use serde::{Deserialize, Serialize};
 
 use cw_storage_plus::{Item, Map, PrimaryKey, Key};
 
-use crate::{ibc_msg::Msg};
+use crate::{ibc_msg::Msg, mempool::DEFAULT_MAX_MEMPOOL_SIZE, payload::DEFAULT_SENDER_FIELD};
 
 
-#[derive(Serialize, Deserialize, Clone, Debug, PartialEq, JsonSchema, Hash)]
+#[derive(Serialize, Deserialize, Clone, Debug, PartialEq, JsonSchema)]
 #[serde(rename_all = "snake_case")]
 pub struct TBInput {
     pub binary: String,
     pub public_key: Vec<u8>,
     pub signature: Vec<u8>,
+    /// how `signature` was made, see signature.rs
+    #[serde(default)]
+    pub scheme: SignatureScheme,
+    /// the deployment the input is meant for, has to match Config::domain
+    pub domain: String,
+    /// per-sender number, an input is executed at most once per nonce, see replay.rs
+    pub nonce: u64,
+    /// the input is refused by a chain past this height or time
+    pub expires: Expiry,
+    /// contract the input is executed on, one of the registered targets. none is Config::contract_addr, if registered
+    #[serde(default)]
+    pub contract: Option<String>,
+    /// coins sent along to the target, escrowed by the chains the input was handed to with them, see escrow.rs
+    #[serde(default)]
+    pub funds: Vec<Coin>,
+}
+
+// An unsigned input of `{}` that never expires, tests set the fields they care about
+#[cfg(test)]
+impl Default for TBInput {
+    fn default() -> Self {
+        TBInput {
+            binary: "e30=".to_string(),
+            public_key: vec![],
+            signature: vec![],
+            scheme: SignatureScheme::Secp256k1,
+            domain: String::new(),
+            nonce: 0,
+            expires: Expiry::AtHeight(u64::MAX),
+            contract: None,
+            funds: vec![],
+        }
+    }
+}
+
+/// Block height or time after which an input is refused
+#[derive(Serialize, Deserialize, Clone, Debug, PartialEq, JsonSchema)]
+#[serde(rename_all = "snake_case")]
+pub enum Expiry {
+    AtHeight(u64),
+    AtTime(Timestamp),
+}
+
+/// Signature schemes a user can sign an input with
+#[derive(Serialize, Deserialize, Clone, Debug, PartialEq, JsonSchema, Default)]
+#[serde(rename_all = "snake_case")]
+pub enum SignatureScheme {
+    /// secp256k1 over Sha256(binary)
+    #[default]
+    Secp256k1,
+    /// ed25519 over binary
+    Ed25519,
+    /// secp256k1 over an ADR-036 amino JSON sign doc carrying binary as its data
+    Adr036 { signer: String },
+}
+
+/// The value replicas agree on: an ordered batch of user inputs, executed one after another,
+/// optionally carrying a reconfiguration of the replica set
+#[derive(Serialize, Deserialize, Clone, Debug, PartialEq, JsonSchema, Default)]
+pub struct Batch {
+    pub inputs: Vec<TBInput>,
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub reconfig: Option<Reconfig>,
 }
 
-impl TBInput {
+/// A new replica set, active from a fixed slot after the one it is decided in
+#[derive(Serialize, Deserialize, Clone, Debug, PartialEq, JsonSchema)]
+pub struct Reconfig {
+    pub n: u32,
+    pub f: u32,
+    pub three_chain_demo: bool,
+    /// replaces the configured membership, empty accepts any chain_id below n
+    pub members: Vec<(u32, Member)>,
+}
+
+impl Batch {
+    // Empty batch a replica joins a slot with when it has nothing queued
+    pub fn noop() -> Self {
+        Self::default()
+    }
+
+    pub fn is_noop(&self) -> bool {
+        self.inputs.is_empty() && self.reconfig.is_none()
+    }
+}
 
-    pub fn calculate_hash(self) -> u64 {
-        let mut s = DefaultHasher::new();
-        self.hash(&mut s);
-        s.finish()
+impl From<TBInput> for Batch {
+    fn from(input: TBInput) -> Self {
+        Self { inputs: vec![input], reconfig: None }
     }
 }
 
 
-pub type InputType = TBInput;
+pub type InputType = Batch;
 
+/// Replica-wide settings shared by every consensus instance
+#[derive(Serialize, Deserialize, Clone, Debug, PartialEq, JsonSchema)]
+pub struct Config {
+    /// number of replicas and the number of faulty ones tolerated, fixed at instantiate
+    pub n: u32,
+    pub f: u32,
+    /// allow n = 3 with f = 1, below the 3f + 1 bound
+    pub three_chain_demo: bool,
+    pub chain_id: u32,
+    pub channel_ids: Vec<String>,
+    pub contract_addr: Addr,
+    /// max number of slots past the end of the log that are started automatically, 0 disables pipelining
+    pub pipeline_depth: u64,
+    /// max number of inputs taken from the mempool into one proposal
+    pub max_batch_size: u32,
+    /// max number of inputs waiting in the mempool, submissions are refused beyond it
+    pub max_mempool_size: u32,
+    /// counterparty ports allowed to open a channel, empty allows any
+    pub allowed_ports: Vec<String>,
+    /// connections channels may be opened on, empty allows any
+    pub allowed_connections: Vec<String>,
+    /// identifies the deployment, every replica has the same, inputs signed for another are refused
+    pub domain: String,
+    /// field of the target contract's execute msg that receives the sender of an input
+    pub sender_field: String,
+}
+
+impl Config {
+    pub(crate) fn new(chain_id: u32, contract_addr: Addr) -> Self {
+        Self {
+            n: 1,
+            f: 0,
+            three_chain_demo: false,
+            chain_id,
+            channel_ids: Vec::new(),
+            contract_addr,
+            pipeline_depth: 0,
+            max_batch_size: 1,
+            max_mempool_size: DEFAULT_MAX_MEMPOOL_SIZE,
+            allowed_ports: Vec::new(),
+            allowed_connections: Vec::new(),
+            domain: String::new(),
+            sender_field: DEFAULT_SENDER_FIELD.to_string(),
+        }
+    }
+}
+
+/// State of a single IT-HS instance, stored under its instance id
 #[derive(Serialize, Deserialize, Clone, Debug, PartialEq, JsonSchema)]
 pub struct State {
     // pub role: String,
+    pub instance_id: u64,
     pub n: u32,
     pub chain_id: u32,
-    pub channel_ids: Vec<String>,
-    pub current_tx_id: u32,
     pub view: u32,
     pub primary: u32,
     pub key1: u32,
     pub key2: u32,
     pub key3: u32,
     pub lock: u32,
+    // the value this replica started the instance with
+    pub input: InputType,
     pub key1_val: InputType,
     pub key2_val: InputType,
     pub key3_val: InputType,
@@ -62,31 +191,37 @@ pub struct State {
     pub sent: HashSet<String>,
     pub done: Option<InputType>,
     pub start_time: Timestamp,
-    pub contract_addr: Addr,
     pub done_executed:bool,
     pub done_timestamp: Option<Timestamp>,
     pub done_block_height: Option<u64>,
     pub F: u32,
 }
 
+// The primary of a view: chain 1 leads view 0, wrapping around to chain 0 in view n - 1
+pub fn primary_of(view: u32, n: u32) -> u32 {
+    (view + 1) % n
+}
+
 impl State {
-    // Another associated function, taking two arguments:
-    pub(crate) fn new(chain_id: u32, input: InputType, contract_addr: Addr, start_time: Timestamp) -> Self {
+    // Fresh instance, n and chain_id are taken from the current Config
+    pub(crate) fn new(instance_id: u64, config: &Config, input: InputType, start_time: Timestamp) -> Self {
+        let n = config.n;
+        let view = 0;
         Self {
-            n: 1,
-            chain_id,
-            channel_ids: Vec::new(),
-            current_tx_id: 0,
-            view: 0,
-            primary: 1,
+            instance_id,
+            n,
+            chain_id: config.chain_id,
+            view,
+            primary: primary_of(view, n),
             key1: 0,
             key2: 0,
             key3: 0,
             lock: 0,
+            input: input.clone(),
             key1_val: input.clone(),
             key2_val: input.clone(),
             key3_val: input.clone(),
-            lock_val: input.clone(),
+            lock_val: input,
             prev_key1: -1,
             prev_key2: -1,
             suggestions: Vec::new(),
@@ -99,77 +234,225 @@ impl State {
             sent: HashSet::new(),
             done: None,
             start_time,
-            contract_addr,
             done_executed: false,
             done_timestamp: None,
             done_block_height: None,
-            F: 0,
+            F: config.f,
         }
     }
-    pub(crate) fn re_init(&mut self, input: InputType, start_time: Timestamp) -> () {
-        self.sent = HashSet::new();
-        self.done = None;
-        self.view = 0;
-        self.key1 = 0;
-        self.key2 = 0;
-        self.key3 = 0;
-        self.lock = 0;
-        self.prev_key1 = -1;
-        self.prev_key2 = -1;
-        self.key1_val = input.clone();
-        self.key2_val = input.clone();
-        self.key3_val = input.clone();
-        self.lock_val = input.clone();
-        // Set suggestions and key2_proofs to empty set
-        self.suggestions = Vec::new();
-        self.key2_proofs = Vec::new();
-
-        // Use block time..
+
+    // Move to a later view, dropping what was collected in the previous one
+    pub(crate) fn enter_view(&mut self, view: u32, start_time: Timestamp) {
+        self.view = view;
+        self.primary = primary_of(view, self.n);
         self.start_time = start_time;
+        self.suggestions.clear();
+        self.key2_proofs.clear();
+        self.proofs.clear();
+        self.received_propose = false;
+        // Done is sent at most once per instance, whatever the view
+        self.sent.retain(|name| name == "Done");
+    }
+}
 
-        // Set the primary to be (view mod n) + 1
-        self.primary = self.view % self.n + 1;
 
-        ////    process_messages() part     ////
-        // initialize proofs to an empty set
-        self.proofs = Vec::new();
+/// A decided value together with where it was decided, stored in slot order
+#[derive(Serialize, Deserialize, Clone, Debug, PartialEq, JsonSchema)]
+pub struct LogEntry {
+    pub slot: u64,
+    pub value: InputType,
+    pub view: u32,
+    pub block_height: u64,
+    pub timestamp: Timestamp,
+}
 
-        // reset values
-        self.received_propose = false;
-        self.done_executed = false;
-        self.done_timestamp = None;
-        self.done_block_height = None;
-        if (self.n == 3) {
-            self.F = 1;
-        } else {
-            self.F = (self.n-1)/3;
-        }
-        ()
+/// Who may configure the contract and call its debug entry points
+#[derive(Serialize, Deserialize, Clone, Debug, PartialEq, JsonSchema)]
+pub struct Ownership {
+    /// none once ownership was renounced
+    pub owner: Option<Addr>,
+    /// proposed by the owner, becomes the owner when it accepts
+    pub pending_owner: Option<Addr>,
+    /// the proposal can no longer be accepted from this height or time
+    pub pending_expiry: Option<Expiry>,
+}
 
-    }
+/// Coins held for an input until it is executed or refunded
+#[derive(Serialize, Deserialize, Clone, Debug, PartialEq, JsonSchema)]
+pub struct Escrow {
+    /// account that sent the coins, refunds go back to it
+    pub depositor: Addr,
+    pub input: TBInput,
+}
+
+/// What became of one input of a decided slot on this chain
+#[derive(Serialize, Deserialize, Clone, Debug, PartialEq, JsonSchema)]
+pub struct Receipt {
+    pub slot: u64,
+    /// position of the input in the decided batch
+    pub index: u32,
+    /// address the input is executed for, none if its key is malformed
+    pub sender: Option<Addr>,
+    pub nonce: u64,
+    /// contract the input was sent to, none if it was skipped before
+    pub contract: Option<Addr>,
+    /// coins sent along from the escrow, see escrow.rs
+    pub funds: Vec<Coin>,
+    pub status: ReceiptStatus,
+    /// events and data the target returned
+    pub events: Vec<Event>,
+    pub data: Option<Binary>,
+    /// height of the block the input was executed or skipped in
+    pub block_height: u64,
+}
+
+#[derive(Serialize, Deserialize, Clone, Debug, PartialEq, JsonSchema)]
+#[serde(rename_all = "snake_case")]
+pub enum ReceiptStatus {
+    /// sent to the target, waiting for its reply
+    Pending {},
+    Succeeded {},
+    /// the target returned an error, its changes were reverted
+    Failed { error: String },
+    /// not sent to the target, e.g. its signature does not verify or the nonce was used
+    Skipped { reason: String },
+}
 
+/// Expected replica behind a chain_id, unset fields match any channel
+#[derive(Serialize, Deserialize, Clone, Debug, PartialEq, JsonSchema, Default)]
+pub struct Member {
+    pub port_id: Option<String>,
+    pub connection_id: Option<String>,
+}
 
+/// Counterparty of a connected local channel
+#[derive(Serialize, Deserialize, Clone, Debug, PartialEq, JsonSchema)]
+pub struct ChannelPeer {
+    pub port_id: String,
+    pub connection_id: String,
 }
 
+/// Wire encoding of the packets on a channel, selected by the channel version
+#[derive(Serialize, Deserialize, Clone, Copy, Debug, PartialEq, JsonSchema)]
+#[serde(rename_all = "snake_case")]
+pub enum Encoding {
+    Json,
+    Compact,
+}
+
+/// A MsgQueue packet for a peer that has not been acked yet
+#[derive(Serialize, Deserialize, Clone, Debug, PartialEq, JsonSchema)]
+pub struct OutboundPacket {
+    pub chain_id: u32,
+    /// the channel the packet was last sent on, or is waiting to be sent on while it is closed
+    pub channel_id: String,
+    pub data: Binary,
+    /// number of times the packet was sent again
+    pub retries: u32,
+}
 
-pub const STATE: Item<State> = Item::new("state");
+/// Byzantine behaviour injected into the msgs this chain sends, for testing only
+#[cfg(any(test, feature = "byzantine"))]
+#[derive(Serialize, Deserialize, Clone, Debug, PartialEq, JsonSchema)]
+#[serde(rename_all = "snake_case")]
+pub enum Fault {
+    /// as primary, propose the own value to peers with an even chain_id and an empty batch to the others
+    EquivocatingPrimary,
+    /// drop every msg of these types, e.g. "Echo"
+    Withhold { msg_types: Vec<String> },
+    /// send every msg with its view lowered by `by`
+    StaleView { by: u32 },
+    /// send Suggest with these key values instead of the real ones
+    ForgeSuggest { key2: u32, prev_key2: i32, key3: u32 },
+    /// send the value decided in instance `from` as Done of every later instance
+    ReplayDone { from: u64 },
+    /// as primary, propose the own value with every input signature altered
+    ForgeInputs,
+    /// as primary, propose the own value with its inputs repeated until it holds `to` of them
+    PadBatch { to: u32 },
+    /// as primary, propose the own value with a reconfiguration to n replicas tolerating f
+    ForgeReconfig { n: u32, f: u32 },
+    /// as primary, propose these inputs instead of the own value
+    ProposeInputs { inputs: Vec<TBInput> },
+}
+
+pub const CONFIG: Item<Config> = Item::new("config");
+// Owner of the contract and the transfer it proposed, see ownership.rs
+pub const OWNERSHIP: Item<Ownership> = Item::new("ownership");
+// Contracts an input may name as its target, Config::contract_addr among them
+pub const TARGETS: Map<&Addr, Empty> = Map::new("targets");
+// <instance_id, State>
+pub const STATE: Map<u64, State> = Map::new("state");
 pub const CHANNELS: Map<u32, String> = Map::new("channels");
+// Configured membership <chain_id, member>, empty accepts any WhoAmI
+pub const MEMBERS: Map<u32, Member> = Map::new("members");
+// <local channel_id, counterparty>, recorded when the channel connects
+pub const CHANNEL_PEERS: Map<String, ChannelPeer> = Map::new("channel_peers");
+// Peers whose channel was closed <chain_id, closed channel_id>, nothing is sent to them
+pub const DISCONNECTED: Map<u32, String> = Map::new("disconnected");
+// <local channel_id, encoding>, kept after the channel closes so late acks and timeouts still decode
+pub const CHANNEL_ENCODING: Map<String, Encoding> = Map::new("channel_encoding");
+
+// <(instance_id, chain_id), view>
+pub const HIGHEST_REQ: Map<(u64, u32), u32> = Map::new("highest_req");
+pub const HIGHEST_ABORT: Map<(u64, u32), i32> = Map::new("highest_abort");
+
+pub const SEND_ALL_UPON: Map<(u64, u32), Vec<Msg>> = Map::new("send_all_upon");
+
+// Msgs received for an instance that has not been started locally yet <(instance_id, channel_id), msgs>
+pub const PENDING_MSGS: Map<(u64, String), Vec<Msg>> = Map::new("pending_msgs");
+
+// Bodies of the values seen in an instance, votes only carry the digest <(instance_id, digest), value>
+pub const VALUES: Map<(u64, Vec<u8>), InputType> = Map::new("values");
+// Votes for a digest whose body has not been fetched yet <(instance_id, digest), (channel_id, msg)>
+pub const PENDING_VOTES: Map<(u64, Vec<u8>), Vec<(Option<String>, Msg)>> = Map::new("pending_votes");
+
+// Nonces of executed inputs <(sender, nonce), slot the input was executed in>
+pub const USED_NONCES: Map<(String, u64), u64> = Map::new("used_nonces");
+
+// Coins escrowed on this chain <(sender, nonce) of the input, escrow>
+pub const ESCROWS: Map<(String, u64), Escrow> = Map::new("escrows");
 
-pub const HIGHEST_REQ: Map<u32, u32> = Map::new("highest_req");
-pub const HIGHEST_ABORT: Map<u32, i32> = Map::new("highest_abort");
+// Execution receipts <(slot, index in the batch), receipt>, see receipts.rs
+pub const RECEIPTS: Map<(u64, u32), Receipt> = Map::new("receipts");
+// Inputs sent to their target in this transaction whose reply has not come back yet, in send order
+pub const AWAITING_REPLY: Item<Vec<(u64, u32)>> = Item::new("awaiting_reply");
+// Outcome of the inputs of a slot as reported by each chain, this one included <(slot, chain_id), statuses>
+pub const CHAIN_REPORTS: Map<(u64, u32), Vec<ReceiptStatus>> = Map::new("chain_reports");
+// Next executed slot whose receipts are reported to the other chains
+pub const REPORT_CURSOR: Item<u64> = Item::new("report_cursor");
 
-pub const SEND_ALL_UPON: Map<u32, Vec<Msg>> = Map::new("send_all_upon");
+// Replicated decision log <slot, entry>, slots are instance ids
+pub const LOG: Map<u64, LogEntry> = Map::new("log");
+// Number of slots in the log, i.e. the next slot to append
+pub const LOG_LENGTH: Item<u64> = Item::new("log_length");
+// Next slot of the log to be executed against the target contract
+pub const EXEC_CURSOR: Item<u64> = Item::new("exec_cursor");
+// Mempool of inputs submitted locally that wait to be batched into a slot
+pub const PENDING_INPUTS: Item<Vec<TBInput>> = Item::new("pending_inputs");
+// Reconfiguration proposed by governance on this chain, waiting to be batched into a slot
+pub const PENDING_RECONFIG: Item<Reconfig> = Item::new("pending_reconfig");
+// Reconfiguration governance on this chain approved, proposals carrying it are echoed until it is decided
+pub const APPROVED_RECONFIG: Item<Reconfig> = Item::new("approved_reconfig");
+// Decided reconfigurations that are not active yet <activate_at slot, reconfig>
+pub const SCHEDULED_RECONFIGS: Map<u64, Reconfig> = Map::new("scheduled_reconfigs");
+// Packets waiting for their ack <(peer chain_id, sha256 of the packet data), packet>
+pub const OUTBOX: Map<(u32, Vec<u8>), OutboundPacket> = Map::new("outbox");
+// Faults applied to outgoing msgs, in order
+#[cfg(any(test, feature = "byzantine"))]
+pub const FAULTS: Item<Vec<Fault>> = Item::new("faults");
 
-// FOR DEDUPING MESSAGES <Channel_Id, has_received_the_message_before>
-pub const RECEIVED: Map<String, HashSet<u32>> = Map::new("received");
+// FOR DEDUPING MESSAGES <(instance_id, msg_type), chain_ids the message was received from>
+pub const RECEIVED: Map<(u64, String), HashSet<u32>> = Map::new("received");
 // pub const RECEIVED_SUGGEST: Map<String, HashSet<u32>> = Map::new("received_suggest");
 // pub const RECEIVED_PROOF: Map<String, HashSet<u32>> = Map::new("received_proof");
-pub const RECEIVED_ECHO: Map<u64, HashSet<u32>> = Map::new("received_echo");
-pub const RECEIVED_KEY1: Map<u64, HashSet<u32>> = Map::new("received_key1");
-pub const RECEIVED_KEY2: Map<u64, HashSet<u32>> = Map::new("received_key2");
-pub const RECEIVED_KEY3: Map<u64, HashSet<u32>> = Map::new("received_key3");
-pub const RECEIVED_LOCK: Map<u64, HashSet<u32>> = Map::new("received_lock");
-pub const RECEIVED_DONE: Map<u64, HashSet<u32>> = Map::new("received_done");
+// <(instance_id, digest of the value), chain_ids>, see digest.rs
+pub const RECEIVED_ECHO: Map<(u64, Vec<u8>), HashSet<u32>> = Map::new("received_echo");
+pub const RECEIVED_KEY1: Map<(u64, Vec<u8>), HashSet<u32>> = Map::new("received_key1");
+pub const RECEIVED_KEY2: Map<(u64, Vec<u8>), HashSet<u32>> = Map::new("received_key2");
+pub const RECEIVED_KEY3: Map<(u64, Vec<u8>), HashSet<u32>> = Map::new("received_key3");
+pub const RECEIVED_LOCK: Map<(u64, Vec<u8>), HashSet<u32>> = Map::new("received_lock");
+pub const RECEIVED_DONE: Map<(u64, Vec<u8>), HashSet<u32>> = Map::new("received_done");
 
 
 //// TESTING.. ////
@@ -0,0 +1,88 @@
+use cosmwasm_std::{Addr, Api, Empty, Order, StdResult, Storage};
+
+use crate::error::ContractError;
+use crate::msg::TargetsResponse;
+use crate::state::{TBInput, CONFIG, TARGETS};
+
+// An input names the contract it is executed on in its signed `contract` field, or none for
+// the default target Config::contract_addr. Either has to be registered: the default is
+// registered with the contract and moves with SetContractAddr, any other contract is added by
+// the owner. The registry is local to a chain, so it is checked when an input is handed to
+// the chain and again when its slot is executed; an input whose target was removed in between
+// is skipped.
+
+pub fn target_of(store: &dyn Storage, input: &TBInput) -> Result<Addr, ContractError> {
+    let contract = match &input.contract {
+        None => CONFIG.load(store)?.contract_addr,
+        Some(contract) => Addr::unchecked(contract),
+    };
+    if !TARGETS.has(store, &contract) {
+        return Err(ContractError::TargetNotAllowed { contract: contract.to_string() });
+    }
+    Ok(contract)
+}
+
+// Make contract the default target in place of the current one
+pub fn set_default_target(store: &mut dyn Storage, contract: Addr) -> StdResult<()> {
+    let mut config = CONFIG.load(store)?;
+    TARGETS.remove(store, &config.contract_addr);
+    TARGETS.save(store, &contract, &Empty {})?;
+    config.contract_addr = contract;
+    CONFIG.save(store, &config)
+}
+
+pub fn add_target(store: &mut dyn Storage, api: &dyn Api, contract: &str) -> StdResult<Addr> {
+    let contract = api.addr_validate(contract)?;
+    TARGETS.save(store, &contract, &Empty {})?;
+    Ok(contract)
+}
+
+pub fn remove_target(store: &mut dyn Storage, api: &dyn Api, contract: &str) -> StdResult<Addr> {
+    let contract = api.addr_validate(contract)?;
+    TARGETS.remove(store, &contract);
+    Ok(contract)
+}
+
+pub fn list_targets(store: &dyn Storage) -> StdResult<TargetsResponse> {
+    Ok(TargetsResponse {
+        default_target: CONFIG.load(store)?.contract_addr,
+        targets: TARGETS.keys(store, None, None, Order::Ascending).collect::<StdResult<_>>()?,
+    })
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use cosmwasm_std::testing::mock_dependencies;
+    use crate::state::Config;
+
+    fn input(contract: Option<&str>) -> TBInput {
+        TBInput { contract: contract.map(str::to_string), ..TBInput::default() }
+    }
+
+    #[test]
+    fn only_registered_targets_are_accepted() {
+        let mut deps = mock_dependencies();
+        let store = &mut deps.storage;
+        CONFIG.save(store, &Config::new(0, Addr::unchecked("nameservice"))).unwrap();
+        // only registered once the contract is instantiated
+        assert!(matches!(target_of(store, &input(None)), Err(ContractError::TargetNotAllowed { .. })));
+        set_default_target(store, Addr::unchecked("nameservice")).unwrap();
+
+        assert_eq!(target_of(store, &input(None)).unwrap(), Addr::unchecked("nameservice"));
+        assert_eq!(target_of(store, &input(Some("nameservice"))).unwrap(), Addr::unchecked("nameservice"));
+        assert!(matches!(target_of(store, &input(Some("escrow"))), Err(ContractError::TargetNotAllowed { .. })));
+
+        add_target(store, &deps.api, "escrow").unwrap();
+        assert_eq!(target_of(store, &input(Some("escrow"))).unwrap(), Addr::unchecked("escrow"));
+        remove_target(store, &deps.api, "escrow").unwrap();
+        assert!(target_of(store, &input(Some("escrow"))).is_err());
+
+        // the default goes through the same registry
+        set_default_target(store, Addr::unchecked("escrow")).unwrap();
+        assert_eq!(target_of(store, &input(None)).unwrap(), Addr::unchecked("escrow"));
+        assert!(target_of(store, &input(Some("nameservice"))).is_err());
+        remove_target(store, &deps.api, "escrow").unwrap();
+        assert!(target_of(store, &input(None)).is_err());
+    }
+}
@@ -1,83 +1,97 @@
 use std::collections::HashSet;
 
 use cosmwasm_std::{
-    StdResult, Order, IbcTimeout, Env, IbcOrder, StdError, IbcChannelOpenMsg, Storage, IbcMsg, to_binary, Addr, Binary, Deps, Api, Timestamp
+    StdResult, Order, IbcTimeout, Env, IbcOrder, StdError, IbcChannel, Storage, IbcMsg, Binary, Deps, Timestamp
 };
 
 use crate::ibc_msg::{
     Msg, PacketMsg
 };
+use crate::reconfig::{config_at, max_n};
+use crate::values::remember;
+use crate::codec::encode_for_channel;
 
-use sha2::{Digest, Sha256};
-use bech32::ToBase32;
-use ripemd::{Digest as RipDigest, Ripemd160};
 
 use cw_storage_plus::{Map};
 use crate::state::{
-    CHANNELS, SEND_ALL_UPON, STATE, HIGHEST_REQ, HIGHEST_ABORT, RECEIVED, RECEIVED_ECHO, RECEIVED_KEY1, RECEIVED_KEY2, RECEIVED_KEY3, RECEIVED_LOCK, TEST_QUEUE,RECEIVED_DONE, InputType
+    CHANNELS, Config, State, STATE, HIGHEST_REQ, HIGHEST_ABORT, RECEIVED, RECEIVED_ECHO, RECEIVED_KEY1, RECEIVED_KEY2, RECEIVED_KEY3, RECEIVED_LOCK, TEST_QUEUE,RECEIVED_DONE, InputType, Encoding
 };
 
 /// Setting the lifetime of packets to be one hour
 pub const PACKET_LIFETIME: u64 = 60 * 60;
 /// Setting up constant
 pub const IBC_APP_VERSION: &str = "simple_storage";
+/// Same app, packets in the compact binary encoding of codec.rs
+pub const IBC_APP_VERSION_COMPACT: &str = "simple_storage-compact";
+/// Channel id an Abort runs its own msgs under, so their replies are sent like a received queue's
+pub const ABORT_CHANNEL: &str = "ABORT_UNUSED_CHANNEL";
 
 
-use crate::ContractError;
 
-pub fn get_chain_id(store: &mut dyn Storage, channel_id_to_get: String) -> u32 {
-    // Get the chain_id of the sender
+// The chain_id bound to a local channel, None until its WhoAmI has been accepted
+pub fn find_chain_id(store: &dyn Storage, channel_id: &str) -> Option<u32> {
     CHANNELS
-    .range(store, None, None, Order::Ascending)
-    .find_map(|res| { 
-        let (chain_id,channel_id) = res.unwrap(); 
-        if channel_id == channel_id_to_get { 
-            Some(chain_id) 
-        } 
-        else { None }
-    }).unwrap()
+        .range(store, None, None, Order::Ascending)
+        .filter_map(|item| item.ok())
+        .find(|(_, id)| id == channel_id)
+        .map(|(chain_id, _)| chain_id)
+}
+
+// Create the local state of an instance that has not been started yet
+pub fn init_instance(store: &mut dyn Storage, env: &Env, instance_id: u64, input: InputType) -> StdResult<()> {
+    let config = config_at(store, instance_id)?;
+    remember(store, instance_id, &input)?;
+    let state = State::new(instance_id, &config, input, env.block.time);
+    // Store values to state
+    STATE.save(store, instance_id, &state)?;
+    init_receive_map(store, instance_id)
 }
 
 // reset views for a new "Instance" of the IT-HS algorithm
-pub fn init_receive_map(store: &mut dyn Storage) -> StdResult<()> {
-    let state = STATE.load(store)?;
+pub fn init_receive_map(store: &mut dyn Storage, instance_id: u64) -> StdResult<()> {
+    let state = STATE.load(store, instance_id)?;
     // Initialize highest_request (all to the max of u32 to differentiate between the initial state)
     let all_chain_ids: StdResult<Vec<_>> = CHANNELS
         .keys(store, None, None, Order::Ascending)
         .collect();
     let all_chain_ids = all_chain_ids?;
     // initialize the highest_request of oneself
-    HIGHEST_REQ.save(store, state.chain_id, &0)?;
+    HIGHEST_REQ.save(store, (instance_id, state.chain_id), &0)?;
     // initialize the highest_abort of oneself
     for chain_id in all_chain_ids {
-        HIGHEST_REQ.save(store, chain_id, &0)?;
+        HIGHEST_REQ.save(store, (instance_id, chain_id), &0)?;
         // Resetting highest_abort
         // RECEIVED_SUGGEST.save(store, chain_id, &false)?;
         // RECEIVED_PROOF.save(store, chain_id, &false)?;
     }
+    for msg_type in ["Suggest", "Proof"] {
+        RECEIVED.save(store, (instance_id, msg_type.to_string()), &HashSet::new())?;
+    }
     
-    reset_view_specific_maps(store)?;
-    reset_aborts(store)?;
+    reset_view_specific_maps(store, instance_id)?;
+    // Done is not tied to a view, votes for it are kept across view changes
+    delete_map(store, RECEIVED_DONE, instance_id)?;
+    reset_aborts(store, instance_id)?;
     Ok(())
 }
 
 // Reset maps that are specific to views...
-pub fn reset_view_specific_maps(store: &mut dyn Storage) -> StdResult<()> {
+pub fn reset_view_specific_maps(store: &mut dyn Storage, instance_id: u64) -> StdResult<()> {
 
         // remove all records for previous values
     let msg_types: StdResult<Vec<_>> = RECEIVED
+        .prefix(instance_id)
         .keys(store, None, None, Order::Ascending)
         .collect();
     for msg_type in msg_types? {
-        RECEIVED.save(store, msg_type, &HashSet::new())?;
+        RECEIVED.save(store, (instance_id, msg_type), &HashSet::new())?;
     }
 
-    delete_map(store, RECEIVED_ECHO)?;
-    delete_map(store, RECEIVED_KEY1)?;
-    delete_map(store, RECEIVED_KEY2)?;
-    delete_map(store, RECEIVED_KEY3)?;
-    delete_map(store, RECEIVED_LOCK)?;
-    delete_map(store, RECEIVED_DONE)?;
+    delete_map(store, RECEIVED_ECHO, instance_id)?;
+    delete_map(store, RECEIVED_KEY1, instance_id)?;
+    delete_map(store, RECEIVED_KEY2, instance_id)?;
+    delete_map(store, RECEIVED_KEY3, instance_id)?;
+    delete_map(store, RECEIVED_LOCK, instance_id)?;
 
     //// TESTING ////
     let keys: StdResult<Vec<_>> = TEST_QUEUE
@@ -91,27 +105,28 @@ pub fn reset_view_specific_maps(store: &mut dyn Storage) -> StdResult<()> {
     Ok(())
 }
 
-fn reset_aborts(store: &mut dyn Storage) -> StdResult<()> {
-    let state = STATE.load(store)?;
+fn reset_aborts(store: &mut dyn Storage, instance_id: u64) -> StdResult<()> {
+    let state = STATE.load(store, instance_id)?;
     // Initialize highest_request (all to the max of u32 to differentiate between the initial state)
     let all_chain_ids: StdResult<Vec<_>> = CHANNELS
     .keys(store, None, None, Order::Ascending)
     .collect();
     let all_chain_ids = all_chain_ids?;
-    HIGHEST_ABORT.save(store, state.chain_id, &-1)?;
+    HIGHEST_ABORT.save(store, (instance_id, state.chain_id), &-1)?;
     for chain_id in all_chain_ids {
         // Resetting highest_abort
-        HIGHEST_ABORT.save(store, chain_id, &-1)?;
+        HIGHEST_ABORT.save(store, (instance_id, chain_id), &-1)?;
     }
     Ok(())
 }
 
-fn delete_map(store: &mut dyn Storage, map: Map<u64, HashSet<u32>>)  -> StdResult<()> {
+fn delete_map(store: &mut dyn Storage, map: Map<(u64, Vec<u8>), HashSet<u32>>, instance_id: u64)  -> StdResult<()> {
     let vals: StdResult<Vec<_>> = map
+        .prefix(instance_id)
         .keys(store, None, None, Order::Ascending)
         .collect();
     for v in vals? {
-        map.remove(store, v);
+        map.remove(store, (instance_id, v));
     }       
     Ok(())
 }
@@ -136,121 +151,64 @@ pub fn get_id_channel_pair_from_storage(storage: &mut dyn Storage) -> StdResult<
     channels
 }
 
-pub fn send_all_upon_join_queue(storage: &mut dyn Storage, packet_to_broadcast: Msg, 
-                                queue: &mut Vec<Vec<Msg>>) -> Result<(), ContractError> {
-    let channel_ids = get_id_channel_pair_from_storage(storage)?;
-    let state = STATE.load(storage)?;
-    for (chain_id, _channel_id) in &channel_ids {
-        let highest_request = HIGHEST_REQ.load(storage, chain_id.clone())?;
-        if highest_request == state.view {
-            queue[*chain_id as usize].push(packet_to_broadcast.clone());
-        }
-        else{
-            let action = |packets: Option<Vec<Msg>>| -> StdResult<Vec<Msg>> {
-                match packets {
-                    Some(_) => todo!(),
-                    None => Ok(vec!(packet_to_broadcast.clone())),
-                }
-                
-            };
-            SEND_ALL_UPON.update(storage, *chain_id, action)?;
-        }
-    }
-    Ok(())
+// The bound peers that take part in an instance, chains outside of its n are left out
+pub fn get_instance_peers(storage: &mut dyn Storage, instance_id: u64) -> StdResult<Vec<(u32, String)>> {
+    let n = match STATE.may_load(storage, instance_id)? {
+        Some(state) => state.n,
+        None => return get_id_channel_pair_from_storage(storage),
+    };
+    let channels = get_id_channel_pair_from_storage(storage)?;
+    Ok(channels.into_iter().filter(|(chain_id, _)| *chain_id < n).collect())
 }
 
-fn _verify_channel(msg: IbcChannelOpenMsg) -> StdResult<()> {
-    let channel = msg.channel();
+// One msg queue per chain id, including chains that join with a scheduled reconfiguration
+pub fn new_queue(storage: &dyn Storage) -> StdResult<Vec<Vec<Msg>>> {
+    let bound = CHANNELS
+        .keys(storage, None, None, Order::Descending)
+        .next()
+        .transpose()?
+        .map_or(0, |chain_id| chain_id + 1);
+    Ok(vec![Vec::new(); max_n(storage)?.max(bound) as usize])
+}
 
+// Checked on every handshake step: our app version and ordering, and the counterparty
+// port and connection when the allow-lists in Config are set. Both ends must use the same
+// version, which selects the encoding of the packets on the channel.
+pub fn verify_channel(config: &Config, channel: &IbcChannel, counterparty_version: Option<&str>) -> StdResult<Encoding> {
     if channel.order != IbcOrder::Ordered {
         return Err(StdError::generic_err("Only supports ordered channels"));
     }
-    if channel.version.as_str() != IBC_APP_VERSION {
-        return Err(StdError::generic_err(format!(
-            "Must set version to `{}`",
-            IBC_APP_VERSION
-        )));
-    }
-    if let Some(counter_version) = msg.counterparty_version() {
-        if counter_version != IBC_APP_VERSION {
+    let encoding = Encoding::from_version(&channel.version).ok_or_else(|| StdError::generic_err(format!(
+        "Must set version to `{}` or `{}`",
+        IBC_APP_VERSION, IBC_APP_VERSION_COMPACT
+    )))?;
+    if let Some(counter_version) = counterparty_version {
+        if counter_version != channel.version {
             return Err(StdError::generic_err(format!(
                 "Counterparty version must be `{}`",
-                IBC_APP_VERSION
+                channel.version
             )));
         }
     }
-
-    Ok(())
-}
-
-pub fn convert_send_ibc_msg(channel_id: String, packet: PacketMsg, timeout: IbcTimeout) -> IbcMsg {
-    IbcMsg::SendPacket {
-        channel_id,
-        data: to_binary(&packet).unwrap(),
-        timeout,
-    }
-}
-
-pub fn derive_addr_from_pubkey(pub_key_bytes: &[u8]) -> Result<Addr, ContractError> {
-
-   // derive external address for merkle proof check
-    let sha_hash = Sha256::digest(pub_key_bytes);
-
-    if sha_hash.len() != 32 {
-         return Err(ContractError::CustomError { val: "INVALID_LENGTH NOT 32".to_string() });
-    }
-    
-    let rip_hash = Ripemd160::digest(sha_hash);
-    let rip_slice: &[u8] = rip_hash.as_slice();
-
-    let addr: String = bech32::encode("wasm", rip_slice.to_base32(), bech32::Variant::Bech32)
-        .map_err(|err| ContractError::CustomError { val: err.to_string() })?;
-
-    let addr = cosmwasm_std::Addr::unchecked(addr);
-    Ok(addr)
-}
-
-pub fn append_binary_string(binaryString: String, key: &String, value: &String) -> Binary {
-    let binary = Binary::from_base64(&binaryString).unwrap();;
-    
-    let mut binaryVector = binary.0.to_vec();
-
-    // Pop last two brackets
-    binaryVector.pop();
-    binaryVector.pop();
-
-    binaryVector.push(b',');
-    binaryVector.push(b'"');
-    for elem in key.chars() {
-        binaryVector.push(elem as u8);
+    let port_id = &channel.counterparty_endpoint.port_id;
+    if !config.allowed_ports.is_empty() && !config.allowed_ports.contains(port_id) {
+        return Err(StdError::generic_err(format!("Counterparty port `{}` is not allowed", port_id)));
     }
-    binaryVector.push(b'"');
-    binaryVector.push(b':');
-    binaryVector.push(b'"');
-    for elem in value.chars() {
-        binaryVector.push(elem as u8);
+    if !config.allowed_connections.is_empty() && !config.allowed_connections.contains(&channel.connection_id) {
+        return Err(StdError::generic_err(format!("Connection `{}` is not allowed", channel.connection_id)));
     }
-    binaryVector.push(b'"');
-    binaryVector.push(b'}');
-    binaryVector.push(b'}');
 
-    Binary(binaryVector)
+    Ok(encoding)
 }
 
-pub fn check_signature(api: &dyn Api, val: InputType) -> bool {
-    let mut result: Vec<bool> = Vec::new();
-
-    // Hashing
-    let hash = Sha256::digest(val.binary);
-
-    // Verification
-    let verify_result = api
-        .secp256k1_verify(hash.as_ref(), &val.signature, &val.public_key);
-
-    verify_result.unwrap()
+pub fn convert_send_ibc_msg(store: &dyn Storage, channel_id: String, packet: PacketMsg, timeout: IbcTimeout) -> StdResult<IbcMsg> {
+    Ok(IbcMsg::SendPacket {
+        data: encode_for_channel(store, &channel_id, &packet)?,
+        channel_id,
+        timeout,
+    })
 }
 
-
 pub fn get_seconds_diff(start: &Timestamp, end: &Timestamp) -> u64 {
     return end.seconds()-start.seconds();
 } 
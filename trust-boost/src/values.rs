@@ -0,0 +1,125 @@
+use cosmwasm_std::{Binary, Order, StdResult, Storage};
+
+use crate::ibc_msg::Msg;
+use crate::state::{InputType, PENDING_MSGS, PENDING_VOTES, VALUES};
+
+// Echo, Key1, Key2, Key3, Lock and Done only carry the digest of the value they vote for,
+// the body travels in Propose, Suggest and Proof and is kept here per instance. A vote for
+// a digest without a body is parked and the body is fetched from the chain that sent the
+// vote: a correct chain only votes for values it has the body of. Once the body arrives
+// and matches the digest, the parked votes are processed again. A body nobody voted for
+// is not kept, so peers cannot fill the store with values that were never asked for.
+
+pub fn remember(store: &mut dyn Storage, instance_id: u64, val: &InputType) -> StdResult<()> {
+    let key = (instance_id, val.digest().to_vec());
+    if !VALUES.has(store, key.clone()) {
+        VALUES.save(store, key, val)?;
+    }
+    Ok(())
+}
+
+pub fn lookup(store: &dyn Storage, instance_id: u64, digest: &Binary) -> StdResult<Option<InputType>> {
+    VALUES.may_load(store, (instance_id, digest.to_vec()))
+}
+
+// Park a vote until the body of its digest is known
+pub fn park(store: &mut dyn Storage, instance_id: u64, digest: &Binary, channel_id: Option<String>, msg: Msg) -> StdResult<()> {
+    let action = |votes: Option<Vec<(Option<String>, Msg)>>| -> StdResult<Vec<(Option<String>, Msg)>> {
+        let mut votes = votes.unwrap_or_default();
+        votes.push((channel_id, msg));
+        Ok(votes)
+    };
+    PENDING_VOTES.update(store, (instance_id, digest.to_vec()), action)?;
+    Ok(())
+}
+
+// A peer that is back on a new channel: msgs parked under its closed channel now come from the new one
+pub fn move_parked(store: &mut dyn Storage, from: &str, to: &str) -> StdResult<()> {
+    let pending: Vec<((u64, String), Vec<Msg>)> = PENDING_MSGS
+        .range(store, None, None, Order::Ascending)
+        .collect::<StdResult<_>>()?;
+    for ((instance_id, channel_id), msgs) in pending {
+        if channel_id == from {
+            PENDING_MSGS.remove(store, (instance_id, channel_id));
+            let mut moved = PENDING_MSGS.may_load(store, (instance_id, to.to_string()))?.unwrap_or_default();
+            moved.extend(msgs);
+            PENDING_MSGS.save(store, (instance_id, to.to_string()), &moved)?;
+        }
+    }
+    let keys: Vec<(u64, Vec<u8>)> = PENDING_VOTES
+        .keys(store, None, None, Order::Ascending)
+        .collect::<StdResult<_>>()?;
+    for key in keys {
+        let mut votes = PENDING_VOTES.load(store, key.clone())?;
+        if votes.iter().any(|(channel_id, _)| channel_id.as_deref() == Some(from)) {
+            for (channel_id, _) in votes.iter_mut() {
+                if channel_id.as_deref() == Some(from) {
+                    *channel_id = Some(to.to_string());
+                }
+            }
+            PENDING_VOTES.save(store, key, &votes)?;
+        }
+    }
+    Ok(())
+}
+
+// The votes waiting for the body of digest, removed from the store
+pub fn unpark(store: &mut dyn Storage, instance_id: u64, digest: &Binary) -> StdResult<Vec<(Option<String>, Msg)>> {
+    let key = (instance_id, digest.to_vec());
+    let votes = PENDING_VOTES.may_load(store, key.clone())?.unwrap_or_default();
+    PENDING_VOTES.remove(store, key);
+    Ok(votes)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use cosmwasm_std::testing::{mock_dependencies, mock_env, MockApi};
+    use cosmwasm_std::Addr;
+    use crate::queue_handler::process_queue;
+    use crate::state::{Batch, Config, TBInput, CHANNELS, LOG_LENGTH, RECEIVED_ECHO};
+    use crate::state::CONFIG;
+    use crate::utils::{get_timeout, init_instance};
+
+    #[test]
+    fn vote_for_unknown_digest_fetches_the_body() {
+        let mut deps = mock_dependencies();
+        let env = mock_env();
+        let api = MockApi::default();
+        let store = deps.as_mut().storage;
+        let mut config = Config::new(0, Addr::unchecked("target"));
+        config.n = 4;
+        config.f = 1;
+        CONFIG.save(store, &config).unwrap();
+        for chain_id in 1..4 {
+            CHANNELS.save(store, chain_id, &format!("channel-{}", chain_id)).unwrap();
+        }
+        LOG_LENGTH.save(store, &0).unwrap();
+        init_instance(store, &env, 0, Batch::noop()).unwrap();
+
+        let val = Batch::from(TBInput { binary: "a".to_string(), ..TBInput::default() });
+        let digest = val.digest();
+        let mut queue = vec![Vec::new(); 4];
+        // a body no vote is waiting for is dropped
+        let value = Msg::Value { instance_id: 0, val: val.clone() };
+        process_queue(store, get_timeout(&env), Some("channel-2".to_string()), vec![value], &mut queue, &env, &api).unwrap();
+        assert!(lookup(store, 0, &digest).unwrap().is_none());
+
+        let echo = Msg::Echo { instance_id: 0, digest: digest.clone(), view: 0 };
+        process_queue(store, get_timeout(&env), Some("channel-2".to_string()), vec![echo], &mut queue, &env, &api).unwrap();
+        assert_eq!(queue[2], vec![Msg::Fetch { instance_id: 0, digest: digest.clone() }]);
+        assert!(RECEIVED_ECHO.may_load(store, (0, digest.to_vec())).unwrap().is_none());
+
+        // the body counts the parked vote
+        let value = Msg::Value { instance_id: 0, val: val.clone() };
+        process_queue(store, get_timeout(&env), Some("channel-2".to_string()), vec![value], &mut queue, &env, &api).unwrap();
+        assert!(PENDING_VOTES.may_load(store, (0, digest.to_vec())).unwrap().is_none());
+        assert!(RECEIVED_ECHO.load(store, (0, digest.to_vec())).unwrap().contains(&2));
+
+        // and is served to peers that miss it
+        let mut queue = vec![Vec::new(); 4];
+        let fetch = Msg::Fetch { instance_id: 0, digest };
+        process_queue(store, get_timeout(&env), Some("channel-1".to_string()), vec![fetch], &mut queue, &env, &api).unwrap();
+        assert_eq!(queue[1], vec![Msg::Value { instance_id: 0, val }]);
+    }
+}
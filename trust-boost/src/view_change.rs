@@ -5,26 +5,33 @@ use cosmwasm_std::{
     IbcTimeout, Response, IbcMsg, Storage, StdResult, Env, Api
 };
 
-use crate::ibc_msg::{PacketMsg, Msg};
-use crate::queue_handler::{receive_queue, send_all_party, send_all_upon_join_queue};
+use crate::ibc_msg::Msg;
+use crate::queue_handler::{receive_queue, send_all_party, send_all_upon_join_queue, replay_pending_msgs};
 use crate::state::{
-    HIGHEST_REQ, STATE, TEST_QUEUE, CHANNELS, IBC_MSG_SEND_DEBUG
+    HIGHEST_REQ, STATE, TEST_QUEUE, IBC_MSG_SEND_DEBUG, CONFIG, DEBUG_CTR
 };
 
 use crate::ContractError;
-use crate::utils::{convert_send_ibc_msg};
+use crate::log::execute_decided;
+use crate::reports::queue_reports;
+use crate::retransmit::send_queue;
+use crate::utils::new_queue;
 
-pub fn view_change(storage: &mut dyn Storage, timeout: IbcTimeout, env: &Env, api: &dyn Api) -> Result<Response, ContractError> {
+pub fn view_change(storage: &mut dyn Storage, instance_id: u64, timeout: IbcTimeout, env: &Env, api: &dyn Api) -> Result<Response, ContractError> {
 
-    let state = STATE.load(storage)?;
-    let mut queue: Vec<Vec<Msg>> = vec!(Vec::new(); state.n.try_into().unwrap());
+    let mut queue: Vec<Vec<Msg>> = new_queue(storage)?;
 
-    append_queue_view_change(storage, & mut queue, timeout.clone(), env, api)?;
+    append_queue_view_change(storage, & mut queue, instance_id, timeout.clone(), env, api)?;
+    // Catch up with msgs other chains sent before this instance was started
+    replay_pending_msgs(storage, instance_id, timeout.clone(), &mut queue, env, api)?;
+    let wasm_msgs = execute_decided(storage, env, api)?;
+    queue_reports(storage, &mut queue)?;
     let msgs = convert_queue_to_ibc_msgs(storage, &queue, timeout.clone())?;
 
 
     let response = Response::new()
         .add_messages(msgs)
+        .add_submessages(wasm_msgs)
         .add_attribute("action", "execute")
         .add_attribute("msg_type", "input");
 
@@ -36,14 +43,16 @@ pub fn view_change(storage: &mut dyn Storage, timeout: IbcTimeout, env: &Env, ap
 pub fn append_queue_view_change(
     storage: &mut dyn Storage,
     queue: &mut Vec<Vec<Msg>>,
+    instance_id: u64,
     timeout: IbcTimeout,
     env: &Env,
     api: &dyn Api,
 ) -> Result<(), ContractError> {
     // load the state
-    let state = STATE.load(storage)?;
+    let state = STATE.load(storage, instance_id)?;
     // Add Request message to packets_to_be_broadcasted
     let request_packet = Msg::Request {
+        instance_id,
         view: state.view,
         chain_id: state.chain_id,
     };
@@ -53,6 +62,7 @@ pub fn append_queue_view_change(
 
     
     let suggest_packet = Msg::Suggest {
+        instance_id,
         chain_id: state.chain_id,
         view: state.view,
         key2: state.key2,
@@ -63,7 +73,7 @@ pub fn append_queue_view_change(
     };
     // Upon highest_request[primary] == view
     if state.chain_id != state.primary {
-        if state.view == HIGHEST_REQ.load(storage, state.primary)? {
+        if state.view == HIGHEST_REQ.load(storage, (instance_id, state.primary))? {
             queue[state.primary as usize].push(suggest_packet);
         }
     } else {
@@ -73,6 +83,7 @@ pub fn append_queue_view_change(
 
     // Contruct Request messages to be broadcasted
     let proof_packet = Msg::Proof {
+        instance_id,
         key1: state.key1,
         key1_val: state.key1_val.clone(),
         prev_key1: state.prev_key1,
@@ -99,8 +110,8 @@ pub fn testing_add2queue(
             None => Ok(vec!(chain_msg_pair.clone())),
         }
     };
-    let state = STATE.load(store)?;
-    TEST_QUEUE.update(store, state.current_tx_id, action)?;
+    let tx_id = DEBUG_CTR.load(store)?;
+    TEST_QUEUE.update(store, tx_id, action)?;
     // TEST_QUEUE.save(storage, state.current_tx_id, &(chain_id as u32, msg_queue.to_vec()))?;
     Ok(())
 }
@@ -112,18 +123,22 @@ pub fn convert_queue_to_ibc_msgs(
     queue: &Vec<Vec<Msg>>,
     timeout: IbcTimeout,
 ) -> Result<Vec<IbcMsg>, ContractError>{
-    let state = STATE.load(storage)?;
+    let config = CONFIG.load(storage)?;
+    #[cfg(any(test, feature = "byzantine"))]
+    let queue = &crate::byzantine::corrupt(storage, queue.clone())?;
     let mut msgs = Vec::new();
     for (chain_id, msg_queue) in queue.iter().enumerate() {
         //// TESTING ////
         testing_add2queue(storage, chain_id.try_into().unwrap(), msg_queue.to_vec())?;
         //// TESTING ////
 
-        if chain_id != state.chain_id as usize {
-            // When chain wishes to send some msgs to dest chain
+        if chain_id != config.chain_id as usize {
+            // When chain wishes to send some msgs to dest chain, msgs to disconnected chains wait in the outbox
             if msg_queue.len() > 0 {
-                let channel_id = CHANNELS.load(storage, chain_id.try_into().unwrap())?;
-                let msg = convert_send_ibc_msg(channel_id, PacketMsg::MsgQueue ( msg_queue.to_vec() ), timeout.clone());
+                let msg = match send_queue(storage, chain_id.try_into().unwrap(), msg_queue.to_vec(), timeout.clone())? {
+                    Some(msg) => msg,
+                    None => continue,
+                };
                 // let msg = IbcMsg::SendPacket {
                 //     channel_id,
                 //     data: to_binary(&PacketMsg::MsgQueue ( msg_queue.to_vec() ) )?,
@@ -134,9 +149,7 @@ pub fn convert_queue_to_ibc_msgs(
         }
     }
     //// TESTING /////
-    let mut state = STATE.load(storage)?;
-    state.current_tx_id += 1;
-    STATE.save(storage, &state)?;
+    DEBUG_CTR.update(storage, |tx_id| -> StdResult<u32> { Ok(tx_id + 1) })?;
     //// TESTING /////
 
     Ok(msgs)